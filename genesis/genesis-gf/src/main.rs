@@ -2,10 +2,7 @@
 extern crate serde;
 extern crate toml;
 #[macro_use] extern crate serde_derive;
-extern crate ed25519_dalek;
-extern crate bs58;
-extern crate mtdparts;
-extern crate sha2;
+extern crate identity;
 
 
 fn maketrue() -> bool {
@@ -68,10 +65,123 @@ pub struct ConfigCaptif {
     url: String,
 }
 
+#[derive(Serialize, Default, Deserialize)]
+pub struct ConfigLifeline {
+    /// base58 ed25519 key the lifeline servers authenticate with
+    #[serde(default)]
+    server_key: Option<String>,
+    /// lifeline server hostnames, in order of preference
+    #[serde(default)]
+    servers: Vec<String>,
+    /// look up servers as SRV records _lifeline._tcp.<srv_domain>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    srv_domain: Option<String>,
+    /// resolve the servers over TLS only
+    #[serde(default)]
+    dns_tls: bool,
+    /// plaintext port of the servers, 80 if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    /// tls port of the servers, 443 if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls_port: Option<u16>,
+    /// "off", "prefer" or "require"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<String>,
+    /// base64 sha256 of the SubjectPublicKeyInfo of the server certificates
+    #[serde(default)]
+    tls_pins: Vec<String>,
+    /// "http://[user:password@]host[:port]", "socks5://..." or "wpad"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy: Option<String>,
+    /// "ip:port" of venue devices the server may open streams to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    forwards: Vec<String>,
+    /// allow services and forwards into the guest network
+    #[serde(default)]
+    guest_access: bool,
+    /// base58 ed25519 key the operator commands are signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    operator_key: Option<String>,
+    /// base58 ed25519 key the server lists are signed with, the server key if
+    /// not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    list_key: Option<String>,
+    /// local services the server may open streams to, name = "host:port".
+    /// When empty, only ssh is allowed.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    services: HashMap<String, String>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfigHatchServices {
+    #[serde(default = "enabled")]
+    sentry: bool,
+    #[serde(default = "enabled")]
+    lifeline: bool,
+    #[serde(default = "enabled")]
+    access_control: bool,
+    #[serde(default = "enabled")]
+    time_control: bool,
+    #[serde(default = "enabled")]
+    watchdog: bool,
+}
+
+impl Default for ConfigHatchServices {
+    fn default() -> Self {
+        ConfigHatchServices {
+            sentry: true,
+            lifeline: true,
+            access_control: true,
+            time_control: true,
+            watchdog: true,
+        }
+    }
+}
+
+#[derive(Serialize, Default, Deserialize)]
+pub struct ConfigHatchLog {
+    /// "error", "warn", "info", "debug" or "trace"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    level: Option<String>,
+    /// "udp://host:port" or "tcp://host:port" of a syslog to forward to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    syslog: Option<String>,
+    /// log records kept for the lifeline command logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ring: Option<usize>,
+    /// levels by module path, instead of level
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    modules: HashMap<String, String>,
+}
+
+#[derive(Serialize, Default, Deserialize)]
+pub struct ConfigHatch {
+    /// run all services in one process with `hatch supervise`
+    #[serde(default)]
+    supervise: bool,
+    /// run lifeline with protocol version 2, off until the servers speak it
+    #[serde(default)]
+    lifeline2: bool,
+    /// which services `hatch supervise` runs
+    #[serde(default)]
+    services: ConfigHatchServices,
+    #[serde(default)]
+    log: ConfigHatchLog,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     wifi:   ConfigWifi,
     captif: ConfigCaptif,
+    #[serde(default)]
+    lifeline: ConfigLifeline,
+    #[serde(default)]
+    hatch: ConfigHatch,
 }
 
 #[derive(BartDisplay)]
@@ -117,6 +227,7 @@ pub struct TplRcLocal <'a> {
     c: &'a Config,
 }
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::fs;
 use std::io::Write;
@@ -178,6 +289,26 @@ fn main() {
         f.write_all(config.captif.url.as_bytes()).unwrap();
     }
 
+    {
+        let mut f = File::create("/etc/lifeline.toml").unwrap();
+        let s = toml::to_string(&config.lifeline).unwrap();
+        f.write_all(&s.as_bytes()).unwrap();
+    }
+
+    {
+        let mut f = File::create("/etc/hatch.toml").unwrap();
+        let s = toml::to_string(&config.hatch).unwrap();
+        f.write_all(&s.as_bytes()).unwrap();
+    }
+
+    // the same servers for the old C lifeline, one per line
+    {
+        let mut f = File::create("/etc/lifeline.servers").unwrap();
+        for server in &config.lifeline.servers {
+            writeln!(f, "{}", server).unwrap();
+        }
+    }
+
     {
         let mut f = fs::OpenOptions::new()
             .create(true)
@@ -200,41 +331,11 @@ fn main() {
 
 
 fn get_identity() -> Option<String> {
-    use ed25519_dalek::{SecretKey, PublicKey};
-    let f = match File::open("/proc/mtd") {
-        Ok(f) => f,
-        Err(e) => {println!("cannot read /proc/mtd: {}", e); return None;},
-    };
-    let parts = match mtdparts::parse_mtd(&f) {
-        Ok(v) => v,
-        Err(e) => {println!("cannot parse /proc/mtd: {}", e); return None;},
-    };
-    let i = match parts.get("identity") {
-        Some(i) => i,
-        None  => {println!("missing mtd partition 'identity'"); return None;},
-    };
-    let mut f = match File::open(format!("/dev/mtdblock{}", i)) {
-        Ok(f) => f,
-        Err(e) => {println!("cannot open /dev/mtdblock{}: {}", i, e); return None;},
-    };
-
-    let mut buf = [0;4096];
-    if let Err(e) = f.read_exact(&mut buf) {
-        println!("cannot read /dev/mtdblock{}: {}", i, e);
-        return None;
+    match identity::Identity::load() {
+        Ok(identity) => Some(identity.id().to_string()),
+        Err(e) => {
+            println!("cannot load the device identity: {}", e);
+            None
+        }
     }
-
-    let sc :SecretKey = match SecretKey::from_bytes(&buf[..32]) {
-        Ok(v) => v,
-        Err(e) => {println!("cannot load secret data: {}", e); return None;},
-    };
-
-    let pk: PublicKey = PublicKey::from_secret::<sha2::Sha512>(&sc);
-
-
-    Some(bs58::encode(pk.as_bytes())
-        .with_alphabet(bs58::alphabet::BITCOIN)
-        .into_string())
 }
-
-
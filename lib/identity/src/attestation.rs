@@ -0,0 +1,211 @@
+//! Statements a device signs about itself, so that the backend can tell a
+//! provisioned device from anything else presenting its id.
+//!
+//! The device signs the statement with its key. The statement repeats the
+//! metadata from the identity partition and comes with the factory
+//! certificate over it, so the verifier checks both that the device holds
+//! the key of its id and that the operator provisioned that id.
+
+use base64;
+use ed25519_dalek::{PublicKey, Signature};
+use serde_json;
+use sha2::Sha512;
+
+use blob::{Certificate, Metadata};
+use {DeviceId, Identity, IdentityError};
+
+const ATTESTATION_CONTEXT: &'static [u8] = b"hatch attestation\0";
+
+/// What the device reports about the hardware and software it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Facts {
+    /// From the `mac` partition, as aa:bb:cc:dd:ee:ff.
+    pub mac: String,
+    /// hex sha256 of the firmware partition.
+    pub firmware: String,
+    /// Hash of the genesis package the device booted with.
+    pub genesis: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Statement {
+    /// base58 device id.
+    pub device: String,
+    pub board: Option<String>,
+    pub batch: Option<String>,
+    pub provisioned: Option<u64>,
+    pub mac: String,
+    pub firmware: String,
+    pub genesis: String,
+    /// Chosen by the verifier, so that an old statement cannot be replayed.
+    pub nonce: String,
+    /// unix time.
+    pub issued: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attestation {
+    /// base64 json of the `Statement`
+    pub statement: String,
+    /// base64 signature of the device over the context and the decoded
+    /// `statement`
+    pub signature: String,
+    /// base64 factory certificate, as in the identity partition
+    pub certificate: Option<String>,
+}
+
+fn signed_data(statement: &[u8]) -> Vec<u8> {
+    let mut data = ATTESTATION_CONTEXT.to_vec();
+    data.extend_from_slice(statement);
+    data
+}
+
+fn invalid<E: ToString>(e: E) -> IdentityError {
+    IdentityError::InvalidAttestation(e.to_string())
+}
+
+impl Statement {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            board: self.board.clone(),
+            batch: self.batch.clone(),
+            provisioned: self.provisioned,
+        }
+    }
+}
+
+impl Attestation {
+    pub fn sign(identity: &Identity, facts: Facts, nonce: &str, issued: u64) -> Result<Attestation, IdentityError> {
+        let metadata = identity.metadata();
+        let statement = Statement {
+            device: identity.id().to_string(),
+            board: metadata.board.clone(),
+            batch: metadata.batch.clone(),
+            provisioned: metadata.provisioned,
+            mac: facts.mac,
+            firmware: facts.firmware,
+            genesis: facts.genesis,
+            nonce: nonce.to_string(),
+            issued: issued,
+        };
+
+        let json = serde_json::to_vec(&statement).map_err(invalid)?;
+        let signature = identity.sign(&signed_data(&json));
+
+        Ok(Attestation {
+            statement: base64::encode(&json),
+            signature: base64::encode(&signature.to_bytes()[..]),
+            certificate: identity.certificate().map(|c| base64::encode(&c.to_bytes())),
+        })
+    }
+
+    /// Checks that the device signed the statement for `nonce`, and that
+    /// `operator` certified the device. Returns the statement.
+    pub fn verify(&self, operator: &PublicKey, nonce: &str) -> Result<Statement, IdentityError> {
+        let json = base64::decode(&self.statement).map_err(invalid)?;
+        let statement: Statement = serde_json::from_slice(&json).map_err(invalid)?;
+        let device: DeviceId = statement.device.parse()?;
+
+        let signature = base64::decode(&self.signature).map_err(invalid)
+            .and_then(|s| Signature::from_bytes(&s).map_err(invalid))?;
+        if !device.public_key().verify::<Sha512>(&signed_data(&json), &signature) {
+            return Err(IdentityError::BadSignature);
+        }
+
+        let certificate = match self.certificate {
+            Some(ref c) => Certificate::from_bytes(&base64::decode(c).map_err(invalid)?)?,
+            None => return Err(IdentityError::NoCertificate),
+        };
+        certificate.verify(&device, &statement.metadata(), operator)?;
+
+        if statement.nonce != nonce {
+            return Err(IdentityError::NonceMismatch);
+        }
+        Ok(statement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blob::Blob;
+
+    fn facts() -> Facts {
+        Facts {
+            mac: "c0:25:e9:00:00:01".to_string(),
+            firmware: "ab".repeat(32),
+            genesis: "genesis-hash".to_string(),
+        }
+    }
+
+    fn operator() -> Identity {
+        Identity::from_secret(&[9; 32]).unwrap()
+    }
+
+    /// A device as provisioned by `operator`.
+    fn device() -> Identity {
+        let metadata = Metadata {
+            board: Some("archer-c7-v4".to_string()),
+            batch: Some("2018-07-a".to_string()),
+            provisioned: Some(1_530_000_000),
+        };
+        let mut blob = Blob::new([3; 32], metadata);
+        blob.certify(operator().keypair()).unwrap();
+        Identity::from_blob(blob).unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let attestation = Attestation::sign(&device(), facts(), "n1", 1_600_000_000).unwrap();
+        let statement = attestation.verify(&operator().keypair().public, "n1").unwrap();
+
+        assert_eq!(statement.device, device().id().to_string());
+        assert_eq!(statement.board, Some("archer-c7-v4".to_string()));
+        assert_eq!(statement.mac, facts().mac);
+        assert_eq!(statement.issued, 1_600_000_000);
+
+        match attestation.verify(&operator().keypair().public, "n2") {
+            Err(IdentityError::NonceMismatch) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match attestation.verify(&device().keypair().public, "n1") {
+            Err(IdentityError::UntrustedIssuer) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tampered() {
+        let attestation = Attestation::sign(&device(), facts(), "n1", 1_600_000_000).unwrap();
+        let operator = operator().keypair().public;
+
+        // another firmware
+        let mut statement = attestation.verify(&operator, "n1").unwrap();
+        statement.firmware = "cd".repeat(32);
+        let mut tampered = attestation.clone();
+        tampered.statement = base64::encode(&serde_json::to_vec(&statement).unwrap());
+        match tampered.verify(&operator, "n1") {
+            Err(IdentityError::BadSignature) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // a key of its own, signing for the id of the device
+        let json = serde_json::to_vec(&attestation.verify(&operator, "n1").unwrap()).unwrap();
+        let impostor = Identity::from_secret(&[4; 32]).unwrap();
+        let forged = Attestation {
+            statement: base64::encode(&json),
+            signature: base64::encode(&impostor.sign(&signed_data(&json)).to_bytes()[..]),
+            certificate: attestation.certificate.clone(),
+        };
+        match forged.verify(&operator, "n1") {
+            Err(IdentityError::BadSignature) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // not provisioned
+        match Attestation::sign(&impostor, facts(), "n1", 1_600_000_000).unwrap().verify(&operator, "n1") {
+            Err(IdentityError::NoCertificate) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
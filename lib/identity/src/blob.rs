@@ -0,0 +1,359 @@
+//! The layout of the identity partition.
+//!
+//! Version 1:
+//!
+//! ```text
+//! offset  size
+//! 0       4     magic "HTID"
+//! 4       1     version, 1
+//! 5       32    ed25519 secret key
+//! 37      ..    records: tag (1), length (2, big endian), value
+//! ```
+//!
+//! Records end at tag 0 or 0xff, so the erased rest of the partition ends
+//! them as well. Records with an unknown tag are skipped.
+//!
+//! Anything not starting with the magic is the original layout: the secret
+//! key in the first 32 bytes and nothing else.
+
+use std::fmt;
+use std::str;
+
+use bs58;
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+use sha2::Sha512;
+
+use {DeviceId, Identity, IdentityError, SECRET_LEN};
+
+pub const MAGIC: &'static [u8; 4] = b"HTID";
+pub const VERSION: u8 = 1;
+/// The version of a blob in the original layout.
+pub const LEGACY: u8 = 0;
+/// The size of the identity partition.
+pub const MAX_LEN: usize = 4096;
+
+const HEADER_LEN: usize = 5 + SECRET_LEN;
+
+const TAG_END: u8 = 0x00;
+const TAG_BOARD: u8 = 0x01;
+const TAG_BATCH: u8 = 0x02;
+const TAG_PROVISIONED: u8 = 0x03;
+const TAG_CERTIFICATE: u8 = 0x10;
+const TAG_ERASED: u8 = 0xff;
+
+/// Prefixed to what a certificate signs, so that the signature cannot be
+/// taken for anything else the operator key signs.
+const CERTIFICATE_CONTEXT: &'static [u8] = b"hatch identity certificate\0";
+
+/// What the factory knows about a device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub board: Option<String>,
+    /// Manufacturing batch.
+    pub batch: Option<String>,
+    /// unix time of provisioning.
+    pub provisioned: Option<u64>,
+}
+
+fn put_record(out: &mut Vec<u8>, tag: u8, value: &[u8]) -> Result<(), IdentityError> {
+    if value.len() > u16::max_value() as usize {
+        return Err(IdentityError::InvalidBlob(format!("record {} is too long", tag)));
+    }
+    out.push(tag);
+    out.push((value.len() >> 8) as u8);
+    out.push(value.len() as u8);
+    out.extend_from_slice(value);
+    Ok(())
+}
+
+fn be_u64(n: u64) -> [u8; 8] {
+    let mut b = [0u8; 8];
+    for (i, byte) in b.iter_mut().enumerate() {
+        *byte = (n >> (56 - 8 * i)) as u8;
+    }
+    b
+}
+
+impl Metadata {
+    /// The records, in tag order. This is also what a certificate signs.
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), IdentityError> {
+        if let Some(ref board) = self.board {
+            put_record(out, TAG_BOARD, board.as_bytes())?;
+        }
+        if let Some(ref batch) = self.batch {
+            put_record(out, TAG_BATCH, batch.as_bytes())?;
+        }
+        if let Some(provisioned) = self.provisioned {
+            put_record(out, TAG_PROVISIONED, &be_u64(provisioned))?;
+        }
+        Ok(())
+    }
+}
+
+/// The operator vouching for a device and its metadata.
+pub struct Certificate {
+    pub issuer: PublicKey,
+    pub signature: Signature,
+}
+
+impl fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Certificate {{ issuer: {} }}",
+               bs58::encode(self.issuer.as_bytes()).with_alphabet(bs58::alphabet::BITCOIN).into_string())
+    }
+}
+
+fn certificate_message(device: &DeviceId, metadata: &Metadata) -> Result<Vec<u8>, IdentityError> {
+    let mut msg = CERTIFICATE_CONTEXT.to_vec();
+    msg.extend_from_slice(device.as_bytes());
+    metadata.encode(&mut msg)?;
+    Ok(msg)
+}
+
+impl Certificate {
+    /// The issuer key followed by the signature.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.issuer.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.signature.to_bytes()[..]);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Certificate, IdentityError> {
+        if bytes.len() != 32 + 64 {
+            return Err(invalid("certificate is not 96 bytes"));
+        }
+        Ok(Certificate {
+            issuer: PublicKey::from_bytes(&bytes[..32]).map_err(|e| IdentityError::InvalidBlob(e.to_string()))?,
+            signature: Signature::from_bytes(&bytes[32..]).map_err(|e| IdentityError::InvalidBlob(e.to_string()))?,
+        })
+    }
+
+    pub fn issue(operator: &Keypair, device: &DeviceId, metadata: &Metadata) -> Result<Certificate, IdentityError> {
+        let msg = certificate_message(device, metadata)?;
+        Ok(Certificate {
+            issuer: operator.public,
+            signature: operator.sign::<Sha512>(&msg),
+        })
+    }
+
+    /// Checks that `operator` issued this certificate for `device` with
+    /// `metadata`.
+    pub fn verify(&self, device: &DeviceId, metadata: &Metadata, operator: &PublicKey) -> Result<(), IdentityError> {
+        if self.issuer.as_bytes() != operator.as_bytes() {
+            return Err(IdentityError::UntrustedIssuer);
+        }
+        if self.issuer.verify::<Sha512>(&certificate_message(device, metadata)?, &self.signature) {
+            Ok(())
+        } else {
+            Err(IdentityError::BadSignature)
+        }
+    }
+}
+
+/// The content of the identity partition.
+#[derive(Debug)]
+pub struct Blob {
+    /// `VERSION`, or `LEGACY` for the original layout.
+    pub version: u8,
+    pub secret: [u8; SECRET_LEN],
+    pub metadata: Metadata,
+    pub certificate: Option<Certificate>,
+}
+
+fn invalid(what: &str) -> IdentityError {
+    IdentityError::InvalidBlob(what.to_string())
+}
+
+impl Blob {
+    pub fn new(secret: [u8; SECRET_LEN], metadata: Metadata) -> Blob {
+        Blob {
+            version: VERSION,
+            secret: secret,
+            metadata: metadata,
+            certificate: None,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Blob, IdentityError> {
+        if !data.starts_with(MAGIC) {
+            if data.len() < SECRET_LEN {
+                return Err(invalid("too short for a key"));
+            }
+            let mut secret = [0u8; SECRET_LEN];
+            secret.copy_from_slice(&data[..SECRET_LEN]);
+            return Ok(Blob {
+                version: LEGACY,
+                secret: secret,
+                metadata: Metadata::default(),
+                certificate: None,
+            });
+        }
+
+        if data.len() < HEADER_LEN {
+            return Err(invalid("truncated header"));
+        }
+        if data[4] != VERSION {
+            return Err(IdentityError::UnsupportedVersion(data[4]));
+        }
+
+        let mut blob = Blob::new([0u8; SECRET_LEN], Metadata::default());
+        blob.secret.copy_from_slice(&data[5..HEADER_LEN]);
+
+        let mut pos = HEADER_LEN;
+        while pos < data.len() && data[pos] != TAG_END && data[pos] != TAG_ERASED {
+            if pos + 3 > data.len() {
+                return Err(invalid("truncated record"));
+            }
+            let tag = data[pos];
+            let len = (data[pos + 1] as usize) << 8 | data[pos + 2] as usize;
+            pos += 3;
+            if pos + len > data.len() {
+                return Err(invalid("truncated record"));
+            }
+            let value = &data[pos..pos + len];
+            pos += len;
+
+            match tag {
+                TAG_BOARD => blob.metadata.board = Some(text(value)?),
+                TAG_BATCH => blob.metadata.batch = Some(text(value)?),
+                TAG_PROVISIONED => {
+                    if len != 8 {
+                        return Err(invalid("provisioning time is not 8 bytes"));
+                    }
+                    blob.metadata.provisioned = Some(value.iter().fold(0, |n, b| n << 8 | *b as u64));
+                }
+                TAG_CERTIFICATE => blob.certificate = Some(Certificate::from_bytes(value)?),
+                _ => {}
+            }
+        }
+
+        Ok(blob)
+    }
+
+    /// Always in the current version, so this also upgrades a legacy blob.
+    pub fn encode(&self) -> Result<Vec<u8>, IdentityError> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend_from_slice(&self.secret);
+        self.metadata.encode(&mut out)?;
+        if let Some(ref certificate) = self.certificate {
+            put_record(&mut out, TAG_CERTIFICATE, &certificate.to_bytes())?;
+        }
+
+        if out.len() > MAX_LEN {
+            return Err(invalid("larger than the identity partition"));
+        }
+        Ok(out)
+    }
+
+    /// Replaces the certificate with one from `operator`.
+    pub fn certify(&mut self, operator: &Keypair) -> Result<(), IdentityError> {
+        let id = *Identity::from_secret(&self.secret)?.id();
+        self.certificate = Some(Certificate::issue(operator, &id, &self.metadata)?);
+        Ok(())
+    }
+}
+
+fn text(value: &[u8]) -> Result<String, IdentityError> {
+    str::from_utf8(value)
+        .map(|s| s.to_string())
+        .map_err(|_| invalid("metadata is not utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            board: Some("archer-c7-v4".to_string()),
+            batch: Some("2018-07-a".to_string()),
+            provisioned: Some(1_530_000_000),
+        }
+    }
+
+    fn operator() -> Keypair {
+        Identity::from_secret(&[9; 32]).unwrap().into_keypair()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut blob = Blob::new([3; 32], metadata());
+        blob.certify(&operator()).unwrap();
+
+        // padded like the erased partition
+        let mut data = blob.encode().unwrap();
+        data.resize(MAX_LEN, 0xff);
+
+        let parsed = Blob::parse(&data).unwrap();
+        assert_eq!(parsed.version, VERSION);
+        assert_eq!(parsed.secret, [3; 32]);
+        assert_eq!(parsed.metadata, metadata());
+
+        let id = *Identity::from_secret(&[3; 32]).unwrap().id();
+        let certificate = parsed.certificate.unwrap();
+        assert!(certificate.verify(&id, &metadata(), &operator().public).is_ok());
+    }
+
+    #[test]
+    fn test_legacy() {
+        let mut data = vec![3; 32];
+        data.resize(MAX_LEN, 0);
+
+        let blob = Blob::parse(&data).unwrap();
+        assert_eq!(blob.version, LEGACY);
+        assert_eq!(blob.secret, [3; 32]);
+        assert_eq!(blob.metadata, Metadata::default());
+        assert!(blob.certificate.is_none());
+
+        // upgrading keeps the key
+        assert_eq!(Blob::parse(&blob.encode().unwrap()).unwrap().secret, [3; 32]);
+    }
+
+    #[test]
+    fn test_certificate() {
+        let mut blob = Blob::new([3; 32], metadata());
+        blob.certify(&operator()).unwrap();
+        let certificate = blob.certificate.unwrap();
+        let id = *Identity::from_secret(&[3; 32]).unwrap().id();
+
+        let mut other = metadata();
+        other.batch = Some("2018-08-a".to_string());
+        match certificate.verify(&id, &other, &operator().public) {
+            Err(IdentityError::BadSignature) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let stranger = Identity::from_secret(&[4; 32]).unwrap();
+        match certificate.verify(stranger.id(), &metadata(), &operator().public) {
+            Err(IdentityError::BadSignature) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        match certificate.verify(&id, &metadata(), &stranger.keypair().public) {
+            Err(IdentityError::UntrustedIssuer) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut data = Blob::new([3; 32], metadata()).encode().unwrap();
+
+        data[4] = 2;
+        match Blob::parse(&data) {
+            Err(IdentityError::UnsupportedVersion(2)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        data[4] = VERSION;
+        let len = data.len();
+        match Blob::parse(&data[..len - 1]) {
+            Err(IdentityError::InvalidBlob(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // unknown records are skipped
+        data.extend_from_slice(&[0x42, 0, 2, 1, 2]);
+        assert_eq!(Blob::parse(&data).unwrap().metadata, metadata());
+    }
+}
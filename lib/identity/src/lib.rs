@@ -0,0 +1,351 @@
+//! The identity of a device: the ed25519 key in the `identity` mtd
+//! partition, see `blob` for its layout. The public key, in base58, is the
+//! device id.
+//!
+//! Anything that signs in the name of a device goes through here.
+
+extern crate base64;
+extern crate bs58;
+extern crate ed25519_dalek;
+extern crate mtdparts;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate sha2;
+#[cfg(test)] extern crate tempdir;
+
+pub mod attestation;
+pub mod blob;
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature};
+use sha2::Sha512;
+
+use blob::{Blob, Certificate, Metadata};
+
+pub const PROC_MTD: &'static str = "/proc/mtd";
+pub const DEV_DIR: &'static str = "/dev";
+pub const PARTITION: &'static str = "identity";
+
+/// Overrides where the identity is read from with a file, to run off the
+/// device.
+pub const IDENTITY_ENV: &'static str = "HATCH_IDENTITY";
+
+const SECRET_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum IdentityError {
+    /// A file could not be read.
+    Read { path: PathBuf, error: io::Error },
+    /// The mtd table could not be parsed.
+    Mtd(String),
+    /// There is no mtd partition of this name.
+    NoPartition(String),
+    /// The key is not a valid ed25519 key.
+    InvalidKey(String),
+    /// Not a base58 device id.
+    InvalidId(String),
+    /// The identity partition is not laid out as its version says.
+    InvalidBlob(String),
+    /// The identity partition is of a later version.
+    UnsupportedVersion(u8),
+    /// A signature that does not verify.
+    BadSignature,
+    /// A certificate issued by another key than the operator key.
+    UntrustedIssuer,
+    /// The device has no factory certificate.
+    NoCertificate,
+    /// An attestation that cannot be decoded.
+    InvalidAttestation(String),
+    /// An attestation for another nonce.
+    NonceMismatch,
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IdentityError::Read { ref path, ref error } => write!(f, "cannot read {}: {}", path.display(), error),
+            IdentityError::Mtd(ref e) => write!(f, "cannot parse the mtd table: {}", e),
+            IdentityError::NoPartition(ref name) => write!(f, "missing mtd partition '{}'", name),
+            IdentityError::InvalidKey(ref e) => write!(f, "invalid identity key: {}", e),
+            IdentityError::InvalidId(ref id) => write!(f, "invalid device id {}", id),
+            IdentityError::InvalidBlob(ref e) => write!(f, "invalid identity partition: {}", e),
+            IdentityError::UnsupportedVersion(v) => write!(f, "unsupported identity partition version {}", v),
+            IdentityError::BadSignature => write!(f, "signature does not verify"),
+            IdentityError::UntrustedIssuer => write!(f, "certificate is not issued by the operator key"),
+            IdentityError::NoCertificate => write!(f, "device has no factory certificate"),
+            IdentityError::InvalidAttestation(ref e) => write!(f, "invalid attestation: {}", e),
+            IdentityError::NonceMismatch => write!(f, "attestation is for another nonce"),
+        }
+    }
+}
+
+impl error::Error for IdentityError {
+    fn description(&self) -> &str {
+        "identity error"
+    }
+}
+
+/// Where the secret key is read from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The `identity` partition, looked up in `proc_mtd` and read from its
+    /// mtdblock device in `dev`.
+    Mtd { proc_mtd: PathBuf, dev: PathBuf },
+    /// A file starting with the secret key.
+    File(PathBuf),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::Mtd {
+            proc_mtd: PathBuf::from(PROC_MTD),
+            dev: PathBuf::from(DEV_DIR),
+        }
+    }
+}
+
+/// The mtdblock device in `dev` of the partition `name`, looked up in
+/// `proc_mtd`.
+pub fn mtd_partition(proc_mtd: &Path, dev: &Path, name: &str) -> Result<PathBuf, IdentityError> {
+    let f = File::open(proc_mtd).map_err(|e| IdentityError::Read { path: proc_mtd.to_path_buf(), error: e })?;
+    let parts = mtdparts::parse_mtd(&f).map_err(|e| IdentityError::Mtd(e.to_string()))?;
+    let i = parts.get(name).ok_or_else(|| IdentityError::NoPartition(name.to_string()))?;
+    Ok(dev.join(format!("mtdblock{}", i)))
+}
+
+fn read_blob(path: &Path) -> Result<Vec<u8>, IdentityError> {
+    let read_error = |e: io::Error| IdentityError::Read { path: path.to_path_buf(), error: e };
+
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|f| f.take(blob::MAX_LEN as u64).read_to_end(&mut data))
+        .map_err(&read_error)?;
+    if data.len() < SECRET_LEN {
+        return Err(read_error(io::Error::new(io::ErrorKind::UnexpectedEof, "too short for a key")));
+    }
+    Ok(data)
+}
+
+impl Source {
+    /// The file in `IDENTITY_ENV` if set, the mtd partition otherwise.
+    pub fn from_env() -> Source {
+        match env::var_os(IDENTITY_ENV) {
+            Some(path) => Source::File(PathBuf::from(path)),
+            None => Source::default(),
+        }
+    }
+
+    /// The path of the identity partition.
+    pub fn path(&self) -> Result<PathBuf, IdentityError> {
+        let (proc_mtd, dev) = match *self {
+            Source::File(ref path) => return Ok(path.clone()),
+            Source::Mtd { ref proc_mtd, ref dev } => (proc_mtd, dev),
+        };
+
+        mtd_partition(proc_mtd, dev, PARTITION)
+    }
+
+    /// Reads and parses the identity partition.
+    pub fn blob(&self) -> Result<Blob, IdentityError> {
+        Blob::parse(&read_blob(&self.path()?)?)
+    }
+}
+
+/// The public half of an identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId([u8; 32]);
+
+impl DeviceId {
+    pub fn from_public_key(key: &PublicKey) -> DeviceId {
+        DeviceId(*key.as_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_bytes(&self.0).expect("a device id is always a valid key")
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Checks that this device signed `msg`.
+    pub fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), IdentityError> {
+        if self.public_key().verify::<Sha512>(msg, signature) {
+            Ok(())
+        } else {
+            Err(IdentityError::BadSignature)
+        }
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&bs58::encode(&self.0[..]).with_alphabet(bs58::alphabet::BITCOIN).into_string())
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = IdentityError;
+
+    fn from_str(s: &str) -> Result<DeviceId, IdentityError> {
+        let bytes = bs58::decode(s).with_alphabet(bs58::alphabet::BITCOIN).into_vec()
+            .map_err(|_| IdentityError::InvalidId(s.to_string()))?;
+        let key = PublicKey::from_bytes(&bytes).map_err(|_| IdentityError::InvalidId(s.to_string()))?;
+        Ok(DeviceId::from_public_key(&key))
+    }
+}
+
+pub struct Identity {
+    keypair: Keypair,
+    id: DeviceId,
+    metadata: Metadata,
+    certificate: Option<Certificate>,
+}
+
+impl Identity {
+    /// Loads the identity of this device, see `Source::from_env`.
+    pub fn load() -> Result<Identity, IdentityError> {
+        Identity::load_from(&Source::from_env())
+    }
+
+    pub fn load_from(source: &Source) -> Result<Identity, IdentityError> {
+        Identity::from_blob(source.blob()?)
+    }
+
+    pub fn from_blob(blob: Blob) -> Result<Identity, IdentityError> {
+        let mut identity = Identity::from_secret(&blob.secret)?;
+        identity.metadata = blob.metadata;
+        identity.certificate = blob.certificate;
+        Ok(identity)
+    }
+
+    pub fn from_secret(secret: &[u8]) -> Result<Identity, IdentityError> {
+        let secret = SecretKey::from_bytes(secret).map_err(|e| IdentityError::InvalidKey(e.to_string()))?;
+        let public = PublicKey::from_secret::<Sha512>(&secret);
+
+        Ok(Identity {
+            id: DeviceId::from_public_key(&public),
+            keypair: Keypair { secret: secret, public: public },
+            metadata: Metadata::default(),
+            certificate: None,
+        })
+    }
+
+    pub fn id(&self) -> &DeviceId {
+        &self.id
+    }
+
+    /// Empty for a partition in the original layout.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The operator certificate from the factory, if any. Not verified.
+    pub fn certificate(&self) -> Option<&Certificate> {
+        self.certificate.as_ref()
+    }
+
+    pub fn keypair(&self) -> &Keypair {
+        &self.keypair
+    }
+
+    pub fn into_keypair(self) -> Keypair {
+        self.keypair
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        self.keypair.sign::<Sha512>(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    const MTD_TABLE: &'static str = "dev:    size   erasesize  name\n\
+                                    mtd0: 00020000 00010000 \"u-boot\"\n\
+                                    mtd5: 00010000 00010000 \"identity\"\n";
+
+    fn write(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn test_mtd() {
+        let dir = TempDir::new("identity").unwrap();
+        write(&dir.path().join("mtd"), MTD_TABLE.as_bytes());
+        write(&dir.path().join("mtdblock5"), &[7; 4096]);
+
+        let source = Source::Mtd { proc_mtd: dir.path().join("mtd"), dev: dir.path().to_path_buf() };
+        assert_eq!(source.path().unwrap(), dir.path().join("mtdblock5"));
+
+        let identity = Identity::load_from(&source).unwrap();
+        assert_eq!(identity.id(), Identity::from_secret(&[7; 32]).unwrap().id());
+    }
+
+    #[test]
+    fn test_blob() {
+        let dir = TempDir::new("identity").unwrap();
+        let metadata = Metadata { board: Some("archer-c7-v4".to_string()), ..Metadata::default() };
+        let mut data = Blob::new([7; 32], metadata.clone()).encode().unwrap();
+        data.resize(blob::MAX_LEN, 0xff);
+        write(&dir.path().join("identity"), &data);
+
+        let identity = Identity::load_from(&Source::File(dir.path().join("identity"))).unwrap();
+        assert_eq!(identity.id(), Identity::from_secret(&[7; 32]).unwrap().id());
+        assert_eq!(identity.metadata(), &metadata);
+        assert!(identity.certificate().is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = TempDir::new("identity").unwrap();
+        let source = Source::Mtd { proc_mtd: dir.path().join("mtd"), dev: dir.path().to_path_buf() };
+        match Identity::load_from(&source) {
+            Err(IdentityError::Read { .. }) => {}
+            other => panic!("unexpected {:?}", other.map(|i| *i.id())),
+        }
+
+        write(&dir.path().join("mtd"), b"dev:    size   erasesize  name\nmtd0: 00020000 00010000 \"u-boot\"\n");
+        match Identity::load_from(&source) {
+            Err(IdentityError::NoPartition(ref name)) if name == PARTITION => {}
+            other => panic!("unexpected {:?}", other.map(|i| *i.id())),
+        }
+
+        // too short for a key
+        write(&dir.path().join("short"), &[1; 16]);
+        match Identity::load_from(&Source::File(dir.path().join("short"))) {
+            Err(IdentityError::Read { .. }) => {}
+            other => panic!("unexpected {:?}", other.map(|i| *i.id())),
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        let identity = Identity::from_secret(&[1; 32]).unwrap();
+        let other = Identity::from_secret(&[2; 32]).unwrap();
+
+        let signature = identity.sign(b"hello");
+        assert!(identity.id().verify(b"hello", &signature).is_ok());
+        assert!(identity.id().verify(b"hellO", &signature).is_err());
+        assert!(other.id().verify(b"hello", &signature).is_err());
+    }
+
+    #[test]
+    fn test_device_id() {
+        let id = *Identity::from_secret(&[1; 32]).unwrap().id();
+        assert_eq!(id.to_string().parse::<DeviceId>().unwrap(), id);
+        assert!("not base58!".parse::<DeviceId>().is_err());
+        assert!("2NEpo7TZRRrLZSi2U".parse::<DeviceId>().is_err());
+    }
+}
@@ -0,0 +1,41 @@
+#!/bin/sh
+# Builds hatch once per feature set and prints the stripped size of each.
+#
+#   TARGET  rust target, the host when empty
+#   STRIP   strip of that target
+#
+# The other variables of a cross build (CARGO_TARGET_DIR, RUSTFLAGS, ...) are
+# passed on to cargo.
+
+set -e
+
+cd "$(dirname "$0")"
+
+STRIP=${STRIP:-strip}
+TARGET_DIR=${CARGO_TARGET_DIR:-target}
+
+if [ -n "$TARGET" ]; then
+    TARGET_ARG="--target $TARGET"
+    BIN="$TARGET_DIR/$TARGET/release/hatch"
+else
+    TARGET_ARG=
+    BIN="$TARGET_DIR/release/hatch"
+fi
+
+report() {
+    cargo build --release $TARGET_ARG --no-default-features --features "$1" >&2
+    cp "$BIN" "$BIN.size"
+    "$STRIP" "$BIN.size"
+    printf '%-40s %10d\n' "${1:-(none)}" "$(wc -c < "$BIN.size")"
+    rm "$BIN.size"
+}
+
+printf '%-40s %10s\n' "features" "bytes"
+report ""
+report "portal"
+report "lifeline"
+report "access-control"
+report "time-control"
+report "portal access-control time-control"
+report "lifeline access-control time-control"
+report "portal lifeline access-control time-control"
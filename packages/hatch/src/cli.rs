@@ -0,0 +1,432 @@
+//! The programs built into hatch. Each one runs as `hatch <program>`, or
+//! through a link named like it.
+
+use std::fmt;
+use std::path::Path;
+use std::process;
+#[cfg(feature = "lifeline")]
+use std::sync::Arc;
+
+#[cfg(feature = "time-control")]
+use chrono::{TimeZone, Utc};
+use identity::Identity;
+use logger;
+#[cfg(feature = "lifeline")]
+use lifeline;
+use nix::unistd;
+
+#[cfg(any(feature = "portal", feature = "time-control"))]
+use sentry;
+use services::{self, attestation};
+use supervise;
+
+struct Program {
+    name: &'static str,
+    args: &'static str,
+    about: &'static str,
+    /// Whether the feature it needs was built in.
+    built: bool,
+}
+
+const PROGRAMS: &[Program] = &[
+    Program {
+        name: "lifeline",
+        args: "",
+        about: "remote access through the lifeline servers",
+        built: cfg!(feature = "lifeline"),
+    },
+    Program {
+        name: "lifeline2",
+        args: "",
+        about: "remote access through the version 2 lifeline servers",
+        built: cfg!(feature = "lifeline"),
+    },
+    Program {
+        name: "sentry",
+        args: "[--port port] [--redirect-url file]",
+        about: "captive portal of the public wifi",
+        built: cfg!(feature = "portal"),
+    },
+    Program {
+        name: "supervise",
+        args: "[--status]",
+        about: "runs the enabled services in one process",
+        built: true,
+    },
+    Program {
+        name: "timecontrol",
+        args: "[path]",
+        about: "explains what the public wifi schedule does",
+        built: cfg!(feature = "time-control"),
+    },
+    Program {
+        name: "watchdog",
+        args: "",
+        about: "checks the connectivity and repairs it step by step",
+        built: true,
+    },
+    Program {
+        name: "attest",
+        args: "<nonce>",
+        about: "prints an attestation statement for the backend",
+        built: true,
+    },
+];
+
+fn program(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|p| p.name == name)
+}
+
+fn usage() -> String {
+    "usage: hatch <program> [options]\n       \
+     hatch --list\n\n\
+     A program also runs through a link named like it.".to_string()
+}
+
+fn usage_of(name: &str) -> String {
+    match program(name) {
+        Some(p) if !p.args.is_empty() => format!("usage: hatch {} {}", p.name, p.args),
+        _ => format!("usage: hatch {}", name),
+    }
+}
+
+fn list() {
+    for p in PROGRAMS.iter().filter(|p| p.built) {
+        println!("{:<12} {}", p.name, p.about);
+    }
+}
+
+/// What the command line asks for.
+#[derive(Debug, PartialEq)]
+enum Command {
+    List,
+    /// The usage of hatch, or of one program.
+    Help(Option<&'static str>),
+    /// A program by its name, with its arguments.
+    Run(&'static str, Action),
+}
+
+/// The programs, with their arguments checked.
+#[derive(Debug, PartialEq)]
+enum Action {
+    #[cfg(feature = "lifeline")]
+    Lifeline,
+    #[cfg(feature = "lifeline")]
+    Lifeline2,
+    #[cfg(feature = "portal")]
+    Sentry { port: Option<u16>, redirect_url: Option<String> },
+    Supervise,
+    Status,
+    #[cfg(feature = "time-control")]
+    TimeControl { path: Option<String> },
+    Watchdog,
+    Attest { nonce: String },
+}
+
+/// Why the command line was refused.
+#[derive(Debug, PartialEq)]
+enum Misuse {
+    Usage,
+    UsageOf(&'static str),
+    Unknown(String),
+    NotBuilt(&'static str),
+    #[cfg(feature = "portal")]
+    InvalidPort(String),
+}
+
+impl fmt::Display for Misuse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Misuse::Usage => write!(f, "{}", usage()),
+            Misuse::UsageOf(name) => write!(f, "{}", usage_of(name)),
+            Misuse::Unknown(ref name) => write!(f, "hatch: unknown program {}, see hatch --list", name),
+            Misuse::NotBuilt(name) => write!(f, "hatch: {} is not built into this hatch, see hatch --list", name),
+            #[cfg(feature = "portal")]
+            Misuse::InvalidPort(ref port) => write!(f, "hatch sentry: invalid port {}", port),
+        }
+    }
+}
+
+/// `args` as passed to the binary. The program is the name it was called by,
+/// or the first argument if that is not a program.
+fn parse(args: &[String]) -> Result<Command, Misuse> {
+    let called = args.get(0)
+        .and_then(|arg| Path::new(arg).file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("hatch");
+
+    let (p, args) = match program(called) {
+        Some(p) => (p, &args[1..]),
+        None => match args.get(1).map(|s| s.as_str()) {
+            Some("--list") => return Ok(Command::List),
+            Some("-h") | Some("--help") => return Ok(Command::Help(None)),
+            Some(name) if !name.starts_with('-') => match program(name) {
+                Some(p) => (p, &args[2..]),
+                None => return Err(Misuse::Unknown(name.to_string())),
+            },
+            _ => return Err(Misuse::Usage),
+        },
+    };
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Ok(Command::Help(Some(p.name)));
+    }
+    if !p.built {
+        return Err(Misuse::NotBuilt(p.name));
+    }
+
+    let action = match (p.name, args.len()) {
+        #[cfg(feature = "lifeline")]
+        ("lifeline", 0) => Action::Lifeline,
+        #[cfg(feature = "lifeline")]
+        ("lifeline2", 0) => Action::Lifeline2,
+        #[cfg(feature = "portal")]
+        ("sentry", _) => parse_sentry(args)?,
+        ("supervise", 0) => Action::Supervise,
+        ("supervise", 1) if args[0] == "--status" => Action::Status,
+        #[cfg(feature = "time-control")]
+        ("timecontrol", 0) | ("timecontrol", 1) => Action::TimeControl { path: args.get(0).cloned() },
+        ("watchdog", 0) => Action::Watchdog,
+        ("attest", 1) => Action::Attest { nonce: args[0].clone() },
+        _ => return Err(Misuse::UsageOf(p.name)),
+    };
+    Ok(Command::Run(p.name, action))
+}
+
+#[cfg(feature = "portal")]
+fn parse_sentry(args: &[String]) -> Result<Action, Misuse> {
+    let mut port = None;
+    let mut redirect_url = None;
+
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next().ok_or(Misuse::UsageOf("sentry"))?;
+        match arg.as_str() {
+            "--port" => port = Some(value.parse::<u16>().map_err(|_| Misuse::InvalidPort(value.clone()))?),
+            "--redirect-url" => redirect_url = Some(value.clone()),
+            _ => return Err(Misuse::UsageOf("sentry")),
+        }
+    }
+    Ok(Action::Sentry { port: port, redirect_url: redirect_url })
+}
+
+pub fn main(args: Vec<String>) {
+    let (name, action) = match parse(&args) {
+        Ok(Command::List) => return list(),
+        Ok(Command::Help(None)) => return println!("{}", usage()),
+        Ok(Command::Help(Some(name))) => return println!("{}", usage_of(name)),
+        Ok(Command::Run(name, action)) => (name, action),
+        Err(misuse) => {
+            eprintln!("{}", misuse);
+            process::exit(1);
+        }
+    };
+    logger::set_service(name);
+
+    match action {
+        #[cfg(feature = "lifeline")]
+        Action::Lifeline => run_lifeline(),
+        #[cfg(feature = "lifeline")]
+        Action::Lifeline2 => run_lifeline2(),
+        #[cfg(feature = "portal")]
+        Action::Sentry { port, redirect_url } => run_sentry(port, redirect_url),
+        Action::Supervise => {
+            let (id, _) = identity();
+            supervise::main(id);
+        }
+        Action::Status => status(),
+        #[cfg(feature = "time-control")]
+        Action::TimeControl { path } => timecontrol(path),
+        Action::Watchdog => run_watchdog(),
+        Action::Attest { nonce } => attest(&nonce),
+    }
+}
+
+/// The device identity, and the id the services go by. That is the hostname
+/// on a device without an identity partition.
+fn identity() -> (String, Option<Identity>) {
+    let device = match Identity::load() {
+        Ok(device) => Some(device),
+        Err(e) => {
+            warn!("cannot load the device identity: {}", e);
+            None
+        }
+    };
+    let id = match device {
+        Some(ref device) => device.id().to_string(),
+        None => {
+            let mut buf = [0u8; 64];
+            let hostname_cstr = unistd::gethostname(&mut buf).unwrap();
+            hostname_cstr.to_str().unwrap().to_string()
+        }
+    };
+    logger::set_identity(&id);
+    info!("using identity: {}", id);
+    (id, device)
+}
+
+#[cfg(feature = "lifeline")]
+fn run_lifeline() {
+    let (id, _) = identity();
+    services::lifeline1::main(id);
+}
+
+/// Until the servers speak version 2, only run when asked for by name.
+#[cfg(feature = "lifeline")]
+fn run_lifeline2() {
+    let (id, device) = identity();
+    lifeline::main(id, device.map(Identity::into_keypair),
+                   Box::new(services::telemetry::probe), Arc::new(services::commands::run));
+}
+
+#[cfg(feature = "portal")]
+fn run_sentry(port: Option<u16>, redirect_url: Option<String>) {
+    let (id, _) = identity();
+    if let Err(e) = sentry::sentry_main(id, redirect_url.as_ref().map(|u| u.as_str()), port) {
+        error!("sentry failed:");
+        for cause in e.iter() {
+            error!("  {}", cause);
+        }
+        process::exit(1);
+    }
+}
+
+fn status() {
+    if let Err(e) = supervise::status() {
+        eprintln!("cannot read {}: {}", supervise::HEALTH_PATH, e);
+        process::exit(1);
+    }
+}
+
+fn run_watchdog() {
+    if let Err(e) = services::watchdog::main() {
+        error!("watchdog failed: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Prints an attestation statement for the nonce the verifier chose.
+fn attest(nonce: &str) {
+    match attestation::attest(nonce) {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            eprintln!("cannot attest: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Validates a time control file and explains what the public wifi does with it.
+#[cfg(feature = "time-control")]
+fn timecontrol(path: Option<String>) {
+    let path = path.as_ref()
+        .map(|s| s.as_str())
+        .unwrap_or(sentry::PUBLIC_WIFI_TIME_CONTROL_PATH);
+
+    let tc = match sentry::TimeControl::load(path) {
+        Ok(tc) => tc,
+        Err(e) => {
+            eprintln!("invalid time control:");
+            for cause in e.iter() {
+                eprintln!("  {}", cause);
+            }
+            process::exit(1);
+        }
+    };
+
+    // load() validated the timezone already
+    let tz = tc.tz().unwrap();
+    let now = Utc::now();
+
+    println!("{} is valid", path);
+    println!();
+    println!("now:      {}", tz.from_utc_datetime(&now.naive_utc()).format("%a %F %H:%M %Z"));
+    println!("schedule: {}", tc.explain(&now).unwrap());
+
+    match sentry::time_trust() {
+        sentry::TimeTrust::Trusted => println!("clock:    trusted"),
+        trust => println!("clock:    not trusted ({:?}), the schedule is not enforced", trust),
+    }
+
+    match sentry::is_pub_wifi_enabled() {
+        Ok(true) => println!("wifi:     on"),
+        Ok(false) => println!("wifi:     off"),
+        Err(e) => println!("wifi:     unknown ({})", e),
+    }
+
+    if let Some(enabled) = sentry::public_wifi_override() {
+        println!("override: {} by the operators, the schedule is ignored", if enabled { "on" } else { "off" });
+    }
+
+    if let Some(deadline) = sentry::pending_shutdown() {
+        println!(
+            "shutdown: announced, the wifi closes at {}",
+            tz.timestamp(deadline, 0).format("%a %F %H:%M %Z")
+        );
+    }
+
+    println!();
+    println!("transitions in the coming week:");
+
+    let transitions = tc.transitions(&now, 7 * 24).unwrap();
+    if transitions.is_empty() {
+        println!("  none, the wifi stays {}", if tc.is_up_at(&now).unwrap() { "on" } else { "off" });
+    }
+
+    for (time, status) in transitions {
+        println!(
+            "  {}  {}",
+            time.format("%a %F %H:%M %Z"),
+            if status { "on" } else { "off" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_hatch() {
+        assert_eq!(parse(&args("hatch --list")), Ok(Command::List));
+        assert_eq!(parse(&args("/usr/sbin/hatch --help")), Ok(Command::Help(None)));
+        assert_eq!(parse(&args("hatch")), Err(Misuse::Usage));
+        assert_eq!(parse(&args("hatch --verbose")), Err(Misuse::Usage));
+        assert_eq!(parse(&args("hatch fly")), Err(Misuse::Unknown("fly".to_string())));
+    }
+
+    #[test]
+    fn test_programs() {
+        assert_eq!(parse(&args("hatch supervise --status")), Ok(Command::Run("supervise", Action::Status)));
+        assert_eq!(parse(&args("hatch supervise --now")), Err(Misuse::UsageOf("supervise")));
+        assert_eq!(parse(&args("hatch watchdog -h")), Ok(Command::Help(Some("watchdog"))));
+        assert_eq!(parse(&args("hatch attest")), Err(Misuse::UsageOf("attest")));
+        assert_eq!(parse(&args("hatch attest n1")),
+                   Ok(Command::Run("attest", Action::Attest { nonce: "n1".to_string() })));
+
+        // through a link named like the program
+        assert_eq!(parse(&args("/usr/sbin/watchdog")), Ok(Command::Run("watchdog", Action::Watchdog)));
+    }
+
+    #[cfg(feature = "lifeline")]
+    #[test]
+    fn test_lifeline() {
+        // version 1 until the servers speak version 2
+        assert_eq!(parse(&args("/bin/lifeline")), Ok(Command::Run("lifeline", Action::Lifeline)));
+        assert_eq!(parse(&args("hatch lifeline2")), Ok(Command::Run("lifeline2", Action::Lifeline2)));
+    }
+
+    #[cfg(feature = "portal")]
+    #[test]
+    fn test_sentry() {
+        assert_eq!(parse(&args("hatch sentry --port 8080 --redirect-url /tmp/url")),
+                   Ok(Command::Run("sentry", Action::Sentry { port: Some(8080), redirect_url: Some("/tmp/url".to_string()) })));
+        assert_eq!(parse(&args("hatch sentry --port 80a")), Err(Misuse::InvalidPort("80a".to_string())));
+        assert_eq!(parse(&args("hatch sentry --port")), Err(Misuse::UsageOf("sentry")));
+        assert_eq!(parse(&args("hatch sentry --color red")), Err(Misuse::UsageOf("sentry")));
+    }
+}
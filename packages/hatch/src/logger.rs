@@ -0,0 +1,644 @@
+//! hatch's logger. Every record goes to stderr and the local syslog, and as
+//! a structured record with the service, the device identity and the module
+//! it came from
+//!
+//! - into `/tmp/hatch.log`, which holds about the last `ring` records of every
+//!   hatch process and which the operators fetch with the lifeline command
+//!   `logs`. The services write there whether they run in `hatch supervise`
+//!   or each on its own,
+//! - to a remote syslog in RFC 5424, if `syslog` is set. The root is a tmpfs,
+//!   the local logs do not survive a reboot.
+//!
+//! Configured in the `[log]` section of `/etc/hatch.toml`:
+//!
+//! ```toml
+//! [log]
+//! level = "info"
+//! syslog = "udp://logs.example.com:514"
+//! ring = 256
+//!
+//! [log.modules]
+//! "lifeline::dns" = "debug"
+//! ```
+//!
+//! `syslog` is `udp://host:port` or `tcp://host:port`. A module takes the
+//! level of the longest prefix of its path in `modules`, or `level`.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use failure::{self, Error};
+use libc;
+use log::{self, Level, LevelFilter, Metadata, Record};
+use nix::unistd;
+use toml;
+
+use supervise::CONFIG_PATH;
+
+const DEFAULT_RING: usize = 256;
+/// Shared by all hatch processes, each appends its records.
+const SHARED_PATH: &str = "/tmp/hatch.log";
+/// About the length of a record, the file is rotated by size to spare the
+/// writers counting lines.
+const LINE_BYTES: u64 = 160;
+/// Records waiting for the remote syslog, more are dropped.
+const QUEUE_LEN: usize = 256;
+/// How long the remote syslog is left alone after it failed.
+const RETRY_SECS: u64 = 10;
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+/// RFC 5424 facility "system daemons".
+const FACILITY: u8 = 3;
+/// SD-ID of the structured data. hatch has no enterprise number of its own,
+/// 32473 is the one reserved for examples (RFC 5612).
+const SD_ID: &str = "hatch@32473";
+
+lazy_static! {
+    static ref SHARED: Mutex<Shared> = Mutex::new(Shared::new(SHARED_PATH, DEFAULT_RING));
+    static ref CONTEXT: Mutex<Context> = Mutex::new(Context::default());
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+fn default_ring() -> usize {
+    DEFAULT_RING
+}
+
+/// The `[log]` section of `/etc/hatch.toml`.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_level")]
+    pub level: String,
+    /// Levels by module path, instead of `level`.
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    /// Where to forward the records to, `udp://host:port` or `tcp://host:port`.
+    #[serde(default)]
+    pub syslog: Option<String>,
+    /// How many records are kept for the `logs` command, of all hatch
+    /// processes together.
+    #[serde(default = "default_ring")]
+    pub ring: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            level: default_level(),
+            modules: BTreeMap::new(),
+            syslog: None,
+            ring: DEFAULT_RING,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    log: Config,
+}
+
+impl Config {
+    /// The defaults without a config file.
+    pub fn load() -> Result<Config, Error> {
+        let mut s = String::new();
+        match File::open(CONFIG_PATH) {
+            Ok(mut f) => f.read_to_string(&mut s)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let file: ConfigFile = toml::from_str(&s)?;
+        Ok(file.log)
+    }
+}
+
+/// The level of each module.
+#[derive(Debug)]
+struct Levels {
+    default: LevelFilter,
+    /// Longest path first, the first prefix of a module is its level.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, Error> {
+    LevelFilter::from_str(level).map_err(|_| failure::err_msg(format!("invalid log level {}", level)))
+}
+
+impl Levels {
+    fn parse(config: &Config) -> Result<Levels, Error> {
+        let mut modules = Vec::new();
+        for (module, level) in &config.modules {
+            modules.push((module.clone(), parse_level(level)?));
+        }
+        modules.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+        Ok(Levels {
+            default: parse_level(&config.level)?,
+            modules: modules,
+        })
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        for &(ref module, level) in &self.modules {
+            if target == module || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::")) {
+                return level;
+            }
+        }
+        self.default
+    }
+
+    /// The most verbose level of any module.
+    fn max(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level).fold(self.default, cmp::max)
+    }
+}
+
+/// Who is logging, see `set_service` and `set_identity`.
+#[derive(Default)]
+struct Context {
+    service: String,
+    id: String,
+}
+
+/// The program hatch runs as, the service of records logged outside of the
+/// threads of `hatch supervise`.
+pub fn set_service(service: &str) {
+    CONTEXT.lock().unwrap().service = service.to_string();
+}
+
+/// The identity of the device, once it is known.
+pub fn set_identity(id: &str) {
+    CONTEXT.lock().unwrap().id = id.to_string();
+}
+
+/// One structured record.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub level: Level,
+    pub service: String,
+    pub id: String,
+    /// Where it was logged: module, file and line.
+    pub fields: Vec<(&'static str, String)>,
+    pub message: String,
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// An RFC 5424 PARAM-VALUE.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// An RFC 5424 header field, which is printable ascii without spaces.
+fn header(value: &str, max: usize) -> String {
+    let value: String = value.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+impl Entry {
+    /// As an RFC 5424 message from `hostname`.
+    pub fn to_syslog(&self, hostname: &str) -> String {
+        let mut data = format!("[{} id=\"{}\"", SD_ID, escape(&self.id));
+        for &(key, ref value) in &self.fields {
+            data.push_str(&format!(" {}=\"{}\"", key, escape(value)));
+        }
+        data.push(']');
+
+        format!(
+            "<{}>1 {} {} hatch {} {} {} {}",
+            FACILITY * 8 + severity(self.level),
+            self.time.format("%Y-%m-%dT%H:%M:%S%.6fZ"),
+            header(hostname, 255),
+            unistd::getpid(),
+            header(&self.service, 32),
+            data,
+            self.message
+        )
+    }
+
+    /// As a line of the `logs` command.
+    pub fn to_line(&self) -> String {
+        let module = self.fields.iter()
+            .find(|&&(key, _)| key == "module")
+            .map(|&(_, ref value)| value.as_str())
+            .unwrap_or("-");
+        format!("{} {:<5} {} {}: {}",
+                self.time.format("%Y-%m-%dT%H:%M:%SZ"), self.level, header(&self.service, 32), module, self.message)
+    }
+}
+
+/// The records of all hatch processes, in a file and the one before it.
+struct Shared {
+    path: PathBuf,
+    ring: usize,
+    /// Whether a failed write was reported, it is not every time.
+    failed: bool,
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    path.with_extension("log.1")
+}
+
+impl Shared {
+    fn new<P: AsRef<Path>>(path: P, ring: usize) -> Shared {
+        Shared {
+            path: path.as_ref().to_path_buf(),
+            ring: ring,
+            failed: false,
+        }
+    }
+
+    /// Must not log, like `forward`.
+    fn append(&mut self, line: &str) {
+        match self.try_append(line) {
+            Ok(()) => self.failed = false,
+            Err(e) => if !self.failed {
+                eprintln!("cannot write {}: {}", self.path.display(), e);
+                self.failed = true;
+            },
+        }
+    }
+
+    fn try_append(&self, line: &str) -> io::Result<()> {
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        // one write, the other processes cannot come in between
+        f.write_all(format!("{}\n", line).as_bytes())?;
+
+        if f.metadata()?.len() > self.ring as u64 * LINE_BYTES {
+            fs::rename(&self.path, rotated(&self.path))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(any(feature = "lifeline", test))]
+    fn recent(&self, last: Option<usize>) -> String {
+        let mut buf = String::new();
+        for path in &[rotated(&self.path), self.path.clone()] {
+            if let Ok(mut f) = File::open(path) {
+                let _ = f.read_to_string(&mut buf);
+            }
+        }
+
+        let lines: Vec<&str> = buf.lines().collect();
+        let n = cmp::min(last.unwrap_or(self.ring), self.ring);
+        lines[lines.len().saturating_sub(n)..].join("\n")
+    }
+}
+
+/// The last `last` records of all hatch processes, or the last `ring`, one
+/// per line.
+#[cfg(feature = "lifeline")]
+pub fn recent(last: Option<usize>) -> String {
+    SHARED.lock().unwrap().recent(last)
+}
+
+enum Target {
+    Udp(String),
+    Tcp(String),
+}
+
+impl Target {
+    fn parse(url: &str) -> Result<Target, Error> {
+        if url.starts_with("udp://") {
+            Ok(Target::Udp(url["udp://".len()..].to_string()))
+        } else if url.starts_with("tcp://") {
+            Ok(Target::Tcp(url["tcp://".len()..].to_string()))
+        } else {
+            Err(failure::err_msg(format!("invalid syslog {}, expected udp://host:port or tcp://host:port", url)))
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        match *self {
+            Target::Udp(ref addr) => {
+                let addr = resolve(addr)?;
+                let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(addr)?;
+                Ok(Connection::Udp(socket))
+            }
+            Target::Tcp(ref addr) => {
+                let stream = TcpStream::connect_timeout(&resolve(addr)?, Duration::from_secs(CONNECT_TIMEOUT_SECS))?;
+                stream.set_write_timeout(Some(Duration::from_secs(CONNECT_TIMEOUT_SECS)))?;
+                Ok(Connection::Tcp(stream))
+            }
+        }
+    }
+}
+
+fn resolve(addr: &str) -> io::Result<::std::net::SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", addr)))
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match *self {
+            Connection::Udp(ref socket) => socket.send(message.as_bytes()).map(|_| ()),
+            // octet counting, RFC 6587
+            Connection::Tcp(ref mut stream) => stream.write_all(format!("{} {}", message.len(), message).as_bytes()),
+        }
+    }
+}
+
+/// Sends the records to the remote syslog until hatch exits. Must not log,
+/// its own records would come back to it.
+fn forward(target: Target, records: Receiver<String>) {
+    let mut connection = None;
+    let mut retry = Instant::now();
+
+    for message in records {
+        if connection.is_none() && Instant::now() >= retry {
+            match target.connect() {
+                Ok(c) => connection = Some(c),
+                Err(e) => {
+                    eprintln!("cannot reach the syslog: {}", e);
+                    retry = Instant::now() + Duration::from_secs(RETRY_SECS);
+                }
+            }
+        }
+
+        let sent = match connection {
+            Some(ref mut c) => c.send(&message),
+            None => continue,
+        };
+        if let Err(e) = sent {
+            eprintln!("cannot send to the syslog: {}", e);
+            connection = None;
+            retry = Instant::now() + Duration::from_secs(RETRY_SECS);
+        }
+    }
+}
+
+pub struct Logger {
+    levels: Levels,
+    hostname: String,
+    remote: Option<Mutex<SyncSender<String>>>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = format!("{}", record.args());
+        eprintln!("{} - {}", record.level(), message);
+
+        let sysloglvl = match record.level() {
+            log::Level::Error => Some(libc::LOG_ERR),
+            log::Level::Warn  => Some(libc::LOG_WARNING),
+            log::Level::Info  => Some(libc::LOG_INFO),
+            log::Level::Debug => Some(libc::LOG_DEBUG),
+            log::Level::Trace => None,
+        };
+
+        if let Some(level) = sysloglvl {
+            if let Ok(cmsg) = CString::new(message.clone()) {
+                unsafe{libc::syslog(level, cmsg.as_ptr());};
+            }
+        }
+
+        let mut fields = vec![("module", record.module_path().unwrap_or(record.target()).to_string())];
+        if let (Some(file), Some(line)) = (record.file(), record.line()) {
+            fields.push(("file", file.to_string()));
+            fields.push(("line", line.to_string()));
+        }
+
+        let entry = {
+            let context = CONTEXT.lock().unwrap();
+            // the threads of `hatch supervise` are named after their service
+            let service = match thread::current().name() {
+                Some(name) if name != "main" => name.to_string(),
+                _ => context.service.clone(),
+            };
+            Entry {
+                time: Utc::now(),
+                level: record.level(),
+                service: service,
+                id: context.id.clone(),
+                fields: fields,
+                message: message,
+            }
+        };
+
+        if let Some(ref remote) = self.remote {
+            // dropped while the syslog is behind
+            let _ = remote.lock().unwrap().try_send(entry.to_syslog(&self.hostname));
+        }
+
+        SHARED.lock().unwrap().append(&entry.to_line());
+    }
+
+    fn flush(&self) {}
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 64];
+    unistd::gethostname(&mut buf).ok()
+        .and_then(|name| name.to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+/// Installs the logger with the config from `/etc/hatch.toml`, or the
+/// defaults if it is invalid.
+pub fn init() {
+    let mut problems = Vec::new();
+
+    let config = Config::load().unwrap_or_else(|e| {
+        problems.push(format!("cannot load {}: {}", CONFIG_PATH, e));
+        Config::default()
+    });
+    let levels = Levels::parse(&config).unwrap_or_else(|e| {
+        problems.push(format!("{}, logging at info", e));
+        Levels { default: LevelFilter::Info, modules: Vec::new() }
+    });
+
+    let remote = match config.syslog.as_ref().map(|url| Target::parse(url)) {
+        None => None,
+        Some(Err(e)) => {
+            problems.push(e.to_string());
+            None
+        }
+        Some(Ok(target)) => {
+            let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+            match thread::Builder::new().name("syslog".to_string()).spawn(move || forward(target, rx)) {
+                Ok(_) => Some(Mutex::new(tx)),
+                Err(e) => {
+                    problems.push(format!("cannot start the syslog forwarder: {}", e));
+                    None
+                }
+            }
+        }
+    };
+
+    SHARED.lock().unwrap().ring = config.ring;
+    log::set_max_level(levels.max());
+    log::set_boxed_logger(Box::new(Logger {
+        levels: levels,
+        hostname: hostname(),
+        remote: remote,
+    })).unwrap();
+
+    for problem in problems {
+        warn!("{}", problem);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_levels() {
+        let mut config = Config::default();
+        config.modules.insert("lifeline".to_string(), "warn".to_string());
+        config.modules.insert("lifeline::dns".to_string(), "trace".to_string());
+        let levels = Levels::parse(&config).unwrap();
+
+        assert_eq!(levels.level("hatch::cli"), LevelFilter::Info);
+        assert_eq!(levels.level("lifeline"), LevelFilter::Warn);
+        assert_eq!(levels.level("lifeline::device"), LevelFilter::Warn);
+        assert_eq!(levels.level("lifeline::dns"), LevelFilter::Trace);
+        assert_eq!(levels.level("lifeline1"), LevelFilter::Info);
+        assert_eq!(levels.max(), LevelFilter::Trace);
+
+        config.modules.insert("sentry".to_string(), "loud".to_string());
+        assert!(Levels::parse(&config).is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        let file: ConfigFile = toml::from_str("supervise = true\n").unwrap();
+        assert_eq!(file.log.level, "info");
+        assert_eq!(file.log.ring, DEFAULT_RING);
+
+        let file: ConfigFile = toml::from_str(r#"
+            [log]
+            level = "warn"
+            syslog = "tcp://10.0.0.1:601"
+
+            [log.modules]
+            "lifeline::dns" = "debug"
+        "#).unwrap();
+        assert_eq!(file.log.level, "warn");
+        assert_eq!(file.log.syslog, Some("tcp://10.0.0.1:601".to_string()));
+        assert_eq!(file.log.modules["lifeline::dns"], "debug");
+    }
+
+    #[test]
+    fn test_syslog_format() {
+        let entry = Entry {
+            time: Utc.ymd(2018, 5, 2).and_hms_milli(10, 20, 30, 400),
+            level: Level::Warn,
+            service: "lifeline".to_string(),
+            id: "abc".to_string(),
+            fields: vec![("module", "lifeline::dns".to_string()), ("line", "7".to_string())],
+            message: "no \"servers\" [left]".to_string(),
+        };
+
+        assert_eq!(
+            entry.to_syslog("gf 1"),
+            format!("<28>1 2018-05-02T10:20:30.400000Z gf1 hatch {} lifeline \
+                     [hatch@32473 id=\"abc\" module=\"lifeline::dns\" line=\"7\"] no \"servers\" [left]",
+                    unistd::getpid())
+        );
+        assert_eq!(entry.to_line(), "2018-05-02T10:20:30Z WARN  lifeline lifeline::dns: no \"servers\" [left]");
+
+        let mut quoted = entry.clone();
+        quoted.id = "a\"b]c\\".to_string();
+        assert!(quoted.to_syslog("-").contains("id=\"a\\\"b\\]c\\\\\""));
+    }
+
+    #[test]
+    fn test_shared() {
+        let path = ::std::env::temp_dir().join(format!("hatch-test-{}.log", unistd::getpid()));
+        let mut shared = Shared::new(&path, 3);
+
+        // another process writes to the same file
+        shared.append("a");
+        Shared::new(&path, 3).append("b");
+        shared.append("c");
+        shared.append("d");
+        assert_eq!(shared.recent(None), "b\nc\nd");
+        assert_eq!(shared.recent(Some(2)), "c\nd");
+        assert_eq!(shared.recent(Some(10)), "b\nc\nd");
+
+        // rotated, the records before are still there
+        let long = "x".repeat(3 * LINE_BYTES as usize);
+        shared.append(&long);
+        assert!(!path.exists());
+        shared.append("e");
+        assert_eq!(shared.recent(Some(2)), format!("{}\ne", long));
+
+        fs::remove_file(rotated(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_target() {
+        match Target::parse("udp://10.0.0.1:514").unwrap() {
+            Target::Udp(addr) => assert_eq!(addr, "10.0.0.1:514"),
+            _ => panic!("expected udp"),
+        }
+        match Target::parse("tcp://logs:601").unwrap() {
+            Target::Tcp(addr) => assert_eq!(addr, "logs:601"),
+            _ => panic!("expected tcp"),
+        }
+        assert!(Target::parse("logs:514").is_err());
+    }
+
+    #[test]
+    fn test_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = Target::Udp(server.local_addr().unwrap().to_string());
+        target.connect().unwrap().send("<14>1 - - hatch - - - hi").unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"<14>1 - - hatch - - - hi");
+    }
+}
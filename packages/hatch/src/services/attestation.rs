@@ -0,0 +1,67 @@
+//! Signs attestation statements about this device, see
+//! `identity::attestation`.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use failure::{self, Error};
+use identity::attestation::{Attestation, Facts};
+use identity::{self, Identity};
+use serde_json;
+use services::unix_now;
+use sha2::{Digest, Sha256};
+
+const MAC_PARTITION: &str = "mac";
+/// Where the Archer C7 v4 keeps its MAC address in the `mac` partition.
+const MAC_OFFSET: usize = 8;
+const FIRMWARE_PARTITION: &str = "firmware";
+/// The genesis package, a link named by its hash.
+const GENESIS_LINK: &str = "/genesis/genesis";
+
+fn partition(name: &str) -> Result<File, Error> {
+    let path = identity::mtd_partition(Path::new(identity::PROC_MTD), Path::new(identity::DEV_DIR), name)?;
+    File::open(&path).map_err(|e| failure::err_msg(format!("{}: {}", path.display(), e)))
+}
+
+fn mac() -> Result<String, Error> {
+    let mut buf = [0u8; MAC_OFFSET + 6];
+    partition(MAC_PARTITION)?.read_exact(&mut buf)?;
+    let octets: Vec<String> = buf[MAC_OFFSET..].iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(octets.join(":"))
+}
+
+fn firmware() -> Result<String, Error> {
+    let mut f = partition(FIRMWARE_PARTITION)?;
+    let mut hasher = Sha256::default();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.input(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(hasher.result().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn genesis() -> Result<String, Error> {
+    let target = fs::read_link(GENESIS_LINK).map_err(|e| failure::err_msg(format!("{}: {}", GENESIS_LINK, e)))?;
+    Ok(target.to_string_lossy().into_owned())
+}
+
+pub fn facts() -> Result<Facts, Error> {
+    Ok(Facts {
+        mac: mac()?,
+        firmware: firmware()?,
+        genesis: genesis()?,
+    })
+}
+
+/// A statement for `nonce`, as json.
+pub fn attest(nonce: &str) -> Result<String, Error> {
+    let identity = Identity::load()?;
+    let attestation = Attestation::sign(&identity, facts()?, nonce, unix_now())?;
+    Ok(serde_json::to_string(&attestation)?)
+}
@@ -0,0 +1,41 @@
+//! Runs the commands the operators send over lifeline.
+
+use failure::{self, Error};
+use lifeline::command::Command;
+use logger;
+#[cfg(any(feature = "access-control", feature = "time-control"))]
+use sentry;
+use services::{attestation, system};
+
+pub fn run(command: &Command) -> Result<String, Error> {
+    match *command {
+        Command::Reboot => system::reboot(),
+        Command::Genesis => system::genesis(),
+        #[cfg(feature = "access-control")]
+        Command::RevokeSessions => {
+            let revoked = sentry::revoke_all().map_err(|e| failure::err_msg(e.to_string()))?;
+            Ok(format!("revoked {} clients", revoked))
+        }
+        #[cfg(not(feature = "access-control"))]
+        Command::RevokeSessions => Err(not_built("access-control")),
+        #[cfg(feature = "time-control")]
+        Command::PublicWifi { enabled } => {
+            sentry::override_public_wifi(enabled).map_err(|e| failure::err_msg(e.to_string()))?;
+            Ok(match enabled {
+                Some(true) => "public wifi on".to_string(),
+                Some(false) => "public wifi off".to_string(),
+                None => "public wifi follows the schedule".to_string(),
+            })
+        }
+        #[cfg(not(feature = "time-control"))]
+        Command::PublicWifi { .. } => Err(not_built("time-control")),
+        Command::Attest { ref nonce } => attestation::attest(nonce),
+        Command::Logs { last } => Ok(logger::recent(last.map(|n| n as usize))),
+    }
+}
+
+/// For the commands of a service this hatch was built without.
+#[cfg(not(all(feature = "access-control", feature = "time-control")))]
+fn not_built(feature: &str) -> Error {
+    failure::err_msg(format!("hatch is built without {}", feature))
+}
@@ -0,0 +1,104 @@
+//! A record of every stream to a local service, so it can be reconstructed
+//! who reached which device and what for.
+//!
+//! Records are logged, which ends up in syslog, and sent as the ubus event
+//! `EVENT` for anything on the device that listens.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::process::Command;
+
+use serde_json;
+
+pub const EVENT: &'static str = "lifeline.session";
+
+/// Handles a finished session, `record` unless replaced.
+pub type Auditor = Box<Fn(&Session)>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// Lifeline protocol version, 1 or 2.
+    pub protocol: u8,
+    /// Hostname of the lifeline server and the address it was reached at.
+    /// Behind a proxy that is the address of the proxy.
+    pub server: String,
+    pub server_ip: Option<IpAddr>,
+    /// Stream id, v2 only.
+    pub stream: Option<u32>,
+    pub service: String,
+    pub target: SocketAddr,
+    /// Who opened the stream, if the server said.
+    pub operator: Option<String>,
+    /// unix times.
+    pub start: u64,
+    pub end: u64,
+    /// Bytes from the server to the service, and back.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// `closed`, `aborted`, `idle`, `deadline` or an error.
+    pub reason: String,
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {} for {} via {}", self.service, self.target,
+               self.operator.as_ref().map(|o| o.as_str()).unwrap_or("unknown operator"), self.server)?;
+        if let Some(ip) = self.server_ip {
+            write!(f, " ({})", ip)?;
+        }
+        if let Some(stream) = self.stream {
+            write!(f, " stream {}", stream)?;
+        }
+        write!(f, ", {} to {} ({}s), {} bytes in, {} bytes out, {}",
+               self.start, self.end, self.end.saturating_sub(self.start), self.bytes_in, self.bytes_out, self.reason)
+    }
+}
+
+/// Logs the session and sends it as a ubus event.
+pub fn record(session: &Session) {
+    info!("session: {}", session);
+
+    let json = match serde_json::to_string(session) {
+        Ok(json) => json,
+        Err(e) => {
+            warn!("cannot encode session: {}", e);
+            return;
+        }
+    };
+    match Command::new("ubus").args(&["send", EVENT, &json]).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => debug!("ubus send {} failed: {}", EVENT, status),
+        Err(e) => debug!("cannot run ubus: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut session = Session {
+            protocol: 2,
+            server: "lifeline.exys.org".to_string(),
+            server_ip: Some("192.0.2.1".parse().unwrap()),
+            stream: Some(4),
+            service: "ssh".to_string(),
+            target: "127.0.0.1:22".parse().unwrap(),
+            operator: Some("alice".to_string()),
+            start: 1_600_000_000,
+            end: 1_600_000_300,
+            bytes_in: 1200,
+            bytes_out: 5400,
+            reason: "closed".to_string(),
+        };
+        assert_eq!(session.to_string(),
+                   "ssh at 127.0.0.1:22 for alice via lifeline.exys.org (192.0.2.1) stream 4, \
+                    1600000000 to 1600000300 (300s), 1200 bytes in, 5400 bytes out, closed");
+
+        session.operator = None;
+        session.server_ip = None;
+        session.stream = None;
+        assert!(session.to_string().starts_with("ssh at 127.0.0.1:22 for unknown operator via lifeline.exys.org, "));
+    }
+}
@@ -0,0 +1,185 @@
+//! Mutual authentication on top of the websocket.
+//!
+//! 1. The device sends a random nonce in the `X-LF-Nonce` header of the upgrade
+//!    request, next to its identity in `X-LF-Name`.
+//! 2. The server answers with a `Challenge` text frame: its own nonce and a
+//!    signature over both nonces and the identity, made with the server key the
+//!    device has pinned.
+//! 3. The device answers with a `Proof` text frame: a signature over both nonces
+//!    and the identity, made with the device key. The identity is the base58
+//!    public key, so the server needs nothing else to verify it.
+//!
+//! Nothing of this is bound to the stream it runs on. Without TLS, anyone in
+//! between can relay the handshake and then speak for the server, so the
+//! device only bridges ssh on such a session and runs no commands.
+
+use base64;
+use bs58;
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+use rand;
+use sha2::Sha512;
+
+pub const NONCE_LEN: usize = 32;
+
+const SERVER_CONTEXT: &'static [u8] = b"lifeline.2 server";
+const DEVICE_CONTEXT: &'static [u8] = b"lifeline.2 device";
+
+/// Sent by the server right after the upgrade.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Challenge {
+    /// base64 server nonce
+    pub nonce: String,
+    /// base64 signature of the server
+    pub signature: String,
+}
+
+/// Sent by the device in answer to the challenge.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Proof {
+    /// base64 signature of the device
+    pub signature: String,
+}
+
+pub fn nonce() -> [u8; NONCE_LEN] {
+    rand::random()
+}
+
+pub fn encode_key(key: &PublicKey) -> String {
+    bs58::encode(key.as_bytes())
+        .with_alphabet(bs58::alphabet::BITCOIN)
+        .into_string()
+}
+
+/// Decodes a base58 public key, as used for identities and pinned server keys.
+pub fn decode_key(key: &str) -> Option<PublicKey> {
+    bs58::decode(key)
+        .with_alphabet(bs58::alphabet::BITCOIN)
+        .into_vec()
+        .ok()
+        .and_then(|k| PublicKey::from_bytes(&k).ok())
+}
+
+fn transcript(context: &[u8], first: &[u8], second: &[u8], identity: &str) -> Vec<u8> {
+    let mut t = Vec::with_capacity(context.len() + first.len() + second.len() + identity.len());
+    t.extend_from_slice(context);
+    t.extend_from_slice(first);
+    t.extend_from_slice(second);
+    t.extend_from_slice(identity.as_bytes());
+    t
+}
+
+pub fn decode_signature(signature: &str) -> Option<Signature> {
+    base64::decode(signature)
+        .ok()
+        .and_then(|s| Signature::from_bytes(&s).ok())
+}
+
+/// Creates the challenge for a device that connected with `device_nonce`.
+pub fn challenge(server: &Keypair, device_nonce: &[u8], server_nonce: &[u8], identity: &str) -> Challenge {
+    let sig = server.sign::<Sha512>(&transcript(SERVER_CONTEXT, device_nonce, server_nonce, identity));
+    Challenge {
+        nonce: base64::encode(server_nonce),
+        signature: base64::encode(&sig.to_bytes()[..]),
+    }
+}
+
+/// Verifies the challenge against the pinned server key and returns the server nonce.
+pub fn verify_challenge(server: &PublicKey, device_nonce: &[u8], identity: &str, challenge: &Challenge)
+    -> Option<Vec<u8>>
+{
+    let server_nonce = match base64::decode(&challenge.nonce) {
+        Ok(ref n) if n.len() == NONCE_LEN => n.clone(),
+        _ => return None,
+    };
+    let sig = decode_signature(&challenge.signature)?;
+
+    if server.verify::<Sha512>(&transcript(SERVER_CONTEXT, device_nonce, &server_nonce, identity), &sig) {
+        Some(server_nonce)
+    } else {
+        None
+    }
+}
+
+/// Proves possession of the device key.
+pub fn prove(device: &Keypair, device_nonce: &[u8], server_nonce: &[u8], identity: &str) -> Proof {
+    let sig = device.sign::<Sha512>(&transcript(DEVICE_CONTEXT, server_nonce, device_nonce, identity));
+    Proof {
+        signature: base64::encode(&sig.to_bytes()[..]),
+    }
+}
+
+/// Verifies that the device owns the key its identity is made of.
+pub fn verify_proof(identity: &str, device_nonce: &[u8], server_nonce: &[u8], proof: &Proof) -> bool {
+    let key = match decode_key(identity) {
+        Some(key) => key,
+        None => return false,
+    };
+    let sig = match decode_signature(&proof.signature) {
+        Some(sig) => sig,
+        None => return false,
+    };
+
+    key.verify::<Sha512>(&transcript(DEVICE_CONTEXT, server_nonce, device_nonce, identity), &sig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::OsRng;
+
+    fn keypair() -> Keypair {
+        Keypair::generate::<Sha512>(&mut OsRng::new().unwrap())
+    }
+
+    #[test]
+    fn test_mutual() {
+        let server = keypair();
+        let device = keypair();
+        let identity = encode_key(&device.public);
+
+        let device_nonce = nonce();
+        let server_nonce = nonce();
+
+        let c = challenge(&server, &device_nonce, &server_nonce, &identity);
+        let got = verify_challenge(&server.public, &device_nonce, &identity, &c).unwrap();
+        assert_eq!(&got[..], &server_nonce[..]);
+
+        let p = prove(&device, &device_nonce, &got, &identity);
+        assert!(verify_proof(&identity, &device_nonce, &server_nonce, &p));
+    }
+
+    #[test]
+    fn test_wrong_server_key() {
+        let server = keypair();
+        let device_nonce = nonce();
+
+        let c = challenge(&server, &device_nonce, &nonce(), "device");
+        assert!(verify_challenge(&keypair().public, &device_nonce, "device", &c).is_none());
+    }
+
+    #[test]
+    fn test_replayed_challenge() {
+        let server = keypair();
+
+        let c = challenge(&server, &nonce(), &nonce(), "device");
+        assert!(verify_challenge(&server.public, &nonce(), "device", &c).is_none());
+    }
+
+    #[test]
+    fn test_impersonated_device() {
+        let device = keypair();
+        let identity = encode_key(&device.public);
+        let device_nonce = nonce();
+        let server_nonce = nonce();
+
+        let p = prove(&keypair(), &device_nonce, &server_nonce, &identity);
+        assert!(!verify_proof(&identity, &device_nonce, &server_nonce, &p));
+    }
+
+    #[test]
+    fn test_decode_key() {
+        let k = keypair();
+        assert_eq!(decode_key(&encode_key(&k.public)).unwrap().as_bytes(), k.public.as_bytes());
+        assert!(decode_key("not a key").is_none());
+    }
+}
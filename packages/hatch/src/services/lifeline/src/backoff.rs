@@ -0,0 +1,154 @@
+//! Pacing reconnects, so an outage of the servers does not make the whole
+//! fleet reconnect in lockstep once they are back.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// Exponential backoff with full jitter: after n failures the wait is
+/// uniformly random between zero and `min(cap, base * 2^n)`.
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration) -> Backoff {
+        Backoff {
+            base: base,
+            cap: cap,
+            failures: 0,
+        }
+    }
+
+    /// Number of failures since the last success.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn fail(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// The upper bound of the next wait.
+    pub fn ceiling(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.failures).unwrap_or(u32::max_value());
+        let ceiling = self.base.checked_mul(factor).unwrap_or(self.cap);
+        cmp::min(ceiling, self.cap)
+    }
+
+    /// Returns how long to wait before the next attempt.
+    pub fn wait<R: Rng>(&self, rng: &mut R) -> Duration {
+        let ceiling = self.ceiling();
+
+        let ms = ceiling.as_secs() * 1000 + (ceiling.subsec_nanos() / 1_000_000) as u64;
+        if ms == 0 {
+            return Duration::from_millis(0);
+        }
+        Duration::from_millis(rng.gen_range(0, ms + 1))
+    }
+}
+
+/// Allows at most `max` attempts in any `window`.
+pub struct RateLimit {
+    max: usize,
+    window: Duration,
+    attempts: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(max: usize, window: Duration) -> RateLimit {
+        RateLimit {
+            max: max,
+            window: window,
+            attempts: VecDeque::new(),
+        }
+    }
+
+    /// How long to wait until another attempt is allowed, if at all.
+    pub fn delay(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(&first) = self.attempts.front() {
+            if now.duration_since(first) >= self.window {
+                self.attempts.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.attempts.len() < self.max {
+            return None;
+        }
+
+        self.attempts.front().map(|first| self.window - now.duration_since(*first))
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.attempts.push_back(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, XorShiftRng};
+
+    #[test]
+    fn test_backoff_grows_to_cap() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+
+        let ceilings: Vec<u64> = (0..10).map(|_| {
+            let ceiling = b.ceiling();
+            assert!(b.wait(&mut rng) <= ceiling);
+            b.fail();
+            ceiling.as_secs()
+        }).collect();
+
+        assert_eq!(ceilings, vec![1, 2, 4, 8, 16, 32, 60, 60, 60, 60]);
+        assert_eq!(b.failures(), 10);
+
+        b.reset();
+        assert_eq!(b.ceiling(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_backoff_no_overflow() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(300));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+
+        for _ in 0..100 {
+            b.fail();
+        }
+        assert_eq!(b.ceiling(), Duration::from_secs(300));
+        assert!(b.wait(&mut rng) <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_backoff_jitters() {
+        let b = Backoff::new(Duration::from_secs(100), Duration::from_secs(100));
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+
+        let first = b.wait(&mut rng);
+        assert!((0..10).any(|_| b.wait(&mut rng) != first));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut r = RateLimit::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert_eq!(r.delay(start), None);
+        r.record(start);
+        r.record(start + Duration::from_secs(10));
+
+        assert_eq!(r.delay(start + Duration::from_secs(20)), Some(Duration::from_secs(40)));
+        assert_eq!(r.delay(start + Duration::from_secs(60)), None);
+    }
+}
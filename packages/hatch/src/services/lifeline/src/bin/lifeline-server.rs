@@ -0,0 +1,261 @@
+//! Runs the server side of lifeline on a laptop.
+
+extern crate bs58;
+extern crate ed25519_dalek;
+extern crate env_logger;
+extern crate failure;
+extern crate futures;
+extern crate lifeline;
+#[macro_use] extern crate log;
+extern crate rand;
+extern crate serde_json;
+extern crate sha2;
+extern crate tokio_core;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use failure::Error;
+use futures::Future;
+use lifeline::auth;
+use lifeline::command::{self, Command, Order, Outcome, SignedCommand};
+use lifeline::discovery::{ServerList, SignedServerList};
+use lifeline::server::{self, Options, Server};
+use rand::{OsRng, Rng};
+use sha2::Sha512;
+use tokio_core::reactor::Core;
+
+const DEFAULT_LISTEN: &'static str = "0.0.0.0:8080";
+const DEFAULT_DIR: &'static str = "/tmp/lifeline";
+const DEFAULT_TTL_SECS: u64 = 600;
+
+fn usage() -> ! {
+    eprintln!("usage:");
+    eprintln!("  lifeline-server keygen <key>");
+    eprintln!("  lifeline-server serve <key> [--listen addr] [--dir dir] [--service name].. [--allow identity].. [--server-list file]");
+    eprintln!("  lifeline-server list [dir]");
+    eprintln!("  lifeline-server sign-servers <key> <version> <host>..");
+    eprintln!("  lifeline-server sign-command <operator key> <identity> [--ttl secs] reboot|genesis|revoke-sessions|public-wifi on|off|schedule|attest <nonce>|logs [count]");
+    eprintln!("  lifeline-server command [dir] < signed-command");
+    process::exit(1);
+}
+
+fn fail(e: Error) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+/// A key file holds the 32 bytes of the secret key, like the identity
+/// partition of a device.
+fn load_key(path: &str) -> Result<Keypair, Error> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < 32 {
+        return Err(failure::err_msg(format!("{} is too short for a key", path)));
+    }
+
+    let secret = SecretKey::from_bytes(&buf[..32]).map_err(|e| failure::err_msg(format!("{}: {}", path, e)))?;
+    let public = PublicKey::from_secret::<Sha512>(&secret);
+    Ok(Keypair { secret: secret, public: public })
+}
+
+fn keygen(args: &[String]) {
+    let path = args.get(0).unwrap_or_else(|| usage());
+
+    let mut secret = [0u8; 32];
+    if let Err(e) = OsRng::new().map(|mut rng| rng.fill_bytes(&mut secret)) {
+        fail(e.into());
+    }
+
+    if let Err(e) = File::create(path).and_then(|mut f| f.write_all(&secret)) {
+        fail(e.into());
+    }
+
+    let key = load_key(path).unwrap_or_else(|e| fail(e));
+    println!("server_key = \"{}\"", auth::encode_key(&key.public));
+}
+
+fn serve(args: &[String]) {
+    let key = args.get(0).unwrap_or_else(|| usage());
+    let keypair = load_key(key).unwrap_or_else(|e| fail(e));
+
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut dir = DEFAULT_DIR.to_string();
+    let mut services = Vec::new();
+    let mut allow = Vec::new();
+    let mut server_list = None;
+
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next().unwrap_or_else(|| usage()).clone();
+        match arg.as_str() {
+            "--listen" => listen = value,
+            "--dir" => dir = value,
+            "--service" => services.push(value),
+            "--allow" => allow.push(value),
+            "--server-list" => {
+                let signed: SignedServerList = File::open(&value)
+                    .map_err(Error::from)
+                    .and_then(|f| serde_json::from_reader(f).map_err(Error::from))
+                    .unwrap_or_else(|e| fail(e));
+                if let Err(e) = signed.verify(&keypair.public) {
+                    fail(e);
+                }
+                server_list = Some(signed);
+            }
+            _ => usage(),
+        }
+    }
+
+    if services.is_empty() {
+        services.push("ssh".to_string());
+    }
+
+    let listen: SocketAddr = listen.parse().unwrap_or_else(|e| fail(failure::err_msg(format!("{}: {}", listen, e))));
+
+    info!("server_key = \"{}\"", auth::encode_key(&keypair.public));
+
+    let mut core = Core::new().unwrap_or_else(|e| fail(e.into()));
+    let server = Server::new(Options {
+        keypair: keypair,
+        listen: listen,
+        dir: PathBuf::from(dir),
+        services: services,
+        allow: allow,
+        server_list: server_list,
+    }, &core.handle()).unwrap_or_else(|e| fail(e));
+
+    let (_, accept) = server.serve().unwrap_or_else(|e| fail(e));
+    if let Err(e) = core.run(accept.map(|_| ())) {
+        fail(e);
+    }
+}
+
+fn list(args: &[String]) {
+    let dir = args.get(0).map(|s| s.as_str()).unwrap_or(DEFAULT_DIR);
+    let devices = server::list(Path::new(dir)).unwrap_or_else(|e| fail(e));
+
+    for device in devices {
+        println!("{} from {}", device.identity, device.peer);
+        for (service, port) in &device.ports {
+            println!("  {:<10} 127.0.0.1:{}  {}", service, port, device.sockets[service].display());
+        }
+        if let Some(ref t) = device.telemetry {
+            println!("  {:<10} {}", "telemetry", serde_json::to_string(t).unwrap());
+        }
+    }
+}
+
+fn sign_servers(args: &[String]) {
+    if args.len() < 3 {
+        usage();
+    }
+
+    let keypair = load_key(&args[0]).unwrap_or_else(|e| fail(e));
+    let version = args[1].parse().unwrap_or_else(|_| usage());
+    let list = ServerList {
+        version: version,
+        servers: args[2..].to_vec(),
+    };
+
+    let signed = SignedServerList::sign(&keypair, &list).unwrap_or_else(|e| fail(e));
+    println!("{}", serde_json::to_string(&signed).unwrap());
+}
+
+fn sign_command(args: &[String]) {
+    if args.len() < 3 {
+        usage();
+    }
+
+    let keypair = load_key(&args[0]).unwrap_or_else(|e| fail(e));
+    let device = args[1].clone();
+
+    let mut ttl = DEFAULT_TTL_SECS;
+    let mut rest = &args[2..];
+    if rest.len() > 2 && rest[0] == "--ttl" {
+        ttl = rest[1].parse().unwrap_or_else(|_| usage());
+        rest = &rest[2..];
+    }
+
+    let command = match (rest.get(0).map(|s| s.as_str()), rest.get(1).map(|s| s.as_str())) {
+        (Some("reboot"), None) => Command::Reboot,
+        (Some("genesis"), None) => Command::Genesis,
+        (Some("revoke-sessions"), None) => Command::RevokeSessions,
+        (Some("public-wifi"), Some("on")) => Command::PublicWifi { enabled: Some(true) },
+        (Some("public-wifi"), Some("off")) => Command::PublicWifi { enabled: Some(false) },
+        (Some("public-wifi"), Some("schedule")) => Command::PublicWifi { enabled: None },
+        (Some("attest"), Some(nonce)) => Command::Attest { nonce: nonce.to_string() },
+        (Some("logs"), None) => Command::Logs { last: None },
+        (Some("logs"), Some(count)) => Command::Logs { last: Some(count.parse().unwrap_or_else(|_| usage())) },
+        _ => usage(),
+    };
+
+    let mut id = [0u8; 16];
+    if let Err(e) = OsRng::new().map(|mut rng| rng.fill_bytes(&mut id)) {
+        fail(e.into());
+    }
+
+    let order = Order {
+        id: bs58::encode(&id[..]).into_string(),
+        device: device,
+        expires: command::unix_now() + ttl,
+        command: command,
+    };
+
+    let signed = SignedCommand::sign(&keypair, &order).unwrap_or_else(|e| fail(e));
+    println!("{}", serde_json::to_string(&signed).unwrap());
+}
+
+/// Hands a signed command to the server running in `dir` and waits for the
+/// device to run it.
+fn send_command(args: &[String]) {
+    let dir = args.get(0).map(|s| s.as_str()).unwrap_or(DEFAULT_DIR);
+
+    let mut signed = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut signed) {
+        fail(e.into());
+    }
+
+    let path = Path::new(dir).join(server::CONTROL_SOCKET);
+    let outcome: Outcome = UnixStream::connect(&path)
+        .and_then(|mut stream| {
+            stream.write_all(signed.trim().as_bytes())?;
+            stream.write_all(b"\n")?;
+
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line)?;
+            Ok(line)
+        })
+        .map_err(|e| failure::err_msg(format!("{}: {}", path.display(), e)))
+        .and_then(|line| serde_json::from_str(&line).map_err(Error::from))
+        .unwrap_or_else(|e| fail(e));
+
+    println!("{}", outcome.output.trim_right());
+    if !outcome.ok {
+        process::exit(1);
+    }
+}
+
+fn main() {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.get(0).map(|s| s.as_str()) {
+        Some("keygen") => keygen(&args[1..]),
+        Some("serve") => serve(&args[1..]),
+        Some("list") => list(&args[1..]),
+        Some("sign-servers") => sign_servers(&args[1..]),
+        Some("sign-command") => sign_command(&args[1..]),
+        Some("command") => send_command(&args[1..]),
+        _ => usage(),
+    }
+}
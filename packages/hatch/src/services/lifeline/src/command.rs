@@ -0,0 +1,257 @@
+//! Commands from the operators, relayed by the server as control messages.
+//!
+//! A command is signed with the operator key from the config, names the one
+//! device that may run it and expires after at most `MAX_TTL_SECS`. Its id is
+//! remembered until then, so a command runs at most once. The ids are kept in
+//! `SEEN_PATH` on flash, a captured command cannot be replayed after a reboot
+//! either. A device with an unset clock refuses everything, every command
+//! looks like it is valid for too long.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+use ed25519_dalek::{Keypair, PublicKey};
+use failure::Error;
+use serde_json;
+use sha2::Sha512;
+
+use auth;
+
+pub const SEEN_PATH: &'static str = "/state/lifeline.commands";
+
+/// Commands valid for longer are refused.
+pub const MAX_TTL_SECS: u64 = 3600;
+
+const COMMAND_CONTEXT: &'static [u8] = b"lifeline.2 command";
+
+/// Runs a command on the device and describes what happened. Called on a
+/// thread of its own, a command may take a while.
+pub type Executor = Arc<Fn(&Command) -> Result<String, Error> + Send + Sync>;
+
+/// What a device needs to run commands.
+pub struct Commands {
+    pub operator_key: PublicKey,
+    pub executor: Executor,
+    /// Where the ids of received commands are kept, usually `SEEN_PATH`.
+    pub seen: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Reboot,
+    /// Runs genesis with the config on the genesis partition again.
+    Genesis,
+    /// Drops all clients authorized on the public wifi.
+    RevokeSessions,
+    /// Switches the public wifi on or off until the next reboot, regardless of
+    /// the schedule. `None` goes back to the schedule.
+    PublicWifi { enabled: Option<bool> },
+    /// Signs an attestation statement for `nonce`, the output is its json.
+    Attest { nonce: String },
+    /// Answers with the last `last` records all hatch processes logged, or
+    /// all that were kept.
+    Logs { last: Option<u32> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Order {
+    /// Random, a command with an id seen before is refused.
+    pub id: String,
+    /// Identity of the device that may run the command.
+    pub device: String,
+    /// unix time after which the command is refused.
+    pub expires: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedCommand {
+    /// base64 json of the `Order`
+    pub order: String,
+    /// base64 signature over the context and the decoded `order`
+    pub signature: String,
+}
+
+/// Sent back by the device for every command it received.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Outcome {
+    pub id: String,
+    pub ok: bool,
+    /// What the command did, or why it was refused.
+    pub output: String,
+}
+
+fn signed_data(order: &[u8]) -> Vec<u8> {
+    let mut data = COMMAND_CONTEXT.to_vec();
+    data.extend_from_slice(order);
+    data
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl SignedCommand {
+    pub fn sign(key: &Keypair, order: &Order) -> Result<SignedCommand, Error> {
+        let json = serde_json::to_vec(order)?;
+        let sig = key.sign::<Sha512>(&signed_data(&json));
+
+        Ok(SignedCommand {
+            order: base64::encode(&json),
+            signature: base64::encode(&sig.to_bytes()[..]),
+        })
+    }
+
+    pub fn verify(&self, key: &PublicKey) -> Result<Order, Error> {
+        let json = base64::decode(&self.order)?;
+        let sig = auth::decode_signature(&self.signature)
+            .ok_or_else(|| format_err!("invalid command signature"))?;
+
+        if !key.verify::<Sha512>(&signed_data(&json), &sig) {
+            bail!("command is not signed by the operator key");
+        }
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// The order without checking the signature, to route the command and to
+    /// report about it. Nothing else may be done with it.
+    pub fn unverified(&self) -> Result<Order, Error> {
+        Ok(serde_json::from_slice(&base64::decode(&self.order)?)?)
+    }
+}
+
+/// Checks that `signed` is meant for this device and may run now. Its id is
+/// remembered in `seen`, a second attempt fails.
+pub fn accept(key: &PublicKey, identity: &str, signed: &SignedCommand, now: u64, seen: &Path)
+    -> Result<Order, Error>
+{
+    let order = signed.verify(key)?;
+
+    if order.device != identity {
+        bail!("command {} is for {}", order.id, order.device);
+    }
+    if order.expires < now {
+        bail!("command {} expired", order.id);
+    }
+    if order.expires > now + MAX_TTL_SECS {
+        bail!("command {} is valid for too long, or the clock is not set", order.id);
+    }
+
+    let mut ids = load_seen(seen);
+    ids.retain(|_, expires| *expires >= now);
+    if ids.contains_key(&order.id) {
+        bail!("command {} was received before", order.id);
+    }
+    ids.insert(order.id.clone(), order.expires);
+    store_seen(seen, &ids)?;
+
+    Ok(order)
+}
+
+fn load_seen(path: &Path) -> HashMap<String, u64> {
+    let mut buf = Vec::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+        Ok(_) => serde_json::from_slice(&buf).unwrap_or_else(|e| {
+            warn!("ignoring {}: {}", path.display(), e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Written before the command runs, so a command that reboots the device or
+/// kills lifeline is remembered too.
+fn store_seen(path: &Path, ids: &HashMap<String, u64>) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec(ids)?)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::OsRng;
+    use tempdir::TempDir;
+
+    fn order(id: &str, expires: u64, command: Command) -> Order {
+        Order {
+            id: id.to_string(),
+            device: "device".to_string(),
+            expires: expires,
+            command: command,
+        }
+    }
+
+    #[test]
+    fn test_accept() {
+        let dir = TempDir::new("lifeline").unwrap();
+        let seen = dir.path().join("seen");
+        let key = Keypair::generate::<Sha512>(&mut OsRng::new().unwrap());
+        let now = 1_600_000_000;
+
+        let reboot = SignedCommand::sign(&key, &order("a", now + 60, Command::Reboot)).unwrap();
+        assert_eq!(accept(&key.public, "device", &reboot, now, &seen).unwrap().command, Command::Reboot);
+
+        // replayed
+        assert!(accept(&key.public, "device", &reboot, now + 1, &seen).is_err());
+
+        // for someone else
+        assert!(accept(&key.public, "other", &SignedCommand::sign(&key, &order("b", now + 60, Command::Reboot)).unwrap(),
+                       now, &seen).is_err());
+
+        // expired, or valid for too long
+        let old = SignedCommand::sign(&key, &order("c", now - 1, Command::Genesis)).unwrap();
+        assert!(accept(&key.public, "device", &old, now, &seen).is_err());
+        let long = SignedCommand::sign(&key, &order("d", now + MAX_TTL_SECS + 1, Command::Genesis)).unwrap();
+        assert!(accept(&key.public, "device", &long, now, &seen).is_err());
+
+        // ids are forgotten once their command expired
+        assert!(load_seen(&seen).contains_key("a"));
+        let next = SignedCommand::sign(&key, &order("e", now + 120, Command::RevokeSessions)).unwrap();
+        assert!(accept(&key.public, "device", &next, now + 61, &seen).is_ok());
+        assert!(!load_seen(&seen).contains_key("a"));
+        assert!(accept(&key.public, "device", &reboot, now + 61, &seen).is_err());
+    }
+
+    #[test]
+    fn test_signature() {
+        let key = Keypair::generate::<Sha512>(&mut OsRng::new().unwrap());
+        let other = Keypair::generate::<Sha512>(&mut OsRng::new().unwrap());
+        let wifi = order("a", 100, Command::PublicWifi { enabled: Some(false) });
+
+        let mut signed = SignedCommand::sign(&key, &wifi).unwrap();
+        assert_eq!(signed.verify(&key.public).unwrap(), wifi);
+        assert!(signed.verify(&other.public).is_err());
+
+        // an order changed after signing
+        let mut changed = wifi.clone();
+        changed.command = Command::PublicWifi { enabled: Some(true) };
+        signed.order = base64::encode(&serde_json::to_vec(&changed).unwrap());
+        assert!(signed.verify(&key.public).is_err());
+        assert_eq!(signed.unverified().unwrap(), changed);
+    }
+
+    #[test]
+    fn test_format() {
+        let json = serde_json::to_string(&Command::PublicWifi { enabled: None }).unwrap();
+        assert_eq!(json, r#"{"type":"public_wifi","enabled":null}"#);
+        let json = serde_json::to_string(&Command::RevokeSessions).unwrap();
+        assert_eq!(json, r#"{"type":"revoke_sessions"}"#);
+        let json = serde_json::to_string(&Command::Attest { nonce: "n1".to_string() }).unwrap();
+        assert_eq!(json, r#"{"type":"attest","nonce":"n1"}"#);
+        let command: Command = serde_json::from_str(r#"{"type":"logs"}"#).unwrap();
+        assert_eq!(command, Command::Logs { last: None });
+    }
+}
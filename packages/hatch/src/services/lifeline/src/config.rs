@@ -0,0 +1,189 @@
+use failure::Error;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use toml;
+
+use transport::{Ports, PLAIN_PORT, TLS_PORT};
+
+/// Written by genesis from the `[lifeline]` section of the device config.
+pub const CONFIG_PATH: &'static str = "/etc/lifeline.toml";
+
+/// Overrides `CONFIG_PATH`, to run lifeline against a local server.
+pub const CONFIG_ENV: &'static str = "LIFELINE_CONFIG";
+
+/// The `pub` bridge genesis sets up for the guests, 192.168.44.0/24.
+const GUEST_NETWORK: [u8; 3] = [192, 168, 44];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Always plaintext on port 80.
+    Off,
+    /// TLS on port 443, plaintext on port 80 if 443 is blocked.
+    Prefer,
+    /// TLS on port 443 only.
+    Require,
+}
+
+impl Default for TlsMode {
+    fn default() -> Self {
+        TlsMode::Prefer
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The base58 ed25519 key the lifeline servers authenticate with.
+    /// Without it, lifeline refuses to connect anywhere.
+    pub server_key: Option<String>,
+    /// Hostnames of the lifeline servers, in order of preference.
+    pub servers: Vec<String>,
+    /// Servers are also looked up as SRV records `_lifeline._tcp.<srv_domain>`.
+    pub srv_domain: Option<String>,
+    /// Resolve the servers over TLS only. Needs the `dns-over-tls` feature.
+    pub dns_tls: bool,
+    /// The plaintext port of the servers.
+    pub port: u16,
+    /// The tls port of the servers.
+    pub tls_port: u16,
+    pub tls: TlsMode,
+    /// base64 sha256 of the SubjectPublicKeyInfo of the server certificates.
+    /// TLS is only used if at least one pin is configured.
+    pub tls_pins: Vec<String>,
+    /// `http://[user:password@]host[:port]` or `socks5://...` to reach the
+    /// servers through a proxy, `wpad` to look for one. `wpad` requires TLS.
+    pub proxy: Option<String>,
+    /// Local services the server may open streams to, by name. Anything not
+    /// listed here is refused, and anything but `ssh` without TLS.
+    pub services: HashMap<String, String>,
+    /// `ip:port` of devices in the venue network the server may open streams
+    /// to, the service name of each is the pair itself.
+    pub forwards: Vec<String>,
+    /// Allow services and forwards into the guest network. Off, the guests'
+    /// devices are nobody's business.
+    pub guest_access: bool,
+    /// The base58 ed25519 key commands must be signed with. Without it, no
+    /// command is accepted.
+    pub operator_key: Option<String>,
+    /// The base58 ed25519 key server lists must be signed with, best kept
+    /// offline. Without it, the server key.
+    pub list_key: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut services = HashMap::new();
+        services.insert("ssh".to_string(), "127.0.0.1:22".to_string());
+
+        Config {
+            server_key: None,
+            servers: Vec::new(),
+            srv_domain: None,
+            dns_tls: false,
+            port: PLAIN_PORT,
+            tls_port: TLS_PORT,
+            tls: TlsMode::default(),
+            tls_pins: Vec::new(),
+            proxy: None,
+            services: services,
+            forwards: Vec::new(),
+            guest_access: false,
+            operator_key: None,
+            list_key: None,
+        }
+    }
+}
+
+impl Config {
+    /// Where the config is loaded from.
+    pub fn path() -> String {
+        env::var(CONFIG_ENV).unwrap_or_else(|_| CONFIG_PATH.to_string())
+    }
+
+    /// Loads the config. A missing file gives the default config.
+    pub fn load() -> Result<Config, Error> {
+        let mut f = match File::open(Config::path()) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut buf = String::new();
+        f.read_to_string(&mut buf)?;
+        Ok(toml::from_str(&buf)?)
+    }
+
+    pub fn ports(&self) -> Ports {
+        Ports {
+            plain: self.port,
+            tls: self.tls_port,
+        }
+    }
+
+    /// Where the streams for each service go: the services, then the forwards
+    /// under their own name. Invalid entries and, unless allowed, anything in
+    /// the guest network are left out.
+    pub fn targets(&self) -> HashMap<String, SocketAddr> {
+        let named = self.services.iter().map(|(name, addr)| (name.clone(), addr.clone()));
+        let forwards = self.forwards.iter().map(|addr| (addr.clone(), addr.clone()));
+
+        let mut targets = HashMap::new();
+        for (name, addr) in named.chain(forwards) {
+            match addr.parse::<SocketAddr>() {
+                Ok(ref a) if !self.guest_access && is_guest(a.ip()) =>
+                    warn!("ignoring {} at {}, it is in the guest network", name, addr),
+                Ok(a) => {
+                    targets.insert(name, a);
+                }
+                Err(e) => warn!("ignoring {} at {}: {}", name, addr, e),
+            }
+        }
+        targets
+    }
+}
+
+fn is_guest(ip: IpAddr) -> bool {
+    let v4 = match ip {
+        IpAddr::V4(v4) => v4,
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            Some(v4) => v4,
+            None => return false,
+        },
+    };
+    v4.octets()[..3] == GUEST_NETWORK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets() {
+        let mut config = Config::default();
+        config.forwards = vec![
+            "10.0.0.20:80".to_string(),
+            "192.168.44.23:554".to_string(),
+            "pos.lan:80".to_string(),
+        ];
+
+        let targets = config.targets();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets["ssh"], "127.0.0.1:22".parse().unwrap());
+        assert_eq!(targets["10.0.0.20:80"], "10.0.0.20:80".parse().unwrap());
+
+        config.guest_access = true;
+        assert!(config.targets().contains_key("192.168.44.23:554"));
+    }
+
+    #[test]
+    fn test_guest() {
+        assert!(is_guest("192.168.44.1".parse().unwrap()));
+        assert!(is_guest("::ffff:192.168.44.9".parse().unwrap()));
+        assert!(!is_guest("192.168.4.1".parse().unwrap()));
+        assert!(!is_guest("127.0.0.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,54 @@
+//! Control messages, sent as json text frames once both sides authenticated.
+
+use command::{Outcome, SignedCommand};
+use discovery::SignedServerList;
+use telemetry::Telemetry;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    /// Sent by the device after authentication. The server answers with
+    /// `Servers` if it has a list newer than `version`.
+    GetServers { version: u64 },
+    /// A signed server list, see `discovery`.
+    Servers(SignedServerList),
+    /// Sent by the device after authentication and every few minutes after.
+    Telemetry(Telemetry),
+    /// A command from the operators, relayed by the server.
+    Command(SignedCommand),
+    /// Sent by the device for every `Command`, whether it ran or not.
+    Outcome(Outcome),
+    /// Sent by the server right before it opens `stream`, naming who asked
+    /// for it, for the session audit.
+    Operator { stream: u32, operator: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_format() {
+        let json = serde_json::to_string(&Control::GetServers { version: 4 }).unwrap();
+        assert_eq!(json, r#"{"type":"get_servers","version":4}"#);
+
+        let msg: Control = serde_json::from_str(r#"{"type":"servers","list":"e30=","signature":"AA=="}"#).unwrap();
+        match msg {
+            Control::Servers(s) => assert_eq!(s.list, "e30="),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let msg: Control = serde_json::from_str(r#"{"type":"telemetry","uptime":300,"public_wifi":true}"#).unwrap();
+        match msg {
+            Control::Telemetry(t) => {
+                assert_eq!(t.uptime, Some(300));
+                assert_eq!(t.public_wifi, Some(true));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let json = serde_json::to_string(&Control::Operator { stream: 4, operator: "alice".to_string() }).unwrap();
+        assert_eq!(json, r#"{"type":"operator","stream":4,"operator":"alice"}"#);
+    }
+}
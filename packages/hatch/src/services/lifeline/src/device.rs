@@ -0,0 +1,670 @@
+use failure::Error;
+use futures::{future, stream};
+use futures::sync::mpsc;
+use futures::{Future, Poll, Sink, Stream};
+use tokio_core::reactor::{Core, Handle, Interval};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::Framed;
+use bytes::Bytes;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use base64;
+use rand;
+use ed25519_dalek::{Keypair, PublicKey};
+use serde_json;
+
+use audit::{self, Auditor, Session};
+use auth::{self, NONCE_LEN};
+use backoff::{Backoff, RateLimit};
+use command::{self, Commands, Executor, Outcome, SignedCommand};
+use config::Config;
+use control::Control;
+use discovery;
+use dns::Dns;
+use mux::{Acceptor, Connect, Mux};
+use proxy::{self, Proxy, Setting};
+use state::{self, State};
+use telemetry::{Probe, Telemetry};
+use transport::{self, with_timeout, HalfClose, Io, Ports, Security};
+use websocket::{self, Codec, Frame, HandshakeCodec, Role};
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// A ping is sent after this much time, the server answers with a pong.
+const PING_INTERVAL_SECS: u64 = 30;
+
+/// The session is considered dead, if nothing was received for this long.
+const PING_TIMEOUT_SECS: u64 = 90;
+
+/// Telemetry is sent after authentication and then this often.
+const TELEMETRY_INTERVAL_SECS: u64 = 300;
+
+/// The first wait after a failure is up to this long, doubling from there.
+const BACKOFF_BASE_MS: u64 = 1000;
+const BACKOFF_CAP_SECS: u64 = 300;
+
+/// At most this many connection attempts in the window, even if every
+/// session is established and drops right away.
+const RECONNECT_LIMIT: usize = 10;
+const RECONNECT_WINDOW_SECS: u64 = 300;
+
+/// The only service reachable over plaintext. The authentication is not bound
+/// to the stream, anyone in between could relay it and open streams of their
+/// own. ssh authenticates and encrypts end to end, nothing else does.
+const PLAINTEXT_SERVICE: &'static str = "ssh";
+
+type Transport = Framed<Box<Io>, Codec>;
+
+/// Everything a connection attempt needs to know about this device.
+pub struct Device {
+    identity:   String,
+    keypair:    Keypair,
+    server_key: PublicKey,
+    /// Server lists are signed with this key.
+    list_key:   PublicKey,
+    security:   Security,
+    ports:      Ports,
+    services:   HashMap<String, SocketAddr>,
+    probe:      Probe,
+    commands:   Option<Commands>,
+    auditor:    Auditor,
+    /// Who the server said opens a stream, by stream id, until it does.
+    operators:  RefCell<HashMap<u32, String>>,
+}
+
+impl Device {
+    pub fn new(identity: String, keypair: Keypair, server_key: PublicKey, security: Security, ports: Ports,
+               services: HashMap<String, SocketAddr>) -> Device {
+        Device {
+            identity:   identity,
+            keypair:    keypair,
+            server_key: server_key,
+            list_key:   server_key,
+            security:   security,
+            ports:      ports,
+            services:   services,
+            probe:      Box::new(|_| {}),
+            commands:   None,
+            auditor:    Box::new(audit::record),
+            operators:  RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Lets `probe` add to the telemetry of this device.
+    pub fn with_probe(mut self, probe: Probe) -> Device {
+        self.probe = probe;
+        self
+    }
+
+    /// Accepts commands signed by the operators. Without, every command is
+    /// refused.
+    pub fn with_commands(mut self, commands: Commands) -> Device {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Accepts server lists signed with `key` instead of the server key.
+    pub fn with_list_key(mut self, key: PublicKey) -> Device {
+        self.list_key = key;
+        self
+    }
+
+    /// Hands finished sessions to `auditor` instead of `audit::record`.
+    pub fn with_auditor(mut self, auditor: Auditor) -> Device {
+        self.auditor = auditor;
+        self
+    }
+}
+
+/// The server a session runs with, for the audit.
+#[derive(Debug, Clone)]
+struct Peer {
+    server:    String,
+    ip:        Option<IpAddr>,
+    /// Without TLS, only `PLAINTEXT_SERVICE` is opened and no command is run.
+    plaintext: bool,
+}
+
+/// Completes the websocket handshake on a fresh stream to a lifeline server.
+/// Anything the server sent right after its response stays buffered in the
+/// returned transport.
+fn connect(io: Box<Io>, identity: &str, device_nonce: &[u8], hostname: &str, handle: &Handle)
+    -> Box<Future<Item = Transport, Error = Error>>
+{
+    let key     = websocket::generate_key();
+    let nonce   = base64::encode(device_nonce);
+    let request = websocket::client_request("/lifeline/2", hostname, &key,
+                                            &[("X-LF-Name", identity), ("X-LF-Nonce", &nonce)]);
+
+    let handshake = io.framed(HandshakeCodec)
+        .send(request)
+        .and_then(|framed| framed.into_future().map_err(|(e, _)| e));
+
+    Box::new(with_timeout(handshake, Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), handle, "handshake")
+        .and_then(move |(head, framed)| -> Result<Transport, Error> {
+            let head = head.ok_or_else(|| format_err!("server closed the connection during handshake"))?;
+            head.verify_response(&key)?;
+
+            Ok(Framed::from_parts(framed.into_parts(), Codec::new(Role::Client)))
+        }))
+}
+
+/// Waits for the server challenge, checks it against the pinned server key and
+/// proves the device identity. Nothing is bridged before this succeeded.
+fn authenticate(ws: Transport, device: Rc<Device>, device_nonce: [u8; NONCE_LEN], handle: &Handle)
+    -> Box<Future<Item = Transport, Error = Error>>
+{
+    let challenge = ws.into_future().map_err(|(e, _)| e);
+    let challenge = with_timeout(challenge, Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), handle, "authentication");
+
+    Box::new(challenge
+        .and_then(move |(frame, ws)| -> Result<(Transport, Vec<u8>), Error> {
+            let challenge: auth::Challenge = match frame {
+                Some(Frame::Text(data)) => serde_json::from_slice(&data)?,
+                Some(other) => bail!("expected a challenge, got {:?}", other),
+                None => bail!("server closed the connection during authentication"),
+            };
+
+            let server_nonce = auth::verify_challenge(&device.server_key, &device_nonce,
+                                                      &device.identity, &challenge)
+                .ok_or_else(|| format_err!("server failed to authenticate, refusing to bridge"))?;
+
+            info!("server authenticated");
+
+            let proof = auth::prove(&device.keypair, &device_nonce, &server_nonce, &device.identity);
+            Ok((ws, serde_json::to_vec(&proof)?))
+        })
+        .and_then(|(ws, proof)| ws.send(Frame::Text(Bytes::from(proof))).map_err(Error::from)))
+}
+
+/// Runs an established session until the server closes or stops answering
+/// pings. The server opens streams to the local services over it, as many as
+/// it likes and for as long as the session lasts.
+fn session(ws: Transport, device: Rc<Device>, peer: Peer, handle: &Handle) -> Box<Future<Item = (), Error = Error>> {
+    let (sink, frames) = ws.split();
+
+    // all outgoing frames go through this channel, so stream data, pings and
+    // pongs can be sent from everywhere. It is bounded by the stream windows.
+    let (tx, rx) = mpsc::unbounded::<Frame>();
+    let last_seen = Rc::new(Cell::new(Instant::now()));
+
+    let writer = rx
+        .map_err(|()| format_err!("session channel closed"))
+        .forward(sink.sink_map_err(Error::from))
+        .map(|_| ());
+
+    let interval = match Interval::new(Duration::from_secs(PING_INTERVAL_SECS), handle) {
+        Ok(i) => i,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+
+    let telemetry = match Interval::new(Duration::from_secs(TELEMETRY_INTERVAL_SECS), handle) {
+        Ok(i) => i,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+
+    let keepalive = {
+        let tx = tx.clone();
+        let last_seen = last_seen.clone();
+        interval.map_err(Error::from).for_each(move |()| -> Result<(), Error> {
+            if last_seen.get().elapsed() > Duration::from_secs(PING_TIMEOUT_SECS) {
+                bail!("server stopped answering pings");
+            }
+            tx.unbounded_send(Frame::Ping(Bytes::new()))
+                .map_err(|_| format_err!("session channel closed"))
+        })
+    };
+
+    // the interval first fires after a whole period, the server wants to know
+    // right away
+    let report = {
+        let tx = tx.clone();
+        let device = device.clone();
+        stream::once(Ok(())).chain(telemetry).map_err(Error::from).for_each(move |()| -> Result<(), Error> {
+            let msg = serde_json::to_vec(&Control::Telemetry(Telemetry::collect(&device.probe)))?;
+            tx.unbounded_send(Frame::Text(Bytes::from(msg)))
+                .map_err(|_| format_err!("session channel closed"))
+        })
+    };
+
+    let plaintext = peer.plaintext;
+    let mux = Mux::new(tx.clone(), handle.clone(), Some(acceptor(device.clone(), Rc::new(peer), handle)), 1);
+    let streams = mux.clone();
+
+    let get_servers = Control::GetServers { version: discovery::version(&device.list_key) };
+    match serde_json::to_vec(&get_servers) {
+        Ok(msg) => {
+            let _ = tx.unbounded_send(Frame::Text(Bytes::from(msg)));
+        }
+        Err(e) => return Box::new(future::err(e.into())),
+    }
+
+    let incoming = frames
+        .map_err(Error::from)
+        .inspect(move |_| last_seen.set(Instant::now()))
+        .take_while(|frame| Ok::<bool, Error>(!frame.is_close()))
+        .for_each(move |frame| -> Result<(), Error> {
+            match frame {
+                Frame::Ping(data) => {
+                    let _ = tx.unbounded_send(Frame::Pong(data));
+                }
+                Frame::Binary(data) => mux.handle_frame(data)?,
+                Frame::Text(data) => control(&device, plaintext, &tx, &data),
+                _ => {}
+            }
+            Ok(())
+        });
+
+    // the core outlives the session, the streams must not
+    Box::new(incoming
+        .select(keepalive).map(|_| ()).map_err(|(e, _)| e)
+        .select(report).map(|_| ()).map_err(|(e, _)| e)
+        .select(writer).map(|_| ()).map_err(|(e, _)| e)
+        .then(move |res| {
+            streams.shutdown();
+            res
+        }))
+}
+
+fn control(device: &Device, plaintext: bool, tx: &mpsc::UnboundedSender<Frame>, data: &[u8]) {
+    match serde_json::from_slice::<Control>(data) {
+        Ok(Control::Servers(signed)) => if let Err(e) = discovery::accept(&device.list_key, &signed) {
+            warn!("rejected server list: {}", e);
+        },
+        Ok(Control::Command(signed)) => run_command(device, plaintext, &signed, tx.clone()),
+        Ok(Control::Operator { stream, operator }) => {
+            device.operators.borrow_mut().insert(stream, operator);
+        }
+        Ok(other) => warn!("unexpected control message {:?}", other),
+        Err(e) => warn!("invalid control message: {}", e),
+    }
+}
+
+/// Checks the command here, then runs it on a thread: genesis or a reboot take
+/// a while, and the session must go on answering pings meanwhile. The outcome
+/// goes back through `tx`.
+fn run_command(device: &Device, plaintext: bool, signed: &SignedCommand, tx: mpsc::UnboundedSender<Frame>) {
+    let id = signed.unverified().map(|order| order.id).unwrap_or_default();
+
+    let accepted = match device.commands {
+        Some(_) if plaintext => Err(format_err!("no commands without tls")),
+        Some(ref commands) => {
+            command::accept(&commands.operator_key, &device.identity, signed, command::unix_now(), &commands.seen)
+                .map(|order| (order, commands.executor.clone()))
+        }
+        None => Err(format_err!("no operator_key configured")),
+    };
+
+    let (order, executor) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => return send_outcome(&tx, id, Err(e)),
+    };
+
+    info!("running command {}: {:?}", order.id, order.command);
+    let reply = tx.clone();
+    let spawned = thread::Builder::new()
+        .name("lifeline-command".to_string())
+        .spawn(move || send_outcome(&reply, order.id, executor(&order.command)));
+
+    if let Err(e) = spawned {
+        send_outcome(&tx, id, Err(format_err!("cannot run command: {}", e)));
+    }
+}
+
+fn send_outcome(tx: &mpsc::UnboundedSender<Frame>, id: String, res: Result<String, Error>) {
+    let outcome = match res {
+        Ok(output) => {
+            info!("command {} done: {}", id, output);
+            Outcome { id: id, ok: true, output: output }
+        }
+        Err(e) => {
+            warn!("command {} failed: {}", id, e);
+            Outcome { id: id, ok: false, output: e.to_string() }
+        }
+    };
+
+    if let Ok(msg) = serde_json::to_vec(&Control::Outcome(outcome)) {
+        // gone with the session if it ended meanwhile
+        let _ = tx.unbounded_send(Frame::Text(Bytes::from(msg)));
+    }
+}
+
+/// A stream to a local service or a forward, audited when it ends.
+struct Audited {
+    io:         HalfClose,
+    device:     Rc<Device>,
+    peer:       Rc<Peer>,
+    stream:     u32,
+    service:    String,
+    addr:       SocketAddr,
+    operator:   Option<String>,
+    start:      u64,
+    /// Bytes from the server to the service, and back.
+    to:         u64,
+    from:       u64,
+    /// The service closed its direction, and the server closed its own.
+    read_done:  bool,
+    write_done: bool,
+    error:      Option<String>,
+}
+
+impl Audited {
+    fn track<T>(&mut self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(ref e) = res {
+            if e.kind() != io::ErrorKind::WouldBlock && self.error.is_none() {
+                self.error = Some(e.to_string());
+            }
+        }
+        res
+    }
+}
+
+impl Read for Audited {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.io.read(buf);
+        let n = self.track(res)?;
+        if n == 0 && !buf.is_empty() {
+            self.read_done = true;
+        }
+        self.from += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for Audited {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let res = self.io.write(buf);
+        let n = self.track(res)?;
+        self.to += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let res = self.io.flush();
+        self.track(res)
+    }
+}
+
+impl AsyncRead for Audited {}
+
+impl AsyncWrite for Audited {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let res = self.io.shutdown();
+        let ready = self.track(res)?;
+        self.write_done = true;
+        Ok(ready)
+    }
+}
+
+/// Dropped once the stream is over, however it ended.
+impl Drop for Audited {
+    fn drop(&mut self) {
+        let reason = match self.error.take() {
+            Some(e) => e,
+            None if self.read_done && self.write_done => "closed".to_string(),
+            // reset by the server, or the connection is gone
+            None => "aborted".to_string(),
+        };
+
+        (self.device.auditor)(&Session {
+            protocol:  2,
+            server:    self.peer.server.clone(),
+            server_ip: self.peer.ip,
+            stream:    Some(self.stream),
+            service:   self.service.clone(),
+            target:    self.addr,
+            operator:  self.operator.take(),
+            start:     self.start,
+            end:       command::unix_now(),
+            bytes_in:  self.to,
+            bytes_out: self.from,
+            reason:    reason,
+        });
+    }
+}
+
+/// Connects streams to the services and forwards allowed in the config.
+fn acceptor(device: Rc<Device>, peer: Rc<Peer>, handle: &Handle) -> Acceptor {
+    let handle = handle.clone();
+    Box::new(move |id: u32, service: &str| {
+        let operator = device.operators.borrow_mut().remove(&id);
+        if peer.plaintext && service != PLAINTEXT_SERVICE {
+            warn!("refusing {} without tls", service);
+            return None;
+        }
+        device.services.get(service).map(|addr| {
+            let addr = *addr;
+            let service = service.to_string();
+            let device = device.clone();
+            let peer = peer.clone();
+            let start = command::unix_now();
+            Box::new(transport::tcp(addr, &handle).map(move |stream| {
+                info!("session to {} at {} opened for {}", service, addr,
+                      operator.as_ref().map(|o| o.as_str()).unwrap_or("unknown operator"));
+                Box::new(Audited {
+                    io:         HalfClose(stream),
+                    device:     device,
+                    peer:       peer,
+                    stream:     id,
+                    service:    service,
+                    addr:       addr,
+                    operator:   operator,
+                    start:      start,
+                    to:         0,
+                    from:       0,
+                    read_done:  false,
+                    write_done: false,
+                    error:      None,
+                }) as Box<Io>
+            })) as Connect
+        })
+    })
+}
+
+/// Handshake and authentication on a fresh stream to a server.
+fn establish(io: Box<Io>, device: Rc<Device>, hostname: &str, handle: &Handle)
+    -> Box<Future<Item = Transport, Error = Error>>
+{
+    let device_nonce = auth::nonce();
+    let handle2 = handle.clone();
+
+    Box::new(connect(io, &device.identity, &device_nonce, hostname, handle)
+        .and_then(move |ws| authenticate(ws, device, device_nonce, &handle2)))
+}
+
+/// Runs a whole connection over an existing stream to a server, for running
+/// against a local server. A `plaintext` session is restricted as one to a
+/// remote server without TLS.
+pub fn run(io: Box<Io>, plaintext: bool, device: Rc<Device>, hostname: &str, handle: &Handle)
+    -> Box<Future<Item = (), Error = Error>>
+{
+    let handle = handle.clone();
+    let peer = Peer { server: hostname.to_string(), ip: None, plaintext: plaintext };
+    Box::new(establish(io, device.clone(), hostname, &handle)
+        .and_then(move |ws| session(ws, device, peer, &handle)))
+}
+
+/// Connects to whichever candidate answers first and runs the session.
+/// `established` is set once the server authenticated. With a proxy, the
+/// candidates are the server names with the addresses of the proxy.
+fn remote(core: &mut Core, device: Rc<Device>, dns: Rc<Dns>, candidates: Vec<(String, IpAddr)>,
+          proxy: Option<Proxy>, established: Rc<Cell<bool>>) -> Result<(), Error>
+{
+    let handle  = core.handle();
+    let handle2 = handle.clone();
+    let device2 = device.clone();
+    let proxied = proxy.is_some();
+
+    let work = transport::dial_any(candidates, &device.security, device.ports, proxy.as_ref(), &handle)
+        .and_then(move |(hostname, ip, io, plaintext)| establish(io, device2.clone(), &hostname, &handle2)
+            .map(move |ws| (ws, device2, hostname, ip, plaintext)))
+        .and_then(move |(ws, device, hostname, ip, plaintext)| {
+            info!("lifeline established");
+            established.set(true);
+            // the proxy resolved the name, not us
+            if !proxied {
+                dns.remember(&hostname, ip);
+            }
+            state::report(&State::Connected { server: hostname.clone(), ip: ip });
+            session(ws, device, Peer { server: hostname, ip: Some(ip), plaintext: plaintext }, &handle)
+        });
+
+    core.run(work)
+}
+
+/// The proxy to use this round, if any.
+fn pick_proxy(setting: &Setting, core: &mut Core, dns: &Dns) -> Option<Proxy> {
+    match *setting {
+        Setting::Direct => None,
+        Setting::Fixed(ref proxy) => Some(proxy.clone()),
+        Setting::Wpad => proxy::discover(|name| core.run(dns.resolve(name)).unwrap_or_default()),
+    }
+}
+
+/// Never returns. Used when lifeline must not connect at all, exiting would
+/// only make procd respawn us right away.
+fn refuse(reason: &str) -> ! {
+    error!("{}, refusing to connect", reason);
+    loop {
+        thread::sleep(Duration::from_secs(3600));
+    }
+}
+
+pub fn main(identity: String, keypair: Option<Keypair>, probe: Probe, executor: Executor) {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("cannot load {}: {}", Config::path(), e);
+            Config::default()
+        }
+    };
+
+    let keypair = match keypair {
+        Some(keypair) => keypair,
+        None => refuse("no device key to authenticate with"),
+    };
+
+    let server_key = match config.server_key.as_ref().map(|k| auth::decode_key(k)) {
+        Some(Some(key)) => key,
+        Some(None) => refuse(&format!("invalid server_key in {}", Config::path())),
+        None => refuse(&format!("no server_key in {}", Config::path())),
+    };
+
+    let security = match Security::from_config(&config) {
+        Ok(security) => security,
+        Err(e) => refuse(&format!("tls is required, but {}", e)),
+    };
+
+    let proxy_setting = match Setting::from_config(config.proxy.as_ref().map(|p| p.as_str())) {
+        Ok(setting) => setting,
+        Err(e) => {
+            warn!("ignoring proxy in {}: {}", Config::path(), e);
+            Setting::Direct
+        }
+    };
+
+    let mut device = Device::new(identity, keypair, server_key, security, config.ports(), config.targets())
+        .with_probe(probe);
+
+    // a typo must not hand the fleet back to the server key
+    match config.list_key.as_ref().map(|k| auth::decode_key(k)) {
+        Some(Some(key)) => device = device.with_list_key(key),
+        Some(None) => refuse(&format!("invalid list_key in {}", Config::path())),
+        None => {}
+    }
+
+    match config.operator_key.as_ref().map(|k| auth::decode_key(k)) {
+        Some(Some(key)) => {
+            device = device.with_commands(Commands {
+                operator_key: key,
+                executor: executor,
+                seen: PathBuf::from(command::SEEN_PATH),
+            });
+        }
+        Some(None) => warn!("invalid operator_key in {}, refusing all commands", Config::path()),
+        None => {}
+    }
+    let device = Rc::new(device);
+
+    let mut backoff = Backoff::new(Duration::from_millis(BACKOFF_BASE_MS), Duration::from_secs(BACKOFF_CAP_SECS));
+    let mut limit   = RateLimit::new(RECONNECT_LIMIT, Duration::from_secs(RECONNECT_WINDOW_SECS));
+    let mut rng     = rand::thread_rng();
+
+    // kept for the lifetime of lifeline, so the resolver cache survives
+    // between connections
+    let mut core = match Core::new() {
+        Ok(core) => core,
+        Err(e) => refuse(&format!("cannot create event loop: {}", e)),
+    };
+    let dns = Rc::new(Dns::new(config.dns_tls, &core.handle()));
+
+    loop {
+        if let Some(wait) = limit.delay(Instant::now()) {
+            state::report(&State::RateLimited { ms: millis(wait) });
+            thread::sleep(wait);
+        }
+        limit.record(Instant::now());
+
+        // looked up again every round, a list received over the tunnel is
+        // used right away
+        state::report(&State::Resolving);
+        let names = discovery::servers(&config, &device.list_key, |name| core.run(dns.resolve_srv(name)));
+
+        let proxy = pick_proxy(&proxy_setting, &mut core, &dns);
+        let ips: Vec<(String, IpAddr)> = match proxy {
+            Some(ref proxy) => {
+                let proxy_ips = match proxy.host.parse::<IpAddr>() {
+                    Ok(ip) => vec![ip],
+                    Err(_) => core.run(dns.resolve(&proxy.host)).unwrap_or_else(|e| {
+                        warn!("cannot resolve proxy {}: {}", proxy.host, e);
+                        Vec::new()
+                    }),
+                };
+                names.iter()
+                    .flat_map(|name| proxy_ips.iter().map(move |ip| (name.clone(), *ip)))
+                    .collect()
+            }
+            None => {
+                let lookups = names.into_iter().map(|name| dns.resolve(&name).then(move |res| match res {
+                    Ok(ips) => Ok(ips.into_iter().map(|ip| (name.clone(), ip)).collect()),
+                    Err(e) => {
+                        warn!("cannot resolve {}: {}", name, e);
+                        Ok::<Vec<(String, IpAddr)>, Error>(Vec::new())
+                    }
+                }));
+                match core.run(future::join_all(lookups)) {
+                    Ok(ips) => ips.into_iter().flat_map(|i| i).collect(),
+                    Err(_) => Vec::new(),
+                }
+            }
+        };
+
+        state::report(&State::Dialing { addresses: ips.len() });
+        let established = Rc::new(Cell::new(false));
+        if let Err(e) = remote(&mut core, device.clone(), dns.clone(), ips, proxy, established.clone()) {
+            warn!("{}", e);
+        }
+
+        // a session that ended after it was established starts over with a
+        // short wait. Still jittered, so a restarted server is not hit by all
+        // devices at once.
+        if established.get() {
+            backoff.reset();
+        } else {
+            backoff.fail();
+        }
+        let wait = backoff.wait(&mut rng);
+        state::report(&State::Waiting { ms: millis(wait), failures: backoff.failures() });
+        thread::sleep(wait);
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
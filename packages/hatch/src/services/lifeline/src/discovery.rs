@@ -0,0 +1,222 @@
+//! Where the lifeline servers are.
+//!
+//! Servers are tried in this order:
+//!
+//! 1. the signed server list last received over the tunnel
+//! 2. `servers` from the config
+//! 3. the SRV records `_lifeline._tcp.<srv_domain>`
+//! 4. the compiled in `SERVERS`, only if nothing above named any server
+//!
+//! The signed list lets the operators move the fleet without new firmware or a
+//! new genesis config. It is signed with `list_key` from the config, or the
+//! server key without one, so only the operators can replace it, and its
+//! version only ever goes up, so an old list cannot be replayed. A `list_key`
+//! kept offline means a stolen server key cannot move the fleet.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+
+use base64;
+use ed25519_dalek::{Keypair, PublicKey};
+use failure::Error;
+use serde_json;
+use sha2::Sha512;
+
+use auth;
+use config::Config;
+use dns::SERVERS;
+
+/// On the state partition, the overlay is a tmpfs.
+/// A list must survive a reboot, or the device would start over from servers
+/// the operators already moved away from.
+pub const SERVER_LIST_PATH: &'static str = "/state/lifeline.servers.json";
+
+pub const SRV_SERVICE: &'static str = "_lifeline._tcp";
+
+const LIST_CONTEXT: &'static [u8] = b"lifeline.2 servers";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerList {
+    /// Only lists with a higher version replace the current one.
+    pub version: u64,
+    /// Hostnames, in order of preference.
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedServerList {
+    /// base64 json of the `ServerList`
+    pub list: String,
+    /// base64 signature over the context and the decoded `list`
+    pub signature: String,
+}
+
+fn signed_data(list: &[u8]) -> Vec<u8> {
+    let mut data = LIST_CONTEXT.to_vec();
+    data.extend_from_slice(list);
+    data
+}
+
+impl SignedServerList {
+    pub fn sign(key: &Keypair, list: &ServerList) -> Result<SignedServerList, Error> {
+        let json = serde_json::to_vec(list)?;
+        let sig = key.sign::<Sha512>(&signed_data(&json));
+
+        Ok(SignedServerList {
+            list: base64::encode(&json),
+            signature: base64::encode(&sig.to_bytes()[..]),
+        })
+    }
+
+    pub fn verify(&self, key: &PublicKey) -> Result<ServerList, Error> {
+        let json = base64::decode(&self.list)?;
+        let sig = auth::decode_signature(&self.signature)
+            .ok_or_else(|| format_err!("invalid server list signature"))?;
+
+        if !key.verify::<Sha512>(&signed_data(&json), &sig) {
+            bail!("server list is not signed by the list key");
+        }
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// Loads and verifies the stored server list.
+pub fn load(key: &PublicKey) -> Option<ServerList> {
+    let mut f = File::open(SERVER_LIST_PATH).ok()?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).ok()?;
+
+    match serde_json::from_slice::<SignedServerList>(&buf).map_err(Error::from).and_then(|s| s.verify(key)) {
+        Ok(list) => Some(list),
+        Err(e) => {
+            warn!("ignoring {}: {}", SERVER_LIST_PATH, e);
+            None
+        }
+    }
+}
+
+/// Stores `signed` if it is valid and newer than the stored list.
+/// Returns the list if it was stored.
+pub fn accept(key: &PublicKey, signed: &SignedServerList) -> Result<Option<ServerList>, Error> {
+    let list = signed.verify(key)?;
+    if list.servers.is_empty() {
+        bail!("server list version {} is empty", list.version);
+    }
+
+    if let Some(current) = load(key) {
+        if list.version <= current.version {
+            return Ok(None);
+        }
+    }
+
+    let tmp = format!("{}.tmp", SERVER_LIST_PATH);
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec(signed)?)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, SERVER_LIST_PATH)?;
+
+    info!("stored server list version {}: {}", list.version, list.servers.join(", "));
+    Ok(Some(list))
+}
+
+/// Version of the stored list, 0 without one.
+pub fn version(key: &PublicKey) -> u64 {
+    load(key).map(|l| l.version).unwrap_or(0)
+}
+
+fn push(servers: &mut Vec<String>, name: &str) {
+    let name = name.trim().trim_right_matches('.');
+    if !name.is_empty() && !servers.iter().any(|s| s == name) {
+        servers.push(name.to_string());
+    }
+}
+
+/// All known servers, in the order they should be tried. `resolve_srv`
+/// looks up the targets of SRV records.
+pub fn servers<F>(config: &Config, key: &PublicKey, mut resolve_srv: F) -> Vec<String>
+    where F: FnMut(&str) -> Result<Vec<String>, Error>
+{
+    let mut servers = Vec::new();
+
+    if let Some(list) = load(key) {
+        for name in &list.servers {
+            push(&mut servers, name);
+        }
+    }
+
+    for name in &config.servers {
+        push(&mut servers, name);
+    }
+
+    if let Some(ref domain) = config.srv_domain {
+        let name = format!("{}.{}", SRV_SERVICE, domain);
+        match resolve_srv(&name) {
+            Ok(targets) => for name in &targets {
+                push(&mut servers, name);
+            },
+            Err(e) => warn!("cannot resolve {}: {}", name, e),
+        }
+    }
+
+    if servers.is_empty() {
+        for name in SERVERS.iter() {
+            push(&mut servers, name);
+        }
+    }
+
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::OsRng;
+
+    fn keypair() -> Keypair {
+        Keypair::generate::<Sha512>(&mut OsRng::new().unwrap())
+    }
+
+    fn list() -> ServerList {
+        ServerList {
+            version: 3,
+            servers: vec!["a.example.org".to_string(), "b.example.org".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let key = keypair();
+        let signed = SignedServerList::sign(&key, &list()).unwrap();
+        assert_eq!(signed.verify(&key.public).unwrap(), list());
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let signed = SignedServerList::sign(&keypair(), &list()).unwrap();
+        assert!(signed.verify(&keypair().public).is_err());
+    }
+
+    #[test]
+    fn test_tampered() {
+        let key = keypair();
+        let mut signed = SignedServerList::sign(&key, &list()).unwrap();
+
+        let mut other = list();
+        other.servers.push("evil.example.org".to_string());
+        signed.list = base64::encode(&serde_json::to_vec(&other).unwrap());
+
+        assert!(signed.verify(&key.public).is_err());
+    }
+
+    #[test]
+    fn test_push_dedups() {
+        let mut servers = Vec::new();
+        push(&mut servers, "a.example.org.");
+        push(&mut servers, "a.example.org");
+        push(&mut servers, " ");
+        assert_eq!(servers, vec!["a.example.org".to_string()]);
+    }
+}
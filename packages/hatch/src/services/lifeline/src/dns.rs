@@ -0,0 +1,238 @@
+//! Resolving the lifeline servers on networks that cannot be trusted.
+//!
+//! Names are resolved with public resolvers first, optionally over TLS so a
+//! network intercepting port 53 cannot answer for them. The resolvers from
+//! dhcp are only asked when the public ones fail, venue networks like to
+//! hijack those. When nothing answers at all, the addresses that last led to
+//! an authenticated session are used.
+//!
+//! Spoofed answers only cost a connection attempt: the servers authenticate
+//! with the pinned server key before anything is bridged.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std;
+
+use failure::{self, Error};
+use futures::Future;
+use serde_json;
+use tokio_core::reactor::Handle;
+use trust_dns_resolver::{Resolver, ResolverFuture, system_conf};
+use trust_dns_resolver::config::*;
+
+/// The lifeline servers, in order of preference. Only used when neither the
+/// config, SRV records nor a signed server list name any.
+pub const SERVERS : [&'static str;4] = [
+    "lifeline.hy5.berlin",
+    "lifeline.exys.org",
+    "lifeline.captif.io",
+    "lifeline.superscale.io"
+];
+
+/// The addresses that last led to an authenticated session, by hostname. On
+/// the state partition, they are needed most right after a reboot.
+pub const KNOWN_GOOD_PATH: &'static str = "/state/lifeline.addrs.json";
+
+/// Entries kept in the resolver cache. Entries expire with their TTL.
+const CACHE_SIZE: usize = 64;
+
+fn public_servers(config: &mut ResolverConfig) {
+    let servers = [
+        // cloudflare
+        IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)),
+        IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
+        // google
+        IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+        IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)),
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+    ];
+
+    for ip in servers.iter() {
+        config.add_name_server(NameServerConfig{
+            tls_dns_name: None,
+            socket_addr: SocketAddr::new(*ip, 53),
+            protocol: Protocol::Udp,
+        });
+    }
+}
+
+#[cfg(feature = "dns-over-tls")]
+fn tls_servers(config: &mut ResolverConfig) -> bool {
+    let servers = [
+        (IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), "cloudflare-dns.com"),
+        (IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)), "cloudflare-dns.com"),
+        (IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)), "cloudflare-dns.com"),
+        (IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), "dns.google"),
+        (IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)), "dns.google"),
+    ];
+
+    for &(ip, name) in servers.iter() {
+        config.add_name_server(NameServerConfig{
+            tls_dns_name: Some(name.to_string()),
+            socket_addr: SocketAddr::new(ip, 853),
+            protocol: Protocol::Tls,
+        });
+    }
+    true
+}
+
+#[cfg(not(feature = "dns-over-tls"))]
+fn tls_servers(_config: &mut ResolverConfig) -> bool {
+    false
+}
+
+fn system_servers(config: &mut ResolverConfig) {
+    if let Ok((sysconf, _))  = system_conf::read_system_conf() {
+        for ns in sysconf.name_servers() {
+            config.add_name_server(ns.clone());
+        }
+    }
+}
+
+fn options() -> ResolverOpts {
+    let mut opts = ResolverOpts::default();
+    opts.cache_size = CACHE_SIZE;
+    opts
+}
+
+// trust-dns errors are not Sync, so they cannot become a failure::Error directly
+fn resolve_error<E: std::fmt::Display>(e: E) -> Error {
+    failure::Error::from(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+}
+
+/// Resolves `name` once, with the public resolvers and the ones from dhcp.
+pub fn resolve(name: &str) -> Result<(Vec<std::net::IpAddr>), Error> {
+    debug!("resolving {}", name);
+
+    let mut config = ResolverConfig::new();
+    public_servers(&mut config);
+    system_servers(&mut config);
+
+    let resolver = Resolver::new(config, ResolverOpts::default())?;
+    let response = resolver.lookup_ip(name).map_err(resolve_error)?;
+
+    Ok(response.iter().collect())
+}
+
+/// Resolvers kept for the lifetime of lifeline, so their caches are used.
+pub struct Dns {
+    public: ResolverFuture,
+    system: ResolverFuture,
+    known_good: RefCell<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl Dns {
+    /// With `tls`, the public resolvers are only asked over TLS.
+    pub fn new(tls: bool, handle: &Handle) -> Dns {
+        let mut public = ResolverConfig::new();
+        if !(tls && tls_servers(&mut public)) {
+            if tls {
+                warn!("built without dns over tls, using plain dns");
+            }
+            public_servers(&mut public);
+        }
+
+        let mut system = ResolverConfig::new();
+        system_servers(&mut system);
+
+        Dns {
+            public: ResolverFuture::new(public, options(), handle),
+            system: ResolverFuture::new(system, options(), handle),
+            known_good: RefCell::new(load_known_good()),
+        }
+    }
+
+    /// Resolves `name`, falling back to the resolvers from dhcp and then to
+    /// the addresses that worked last time.
+    pub fn resolve(&self, name: &str) -> Box<Future<Item = Vec<IpAddr>, Error = Error>> {
+        debug!("resolving {}", name);
+
+        let name = name.to_string();
+        let known = self.known_good.borrow().get(&name).cloned().unwrap_or_default();
+        let system = self.system.lookup_ip(&name);
+
+        Box::new(self.public.lookup_ip(&name)
+            .map_err(resolve_error)
+            .and_then(|r| nonempty(r.iter().collect()))
+            .or_else(move |e| {
+                debug!("public resolvers failed for {}: {}, asking dhcp resolvers", name, e);
+                system.map_err(resolve_error)
+                    .and_then(|r| nonempty(r.iter().collect()))
+                    .or_else(move |e| {
+                        if known.is_empty() {
+                            return Err(e);
+                        }
+                        warn!("cannot resolve {}: {}, using last known good addresses", name, e);
+                        Ok(known)
+                    })
+            }))
+    }
+
+    /// Looks up the SRV records of `name` and returns the targets, best first:
+    /// by priority, then by weight.
+    pub fn resolve_srv(&self, name: &str) -> Box<Future<Item = Vec<String>, Error = Error>> {
+        debug!("resolving SRV {}", name);
+
+        Box::new(self.public.lookup_srv(name).map_err(resolve_error).map(|response| {
+            let mut records: Vec<(u16, u16, String)> = response.iter()
+                .map(|srv| (srv.priority(), srv.weight(), srv.target().to_string().trim_right_matches('.').to_string()))
+                .collect();
+            records.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+            records.into_iter().map(|r| r.2).collect()
+        }))
+    }
+
+    /// Remembers that `ip` led to an authenticated session with `name`.
+    /// Only writes to flash when something changed.
+    pub fn remember(&self, name: &str, ip: IpAddr) {
+        let mut known = self.known_good.borrow_mut();
+        {
+            let ips = known.entry(name.to_string()).or_insert_with(Vec::new);
+            if ips.first() == Some(&ip) {
+                return;
+            }
+            ips.retain(|i| *i != ip);
+            ips.insert(0, ip);
+            ips.truncate(4);
+        }
+
+        if let Err(e) = store_known_good(&known) {
+            warn!("cannot write {}: {}", KNOWN_GOOD_PATH, e);
+        }
+    }
+}
+
+fn nonempty(ips: Vec<IpAddr>) -> Result<Vec<IpAddr>, Error> {
+    if ips.is_empty() {
+        bail!("no addresses");
+    }
+    Ok(ips)
+}
+
+fn load_known_good() -> HashMap<String, Vec<IpAddr>> {
+    let mut buf = Vec::new();
+    match File::open(KNOWN_GOOD_PATH).and_then(|mut f| f.read_to_end(&mut buf)) {
+        Ok(_) => serde_json::from_slice(&buf).unwrap_or_else(|e| {
+            warn!("ignoring {}: {}", KNOWN_GOOD_PATH, e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn store_known_good(known: &HashMap<String, Vec<IpAddr>>) -> Result<(), Error> {
+    let tmp = format!("{}.tmp", KNOWN_GOOD_PATH);
+    {
+        let mut f = File::create(&tmp)?;
+        f.write_all(&serde_json::to_vec(known)?)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, KNOWN_GOOD_PATH)?;
+    Ok(())
+}
+
@@ -0,0 +1,45 @@
+extern crate base64;
+extern crate bs58;
+extern crate bytes;
+extern crate ed25519_dalek;
+#[macro_use] extern crate failure;
+extern crate futures;
+extern crate httparse;
+extern crate libc;
+#[macro_use] extern crate log;
+extern crate nix;
+extern crate rand;
+extern crate rustls;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+#[cfg(test)] extern crate tempdir;
+#[macro_use] extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_rustls;
+extern crate tokio_uds;
+extern crate toml;
+extern crate trust_dns_resolver;
+extern crate webpki;
+
+pub mod audit;
+pub mod auth;
+pub mod backoff;
+pub mod command;
+pub mod config;
+pub mod control;
+pub mod discovery;
+pub mod dns;
+pub mod mux;
+pub mod proxy;
+pub mod server;
+pub mod state;
+pub mod telemetry;
+pub mod tls;
+pub mod transport;
+pub mod websocket;
+pub mod device;
+
+pub use device::main;
@@ -0,0 +1,536 @@
+//! Many streams over one lifeline connection.
+//!
+//! Every binary websocket message carries one mux frame:
+//!
+//! ```text
+//! u32 stream id (big endian)
+//! u8  type
+//! ..  payload
+//! ```
+//!
+//! | type       | payload                                           |
+//! |------------|---------------------------------------------------|
+//! | 0 `OPEN`   | utf8 name of the service to connect the stream to |
+//! | 1 `DATA`   | stream data                                       |
+//! | 2 `WINDOW` | u32, how many more bytes the peer may send        |
+//! | 3 `CLOSE`  | none, the sender will send no more data           |
+//! | 4 `RESET`  | utf8 reason, the stream is gone in both directions |
+//!
+//! Each direction of a stream starts with `INITIAL_WINDOW` bytes of credit.
+//! The receiver hands out more credit with `WINDOW` once the local socket took
+//! the data, so no more than the window is ever buffered for a stream that
+//! the local end does not read. Sending more than the credit resets the
+//! stream.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use failure::Error;
+use futures::future::{self, Either};
+use futures::sync::{mpsc, oneshot};
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::Handle;
+use tokio_io::{self, AsyncRead};
+
+use transport::Io;
+use websocket::Frame;
+
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// Largest data frame sent.
+const MAX_DATA: usize = 16 * 1024;
+
+const OPEN: u8 = 0;
+const DATA: u8 = 1;
+const WINDOW: u8 = 2;
+const CLOSE: u8 = 3;
+const RESET: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MuxFrame {
+    Open(u32, String),
+    Data(u32, Bytes),
+    Window(u32, u32),
+    Close(u32),
+    Reset(u32, String),
+}
+
+impl MuxFrame {
+    pub fn encode(&self) -> Bytes {
+        let (id, kind, payload) = match *self {
+            MuxFrame::Open(id, ref service) => (id, OPEN, Bytes::from(service.as_bytes())),
+            MuxFrame::Data(id, ref data) => (id, DATA, data.clone()),
+            MuxFrame::Window(id, n) => (id, WINDOW, Bytes::from(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8][..])),
+            MuxFrame::Close(id) => (id, CLOSE, Bytes::new()),
+            MuxFrame::Reset(id, ref reason) => (id, RESET, Bytes::from(reason.as_bytes())),
+        };
+
+        let mut buf = BytesMut::with_capacity(5 + payload.len());
+        buf.put_u32_be(id);
+        buf.put_u8(kind);
+        buf.put_slice(&payload);
+        buf.freeze()
+    }
+
+    pub fn decode(mut data: Bytes) -> Result<MuxFrame, Error> {
+        if data.len() < 5 {
+            bail!("mux frame too short");
+        }
+
+        let id = (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32;
+        let kind = data[4];
+        let payload = data.split_off(5);
+
+        Ok(match kind {
+            OPEN => MuxFrame::Open(id, String::from_utf8(payload.to_vec())?),
+            DATA => MuxFrame::Data(id, payload),
+            WINDOW if payload.len() == 4 => MuxFrame::Window(id,
+                (payload[0] as u32) << 24 | (payload[1] as u32) << 16 | (payload[2] as u32) << 8 | payload[3] as u32),
+            CLOSE => MuxFrame::Close(id),
+            RESET => MuxFrame::Reset(id, String::from_utf8_lossy(&payload).into_owned()),
+            _ => bail!("invalid mux frame type {}", kind),
+        })
+    }
+
+    fn into_ws(self) -> Frame {
+        Frame::Binary(self.encode())
+    }
+}
+
+/// A future connecting a stream to its local end.
+pub type Connect = Box<Future<Item = Box<Io>, Error = Error>>;
+
+/// Decides where streams opened by the peer go, given the stream id and the
+/// service. `None` refuses the stream.
+pub type Acceptor = Box<Fn(u32, &str) -> Option<Connect>>;
+
+/// Flow control state of one stream, shared between the dispatcher and the
+/// task moving the data.
+struct Shared {
+    /// How many bytes we may still send.
+    credit: Cell<u32>,
+    /// How many bytes the peer may still send.
+    window: Cell<u32>,
+    /// The reader waiting for credit.
+    task: RefCell<Option<Task>>,
+}
+
+struct Entry {
+    shared: Rc<Shared>,
+    /// Dropped when the peer closed its direction.
+    inbound: Option<mpsc::UnboundedSender<Bytes>>,
+    /// Dropping this aborts the stream.
+    _kill: oneshot::Sender<()>,
+}
+
+struct Inner {
+    out: mpsc::UnboundedSender<Frame>,
+    handle: Handle,
+    acceptor: Option<Acceptor>,
+    streams: RefCell<HashMap<u32, Entry>>,
+    next_id: Cell<u32>,
+}
+
+/// Reads from the local end, no more than the peer gave credit for.
+struct CreditedRead<R> {
+    reader: R,
+    shared: Rc<Shared>,
+    buf: Box<[u8]>,
+}
+
+impl<R: AsyncRead> Stream for CreditedRead<R> {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, io::Error> {
+        let credit = self.shared.credit.get() as usize;
+        if credit == 0 {
+            *self.shared.task.borrow_mut() = Some(task::current());
+            return Ok(Async::NotReady);
+        }
+
+        let max = credit.min(self.buf.len());
+        let n = try_nb!(self.reader.read(&mut self.buf[..max]));
+        if n == 0 {
+            return Ok(Async::Ready(None));
+        }
+
+        self.shared.credit.set((credit - n) as u32);
+        Ok(Async::Ready(Some(Bytes::from(&self.buf[..n]))))
+    }
+}
+
+/// One side of a multiplexed connection. Frames for the peer go into `out`,
+/// frames from the peer are passed to `handle_frame`.
+#[derive(Clone)]
+pub struct Mux {
+    inner: Rc<Inner>,
+}
+
+impl Mux {
+    /// `first_id` is 1 on the device and 2 on the server, so both sides can
+    /// open streams without colliding.
+    pub fn new(out: mpsc::UnboundedSender<Frame>, handle: Handle, acceptor: Option<Acceptor>, first_id: u32) -> Mux {
+        Mux {
+            inner: Rc::new(Inner {
+                out: out,
+                handle: handle,
+                acceptor: acceptor,
+                streams: RefCell::new(HashMap::new()),
+                next_id: Cell::new(first_id),
+            }),
+        }
+    }
+
+    fn send(&self, frame: MuxFrame) {
+        let _ = self.inner.out.unbounded_send(frame.into_ws());
+    }
+
+    /// Number of currently open streams.
+    pub fn streams(&self) -> usize {
+        self.inner.streams.borrow().len()
+    }
+
+    /// Aborts all streams, for when the connection is gone.
+    pub fn shutdown(&self) {
+        self.inner.streams.borrow_mut().clear();
+    }
+
+    /// The id the next `open` will use, to refer to the stream in a control
+    /// message sent before it.
+    pub fn next_id(&self) -> u32 {
+        self.inner.next_id.get()
+    }
+
+    /// Opens a stream to `service` on the peer, carrying `io`.
+    pub fn open(&self, service: &str, io: Box<Io>) -> u32 {
+        let id = self.inner.next_id.get();
+        self.inner.next_id.set(id.wrapping_add(2));
+
+        self.send(MuxFrame::Open(id, service.to_string()));
+        self.start(id, Box::new(future::ok(io)));
+        id
+    }
+
+    /// Handles one binary message from the peer.
+    pub fn handle_frame(&self, data: Bytes) -> Result<(), Error> {
+        match MuxFrame::decode(data)? {
+            MuxFrame::Open(id, service) => {
+                if self.inner.streams.borrow().contains_key(&id) {
+                    bail!("peer reopened stream {}", id);
+                }
+
+                let connect = self.inner.acceptor.as_ref().and_then(|a| a(id, &service));
+                match connect {
+                    Some(connect) => {
+                        info!("stream {} to {} opened", id, service);
+                        self.start(id, connect);
+                    }
+                    None => {
+                        warn!("stream {} to {} refused, service not allowed", id, service);
+                        self.send(MuxFrame::Reset(id, format!("service {} not allowed", service)));
+                    }
+                }
+            }
+            MuxFrame::Data(id, data) => {
+                let overrun = match self.inner.streams.borrow().get(&id) {
+                    Some(entry) => {
+                        let window = entry.shared.window.get();
+                        if data.len() as u32 > window {
+                            true
+                        } else {
+                            entry.shared.window.set(window - data.len() as u32);
+                            if let Some(ref inbound) = entry.inbound {
+                                let _ = inbound.unbounded_send(data);
+                            }
+                            false
+                        }
+                    }
+                    None => false,
+                };
+
+                if overrun {
+                    warn!("stream {} overran its window", id);
+                    self.inner.streams.borrow_mut().remove(&id);
+                    self.send(MuxFrame::Reset(id, "window exceeded".to_string()));
+                }
+            }
+            MuxFrame::Window(id, n) => {
+                if let Some(entry) = self.inner.streams.borrow().get(&id) {
+                    let credit = entry.shared.credit.get();
+                    entry.shared.credit.set(credit.saturating_add(n));
+                    if let Some(task) = entry.shared.task.borrow_mut().take() {
+                        task.notify();
+                    }
+                }
+            }
+            MuxFrame::Close(id) => {
+                if let Some(entry) = self.inner.streams.borrow_mut().get_mut(&id) {
+                    entry.inbound = None;
+                }
+            }
+            MuxFrame::Reset(id, reason) => {
+                if self.inner.streams.borrow_mut().remove(&id).is_some() {
+                    info!("stream {} reset by peer: {}", id, reason);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves data between the stream and its local end until both directions
+    /// are closed, the peer resets the stream or an error happens.
+    fn start(&self, id: u32, connect: Connect) {
+        let shared = Rc::new(Shared {
+            credit: Cell::new(INITIAL_WINDOW),
+            window: Cell::new(INITIAL_WINDOW),
+            task: RefCell::new(None),
+        });
+        let (inbound_tx, inbound_rx) = mpsc::unbounded::<Bytes>();
+        let (kill_tx, kill_rx) = oneshot::channel::<()>();
+
+        self.inner.streams.borrow_mut().insert(id, Entry {
+            shared: shared.clone(),
+            inbound: Some(inbound_tx),
+            _kill: kill_tx,
+        });
+
+        let grant = self.clone();
+        let granted = shared.clone();
+        let data = self.clone();
+        let close = self.clone();
+
+        let work = connect.and_then(move |io| {
+            let (local_r, local_w) = io.split();
+
+            let to_local = inbound_rx
+                .map_err(|()| io::Error::new(io::ErrorKind::Other, "stream closed"))
+                .fold(local_w, move |local_w, chunk| {
+                    let grant = grant.clone();
+                    let granted = granted.clone();
+                    tokio_io::io::write_all(local_w, chunk).map(move |(local_w, chunk)| {
+                        // the local socket took the chunk, the peer may send more
+                        granted.window.set(granted.window.get() + chunk.len() as u32);
+                        grant.send(MuxFrame::Window(id, chunk.len() as u32));
+                        local_w
+                    })
+                })
+                .and_then(tokio_io::io::shutdown)
+                .map(|_| ());
+
+            let to_remote = CreditedRead {
+                    reader: local_r,
+                    shared: shared,
+                    buf: Box::new([0; MAX_DATA]),
+                }
+                .for_each(move |chunk| {
+                    data.send(MuxFrame::Data(id, chunk));
+                    Ok(())
+                })
+                .map(move |()| close.send(MuxFrame::Close(id)));
+
+            to_local.join(to_remote).map(|_| ()).map_err(Error::from)
+        });
+
+        let mux = self.clone();
+        let task = work.select2(kill_rx).then(move |res| {
+            match res {
+                Ok(Either::A(_)) => info!("stream {} ended", id),
+                Err(Either::A((e, _))) => {
+                    warn!("stream {} failed: {}", id, e);
+                    mux.send(MuxFrame::Reset(id, e.to_string()));
+                }
+                // the peer reset the stream or the connection is gone
+                Ok(Either::B(_)) | Err(Either::B(_)) => {}
+            }
+            mux.inner.streams.borrow_mut().remove(&id);
+            Ok(())
+        });
+
+        self.inner.handle.spawn(task);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use futures::sync::mpsc::UnboundedReceiver;
+    use tokio_core::reactor::Core;
+    use tokio_io::AsyncWrite;
+
+    /// A local end that takes no more than `room` bytes until they are read.
+    /// Reading from it never returns anything.
+    #[derive(Clone, Default)]
+    struct Pipe(Rc<RefCell<PipeState>>);
+
+    #[derive(Default)]
+    struct PipeState {
+        written: Vec<u8>,
+        room: usize,
+        writer: Option<Task>,
+    }
+
+    impl Pipe {
+        fn drain(&self, room: usize) -> Vec<u8> {
+            let mut state = self.0.borrow_mut();
+            state.room = room;
+            if let Some(task) = state.writer.take() {
+                task.notify();
+            }
+            state.written.split_off(0)
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.0.borrow_mut();
+            let n = cmp::min(buf.len(), state.room - state.written.len());
+            if n == 0 {
+                state.writer = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            state.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe {}
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// A mux with stream 1 open to `pipe`.
+    fn open(core: &Core, pipe: &Pipe) -> (Mux, UnboundedReceiver<Frame>) {
+        let (tx, rx) = mpsc::unbounded();
+        let pipe = pipe.clone();
+        let acceptor: Acceptor = Box::new(move |_, _| {
+            Some(Box::new(future::ok(Box::new(pipe.clone()) as Box<Io>)) as Connect)
+        });
+        let mux = Mux::new(tx, core.handle(), Some(acceptor), 2);
+        mux.handle_frame(MuxFrame::Open(1, "svc".to_string()).encode()).unwrap();
+        (mux, rx)
+    }
+
+    /// Sends `n` bytes on stream 1, in frames of at most `MAX_DATA`.
+    fn send(mux: &Mux, mut n: usize) {
+        while n > 0 {
+            let len = cmp::min(n, MAX_DATA);
+            mux.handle_frame(MuxFrame::Data(1, Bytes::from(vec![7; len])).encode()).unwrap();
+            n -= len;
+        }
+    }
+
+    fn settle(core: &mut Core) {
+        for _ in 0..100 {
+            core.turn(Some(Duration::from_millis(0)));
+        }
+    }
+
+    /// The frames the mux sent since the last call.
+    fn sent(core: &mut Core, rx: &mut UnboundedReceiver<Frame>) -> Vec<MuxFrame> {
+        settle(core);
+        core.run(future::poll_fn(|| {
+            let mut frames = Vec::new();
+            while let Async::Ready(Some(Frame::Binary(data))) = rx.poll()? {
+                frames.push(MuxFrame::decode(data).unwrap());
+            }
+            Ok::<_, ()>(Async::Ready(frames))
+        })).unwrap()
+    }
+
+    fn granted(frames: &[MuxFrame]) -> usize {
+        frames.iter().map(|f| match *f {
+            MuxFrame::Window(1, n) => n as usize,
+            _ => 0,
+        }).sum()
+    }
+
+    fn roundtrip(frame: MuxFrame) {
+        assert_eq!(MuxFrame::decode(frame.encode()).unwrap(), frame);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(MuxFrame::Open(1, "ssh".to_string()));
+        roundtrip(MuxFrame::Data(3, Bytes::from(&b"hello"[..])));
+        roundtrip(MuxFrame::Window(0xdeadbeef, 0x01020304));
+        roundtrip(MuxFrame::Close(7));
+        roundtrip(MuxFrame::Reset(9, "service ssh not allowed".to_string()));
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(&MuxFrame::Window(1, 256).encode()[..], &[0, 0, 0, 1, 2, 0, 0, 1, 0][..]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(MuxFrame::decode(Bytes::from(&[0, 0, 0][..])).is_err());
+        assert!(MuxFrame::decode(Bytes::from(&[0, 0, 0, 1, 9][..])).is_err());
+        assert!(MuxFrame::decode(Bytes::from(&[0, 0, 0, 1, WINDOW, 1][..])).is_err());
+    }
+
+    #[test]
+    fn test_window_exhausted() {
+        let mut core = Core::new().unwrap();
+        let pipe = Pipe::default();
+        pipe.drain(2 * MAX_DATA);
+        let (mux, mut rx) = open(&core, &pipe);
+
+        // the local end takes two chunks, only those are granted again
+        send(&mux, INITIAL_WINDOW as usize);
+        assert_eq!(granted(&sent(&mut core, &mut rx)), 2 * MAX_DATA);
+        assert_eq!(mux.streams(), 1);
+
+        send(&mux, 2 * MAX_DATA);
+        assert!(sent(&mut core, &mut rx).is_empty());
+        send(&mux, 1);
+        assert_eq!(sent(&mut core, &mut rx), vec![MuxFrame::Reset(1, "window exceeded".to_string())]);
+        assert_eq!(mux.streams(), 0);
+    }
+
+    #[test]
+    fn test_window_resumed() {
+        let mut core = Core::new().unwrap();
+        let pipe = Pipe::default();
+        let (mux, mut rx) = open(&core, &pipe);
+
+        send(&mux, INITIAL_WINDOW as usize);
+        assert_eq!(granted(&sent(&mut core, &mut rx)), 0);
+
+        // reading the local end frees the window bit by bit
+        let mut received = pipe.drain(MAX_DATA);
+        assert_eq!(granted(&sent(&mut core, &mut rx)), MAX_DATA);
+        while received.len() < INITIAL_WINDOW as usize {
+            received.extend(pipe.drain(MAX_DATA));
+            settle(&mut core);
+        }
+        assert_eq!(received, vec![7; INITIAL_WINDOW as usize]);
+        assert_eq!(granted(&sent(&mut core, &mut rx)), INITIAL_WINDOW as usize - MAX_DATA);
+
+        // the peer may send a whole window again
+        send(&mux, INITIAL_WINDOW as usize);
+        assert_eq!(mux.streams(), 1);
+    }
+}
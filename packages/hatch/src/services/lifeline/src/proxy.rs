@@ -0,0 +1,522 @@
+//! Reaching the servers through a proxy, for uplinks that allow nothing else.
+//!
+//! `proxy` in the config is either a url, `http://[user:password@]host[:port]`
+//! for HTTP CONNECT or `socks5://[user:password@]host[:port]`, or `wpad` to
+//! look for a proxy the way browsers do. The servers are resolved by the
+//! proxy, not by lifeline.
+//!
+//! Anyone on the network can answer for `wpad`, so a discovered proxy sees
+//! the whole stream. With `wpad`, the servers are only reached over TLS.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{self, IpAddr, SocketAddr};
+use std::time::Duration;
+
+use base64;
+use failure::Error;
+use futures::Future;
+use futures::future::{self, Loop};
+use httparse;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+
+use transport::{tcp, with_timeout};
+
+/// The value of `proxy` that turns on discovery.
+pub const WPAD: &'static str = "wpad";
+
+/// Where DHCP puts the search domain on OpenWrt, then the usual place.
+const RESOLV_CONFS: &'static [&'static str] = &["/tmp/resolv.conf.auto", "/etc/resolv.conf"];
+
+const HTTP_PORT: u16 = 8080;
+const SOCKS5_PORT: u16 = 1080;
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+const WPAD_TIMEOUT_SECS: u64 = 3;
+
+/// A longer proxy response is not a proxy response.
+const MAX_HEAD: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Http,
+    Socks5,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub kind: Kind,
+    pub host: String,
+    pub port: u16,
+    /// User and password.
+    pub auth: Option<(String, String)>,
+}
+
+/// Without the credentials, for logging.
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = match self.kind {
+            Kind::Http => "http",
+            Kind::Socks5 => "socks5",
+        };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+/// What the config says about proxies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Setting {
+    Direct,
+    Fixed(Proxy),
+    Wpad,
+}
+
+impl Setting {
+    pub fn from_config(proxy: Option<&str>) -> Result<Setting, Error> {
+        match proxy {
+            None | Some("") => Ok(Setting::Direct),
+            Some(WPAD) => Ok(Setting::Wpad),
+            Some(url) => Ok(Setting::Fixed(Proxy::parse(url)?)),
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String, Error> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+        let hex = String::from_utf8(hex)?;
+        out.push(u8::from_str_radix(&hex, 16).map_err(|_| format_err!("invalid escape %{}", hex))?);
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+/// Splits `host:port` and `[v6]:port`, the port is optional.
+fn split_host_port(s: &str, default: u16) -> Result<(String, u16), Error> {
+    let (host, port) = if s.starts_with('[') {
+        let end = s.find(']').ok_or_else(|| format_err!("unterminated [ in {}", s))?;
+        (&s[1..end], s[end + 1..].trim_left_matches(':'))
+    } else {
+        match s.rfind(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        }
+    };
+
+    if host.is_empty() {
+        bail!("no host in {}", s);
+    }
+    let port = if port.is_empty() {
+        default
+    } else {
+        port.parse().map_err(|_| format_err!("invalid port in {}", s))?
+    };
+    Ok((host.to_string(), port))
+}
+
+impl Proxy {
+    pub fn parse(url: &str) -> Result<Proxy, Error> {
+        let (kind, rest) = if url.starts_with("http://") {
+            (Kind::Http, &url["http://".len()..])
+        } else if url.starts_with("socks5://") {
+            (Kind::Socks5, &url["socks5://".len()..])
+        } else {
+            bail!("unsupported proxy {}, expected http:// or socks5://", url);
+        };
+        let rest = rest.trim_right_matches('/');
+
+        let (auth, hostport) = match rest.rfind('@') {
+            Some(i) => {
+                let userinfo = &rest[..i];
+                let (user, password) = match userinfo.find(':') {
+                    Some(j) => (&userinfo[..j], &userinfo[j + 1..]),
+                    None => (userinfo, ""),
+                };
+                (Some((percent_decode(user)?, percent_decode(password)?)), &rest[i + 1..])
+            }
+            None => (None, rest),
+        };
+
+        let default = match kind {
+            Kind::Http => HTTP_PORT,
+            Kind::Socks5 => SOCKS5_PORT,
+        };
+        let (host, port) = split_host_port(hostport, default)?;
+
+        Ok(Proxy {
+            kind: kind,
+            host: host,
+            port: port,
+            auth: auth,
+        })
+    }
+
+    /// Connects to the proxy at `ip` and asks it for a stream to `host:port`.
+    pub fn connect(&self, ip: IpAddr, host: &str, port: u16, handle: &Handle)
+        -> Box<Future<Item = TcpStream, Error = Error>>
+    {
+        let proxy  = self.clone();
+        let host   = host.to_string();
+        let handle2 = handle.clone();
+
+        Box::new(tcp(SocketAddr::new(ip, self.port), handle).and_then(move |stream| {
+            let handshake = match proxy.kind {
+                Kind::Http => http_connect(stream, &host, port, proxy.auth.as_ref()),
+                Kind::Socks5 => socks5_connect(stream, &host, port, proxy.auth.clone()),
+            };
+            with_timeout(handshake, Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), &handle2, "proxy handshake")
+        }))
+    }
+}
+
+/// Reads up to and including the empty line after the headers, one byte at a
+/// time. Whatever comes after belongs to the server.
+fn read_head(stream: TcpStream) -> Box<Future<Item = (TcpStream, Vec<u8>), Error = Error>> {
+    Box::new(future::loop_fn((stream, Vec::new()), |(stream, mut head)| {
+        read_exact(stream, [0u8; 1]).map_err(Error::from).and_then(move |(stream, byte)| {
+            head.push(byte[0]);
+            if head.ends_with(b"\r\n\r\n") {
+                Ok(Loop::Break((stream, head)))
+            } else if head.len() > MAX_HEAD {
+                Err(format_err!("proxy response too long"))
+            } else {
+                Ok(Loop::Continue((stream, head)))
+            }
+        })
+    }))
+}
+
+fn http_connect(stream: TcpStream, host: &str, port: u16, auth: Option<&(String, String)>)
+    -> Box<Future<Item = TcpStream, Error = Error>>
+{
+    let target = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some(&(ref user, ref password)) = auth {
+        let credentials = base64::encode(format!("{}:{}", user, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+
+    let authenticated = auth.is_some();
+    Box::new(write_all(stream, request.into_bytes())
+        .map_err(Error::from)
+        .and_then(|(stream, _)| read_head(stream))
+        .and_then(move |(stream, head)| {
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut response = httparse::Response::new(&mut headers);
+            response.parse(&head)?;
+
+            match response.code {
+                Some(code) if code >= 200 && code < 300 => Ok(stream),
+                Some(407) if authenticated => Err(format_err!("proxy refused the credentials")),
+                Some(407) => Err(format_err!("proxy requires authentication")),
+                Some(code) => Err(format_err!("proxy answered {} to CONNECT {}", code, target)),
+                None => Err(format_err!("invalid proxy response")),
+            }
+        }))
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "ttl expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// RFC 1928, with the username and password authentication of RFC 1929.
+fn socks5_connect(stream: TcpStream, host: &str, port: u16, auth: Option<(String, String)>)
+    -> Box<Future<Item = TcpStream, Error = Error>>
+{
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Box::new(future::err(format_err!("{} is too long for socks5", host)));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+
+    let greeting = if auth.is_some() { vec![5, 2, 0, 2] } else { vec![5, 1, 0] };
+
+    Box::new(write_all(stream, greeting)
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .map_err(Error::from)
+        .and_then(move |(stream, choice)| -> Box<Future<Item = TcpStream, Error = Error>> {
+            if choice[0] != 5 {
+                return Box::new(future::err(format_err!("not a socks5 proxy")));
+            }
+            match (choice[1], auth) {
+                (0, _) => Box::new(future::ok(stream)),
+                (2, Some((user, password))) => {
+                    if user.len() > 255 || password.len() > 255 {
+                        return Box::new(future::err(format_err!("socks5 credentials too long")));
+                    }
+                    let mut login = vec![1, user.len() as u8];
+                    login.extend_from_slice(user.as_bytes());
+                    login.push(password.len() as u8);
+                    login.extend_from_slice(password.as_bytes());
+
+                    Box::new(write_all(stream, login)
+                        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+                        .map_err(Error::from)
+                        .and_then(|(stream, status)| -> Result<TcpStream, Error> {
+                            if status[1] != 0 {
+                                bail!("proxy refused the credentials");
+                            }
+                            Ok(stream)
+                        }))
+                }
+                (2, None) => Box::new(future::err(format_err!("proxy requires authentication"))),
+                _ => Box::new(future::err(format_err!("proxy accepts none of our authentication methods"))),
+            }
+        })
+        .and_then(move |stream| write_all(stream, request).map_err(Error::from))
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 4]).map_err(Error::from))
+        .and_then(|(stream, reply)| -> Box<Future<Item = TcpStream, Error = Error>> {
+            if reply[1] != 0 {
+                return Box::new(future::err(format_err!("proxy failed to connect: {}", socks5_error(reply[1]))));
+            }
+
+            // the address the proxy bound, of no interest
+            let skip: Box<Future<Item = (TcpStream, usize), Error = Error>> = match reply[3] {
+                1 => Box::new(future::ok((stream, 4 + 2))),
+                4 => Box::new(future::ok((stream, 16 + 2))),
+                3 => Box::new(read_exact(stream, [0u8; 1]).map(|(stream, len)| (stream, len[0] as usize + 2))
+                                                     .map_err(Error::from)),
+                other => return Box::new(future::err(format_err!("invalid socks5 address type {}", other))),
+            };
+            Box::new(skip.and_then(|(stream, len)| read_exact(stream, vec![0u8; len]).map_err(Error::from))
+                         .map(|(stream, _)| stream))
+        }))
+}
+
+/// The search domains of this network.
+fn search_domains(resolv_conf: &str) -> Vec<String> {
+    let mut domains = Vec::new();
+    for line in resolv_conf.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("search") | Some("domain") => {
+                for domain in words {
+                    let domain = domain.trim_right_matches('.').to_string();
+                    if !domain.is_empty() && !domains.contains(&domain) {
+                        domains.push(domain);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    domains
+}
+
+/// Second level labels under which anyone can register, as in `co.uk`. Not
+/// the public suffix list, but a `wpad.co.uk` answered by a stranger is worse
+/// than a missed proxy.
+const PUBLIC_SECOND_LEVEL: &'static [&'static str] = &[
+    "ac", "co", "com", "edu", "gen", "go", "gob", "gov", "ltd", "mil", "ne", "net", "nom", "or", "org", "plc",
+];
+
+/// Labels of the shortest domain someone registered: `hotel.example` and
+/// `hotel.co.uk`.
+fn registrable_labels(labels: &[&str]) -> usize {
+    let n = labels.len();
+    if n >= 2 && labels[n - 1].len() == 2 && PUBLIC_SECOND_LEVEL.contains(&labels[n - 2]) {
+        3
+    } else {
+        2
+    }
+}
+
+/// `wpad.<domain>` for the domain and its parents, down to the registrable
+/// domain. Nothing above it, those belong to strangers.
+fn wpad_hosts(domain: &str) -> Vec<String> {
+    let labels: Vec<&str> = domain.split('.').filter(|l| !l.is_empty()).collect();
+    let shortest = registrable_labels(&labels);
+    (0..(labels.len() + 1).saturating_sub(shortest))
+        .map(|i| format!("wpad.{}", labels[i..].join(".")))
+        .collect()
+}
+
+/// The first proxy of a PAC file. A PAC file is javascript, this only looks
+/// for the first `PROXY` or `SOCKS5` it returns. `None` also for `DIRECT`.
+fn pac_proxy(pac: &str) -> Option<Proxy> {
+    fn quote(c: char) -> bool {
+        c == '"' || c == '\''
+    }
+    for (i, _) in pac.match_indices(quote) {
+        let candidates = pac[i + 1..].split(quote).next().unwrap_or("");
+        for candidate in candidates.split(';') {
+            let mut words = candidate.split_whitespace();
+            let url = match (words.next(), words.next()) {
+                (Some("PROXY"), Some(hostport)) => format!("http://{}", hostport),
+                (Some("SOCKS5"), Some(hostport)) => format!("socks5://{}", hostport),
+                _ => continue,
+            };
+            if let Ok(proxy) = Proxy::parse(&url) {
+                return Some(proxy);
+            }
+        }
+    }
+    None
+}
+
+/// Fetches a small file over plain http.
+fn http_get(addr: SocketAddr, host: &str, path: &str) -> Result<String, Error> {
+    let timeout = Duration::from_secs(WPAD_TIMEOUT_SECS);
+    let mut stream = net::TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host)?;
+    let mut buf = Vec::new();
+    stream.take(64 * 1024).read_to_end(&mut buf)?;
+
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut response = httparse::Response::new(&mut headers);
+    let len = match response.parse(&buf)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => bail!("incomplete response"),
+    };
+    if response.code != Some(200) {
+        bail!("answered {:?}", response.code);
+    }
+
+    Ok(String::from_utf8_lossy(&buf[len..]).into_owned())
+}
+
+/// Looks for `wpad.dat` next to the search domains. `resolve` looks up the
+/// addresses of a name.
+pub fn discover<F>(mut resolve: F) -> Option<Proxy>
+    where F: FnMut(&str) -> Vec<IpAddr>
+{
+    let mut resolv_conf = String::new();
+    for path in RESOLV_CONFS {
+        if let Ok(mut f) = File::open(path) {
+            let _ = f.read_to_string(&mut resolv_conf);
+        }
+    }
+
+    for domain in search_domains(&resolv_conf) {
+        for host in wpad_hosts(&domain) {
+            for ip in resolve(&host) {
+                match http_get(SocketAddr::new(ip, 80), &host, "/wpad.dat") {
+                    Ok(pac) => {
+                        let proxy = pac_proxy(&pac);
+                        match proxy {
+                            Some(ref proxy) => info!("wpad: {} at {} says {}", host, ip, proxy),
+                            None => info!("wpad: {} at {} says DIRECT", host, ip),
+                        }
+                        return proxy;
+                    }
+                    Err(e) => debug!("wpad: {} at {}: {}", host, ip, e),
+                }
+            }
+        }
+    }
+
+    info!("wpad: no proxy found");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Proxy::parse("http://proxy.venue:3128").unwrap(), Proxy {
+            kind: Kind::Http,
+            host: "proxy.venue".to_string(),
+            port: 3128,
+            auth: None,
+        });
+
+        assert_eq!(Proxy::parse("socks5://us%40er:p%3Ass@[2001:db8::1]/").unwrap(), Proxy {
+            kind: Kind::Socks5,
+            host: "2001:db8::1".to_string(),
+            port: SOCKS5_PORT,
+            auth: Some(("us@er".to_string(), "p:ss".to_string())),
+        });
+
+        assert_eq!(Proxy::parse("http://10.0.0.1").unwrap().port, HTTP_PORT);
+        assert_eq!(Proxy::parse("http://u:p@10.0.0.1").unwrap().to_string(), "http://10.0.0.1:8080");
+        assert!(Proxy::parse("ftp://10.0.0.1").is_err());
+        assert!(Proxy::parse("http://:80").is_err());
+        assert!(Proxy::parse("http://host:port").is_err());
+    }
+
+    #[test]
+    fn test_setting() {
+        assert_eq!(Setting::from_config(None).unwrap(), Setting::Direct);
+        assert_eq!(Setting::from_config(Some("wpad")).unwrap(), Setting::Wpad);
+        match Setting::from_config(Some("socks5://10.0.0.1")).unwrap() {
+            Setting::Fixed(proxy) => assert_eq!(proxy.kind, Kind::Socks5),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_wpad_hosts() {
+        assert_eq!(wpad_hosts("guest.hotel.example"), vec!["wpad.guest.hotel.example", "wpad.hotel.example"]);
+        assert_eq!(wpad_hosts("lan"), Vec::<String>::new());
+        assert_eq!(wpad_hosts("guest.hotel.co.uk"), vec!["wpad.guest.hotel.co.uk", "wpad.hotel.co.uk"]);
+        assert_eq!(wpad_hosts("co.uk"), Vec::<String>::new());
+        assert_eq!(wpad_hosts("hotel.de"), vec!["wpad.hotel.de"]);
+
+        let conf = "# Interface wan\nsearch hotel.example lan\nnameserver 10.0.0.1\n";
+        assert_eq!(search_domains(conf), vec!["hotel.example", "lan"]);
+    }
+
+    #[test]
+    fn test_pac() {
+        let pac = r#"
+            function FindProxyForURL(url, host) {
+                if (isPlainHostName(host)) return "DIRECT";
+                return "PROXY proxy.hotel.example:3128; DIRECT";
+            }"#;
+        assert_eq!(pac_proxy(pac).unwrap(), Proxy::parse("http://proxy.hotel.example:3128").unwrap());
+
+        let pac = "function FindProxyForURL(url, host) { return 'SOCKS5 10.0.0.2:1080'; }";
+        assert_eq!(pac_proxy(pac).unwrap().kind, Kind::Socks5);
+
+        let pac = "function FindProxyForURL(url, host) { return \"DIRECT\"; }";
+        assert_eq!(pac_proxy(pac), None);
+    }
+}
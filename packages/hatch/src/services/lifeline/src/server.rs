@@ -0,0 +1,607 @@
+//! The other end of lifeline: accepts devices, authenticates them and exposes
+//! their services on this machine, as a local port and as a unix socket each.
+//!
+//! Meant for running lifeline on a laptop and for tests. It speaks plaintext
+//! only, put a tls terminating proxy in front of it to test tls.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use base64;
+use bytes::Bytes;
+use ed25519_dalek::Keypair;
+use failure::Error;
+use futures::future::{self, Either};
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Sink, Stream};
+use libc;
+use serde_json;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Interval};
+use tokio_io::AsyncRead;
+use tokio_io::codec::Framed;
+use tokio_io::io::{lines, write_all};
+use tokio_uds::{UnixListener, UnixStream};
+
+use auth::{self, NONCE_LEN};
+use command::{unix_now, Outcome, SignedCommand};
+use control::Control;
+use discovery::SignedServerList;
+use mux::Mux;
+use telemetry::Telemetry;
+use transport::{with_timeout, HalfClose, Io};
+use websocket::{self, Codec, Frame, HandshakeCodec, Role};
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// Devices ping every 30 seconds, a device silent for this long is gone.
+const IDLE_TIMEOUT_SECS: u64 = 90;
+
+/// How long to wait for the outcome of a command.
+const COMMAND_TIMEOUT_SECS: u64 = 120;
+
+/// The connected devices, as json, in `Options::dir`.
+pub const DEVICES_FILE: &'static str = "devices.json";
+
+/// Every telemetry report of a device is appended to `<identity>.json` in
+/// this directory in `Options::dir`, one json object per line.
+pub const TELEMETRY_DIR: &'static str = "telemetry";
+
+/// A unix socket in `Options::dir`. Each connection writes one signed command
+/// as a line of json and gets the outcome back the same way.
+pub const CONTROL_SOCKET: &'static str = "control.sock";
+
+type Transport = Framed<TcpStream, Codec>;
+
+pub struct Options {
+    /// The key the devices have pinned as `server_key`.
+    pub keypair: Keypair,
+    pub listen: SocketAddr,
+    /// Where the unix sockets and the device list go.
+    pub dir: PathBuf,
+    /// Services exposed for every device.
+    pub services: Vec<String>,
+    /// Identities allowed to connect, anyone if empty.
+    pub allow: Vec<String>,
+    /// Handed to devices that have an older list.
+    pub server_list: Option<SignedServerList>,
+}
+
+/// A connected device and where its services are reachable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Connected {
+    pub identity: String,
+    pub peer: SocketAddr,
+    /// unix time of the connection
+    pub since: u64,
+    pub ports: BTreeMap<String, u16>,
+    pub sockets: BTreeMap<String, PathBuf>,
+    /// The last telemetry of the device, and when it arrived in unix time.
+    #[serde(default)]
+    pub telemetry: Option<Telemetry>,
+    #[serde(default)]
+    pub reported: Option<u64>,
+}
+
+/// A line in the telemetry log of a device.
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    time: u64,
+    #[serde(flatten)]
+    telemetry: &'a Telemetry,
+}
+
+struct Slot {
+    info: Connected,
+    conn: u64,
+    /// The frames to the device.
+    tx: mpsc::UnboundedSender<Frame>,
+    /// Dropping these ends the listeners and the session.
+    _listeners: oneshot::Sender<()>,
+    _session: oneshot::Sender<()>,
+}
+
+struct Inner {
+    opts: Options,
+    handle: Handle,
+    devices: RefCell<HashMap<String, Slot>>,
+    next_conn: Cell<u64>,
+    /// Commands waiting for their outcome by id, with the device they were
+    /// sent to.
+    pending: RefCell<HashMap<String, (String, oneshot::Sender<Outcome>)>>,
+}
+
+#[derive(Clone)]
+pub struct Server {
+    inner: Rc<Inner>,
+}
+
+impl Server {
+    pub fn new(opts: Options, handle: &Handle) -> Result<Server, Error> {
+        fs::create_dir_all(opts.dir.join(TELEMETRY_DIR))?;
+
+        Ok(Server {
+            inner: Rc::new(Inner {
+                opts: opts,
+                handle: handle.clone(),
+                devices: RefCell::new(HashMap::new()),
+                next_conn: Cell::new(0),
+                pending: RefCell::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// The currently connected devices.
+    pub fn devices(&self) -> Vec<Connected> {
+        let mut devices: Vec<Connected> = self.inner.devices.borrow().values().map(|s| s.info.clone()).collect();
+        devices.sort_by(|a, b| a.identity.cmp(&b.identity));
+        devices
+    }
+
+    /// Binds the listener. Returns the bound address and the future accepting
+    /// devices, which never completes unless the listener fails.
+    pub fn serve(&self) -> Result<(SocketAddr, Box<Future<Item = (), Error = Error>>), Error> {
+        let listener = TcpListener::bind(&self.inner.opts.listen, &self.inner.handle)?;
+        let addr = listener.local_addr()?;
+        info!("listening on {}", addr);
+
+        self.write_devices();
+        let control = self.control_socket()?;
+
+        let server = self.clone();
+        let accept = listener.incoming().map_err(Error::from).for_each(move |(stream, peer)| {
+            server.inner.handle.spawn(server.accept(stream, peer).then(move |res| {
+                match res {
+                    Ok(()) => info!("{} disconnected", peer),
+                    Err(e) => warn!("{}: {}", peer, e),
+                }
+                Ok(())
+            }));
+            Ok(())
+        });
+
+        Ok((addr, Box::new(accept.select(control).map(|_| ()).map_err(|(e, _)| e))))
+    }
+
+    /// Sends a command to the device it names and waits for the outcome. The
+    /// server can not check the signature, only the device knows the operator
+    /// key.
+    pub fn command(&self, signed: SignedCommand) -> Box<Future<Item = Outcome, Error = Error>> {
+        let order = match signed.unverified() {
+            Ok(order) => order,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let tx = match self.inner.devices.borrow().get(&order.device) {
+            Some(slot) => slot.tx.clone(),
+            None => return Box::new(future::err(format_err!("{} is not connected", order.device))),
+        };
+
+        let msg = match serde_json::to_vec(&Control::Command(signed)) {
+            Ok(msg) => msg,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+        self.inner.pending.borrow_mut().insert(order.id.clone(), (order.device.clone(), done_tx));
+        info!("sending command {} to {}: {:?}", order.id, order.device, order.command);
+        let _ = tx.unbounded_send(Frame::Text(Bytes::from(msg)));
+
+        let server = self.clone();
+        let device = order.device;
+        let id = order.id;
+        let outcome = done_rx.map_err(move |_| format_err!("{} disconnected", device));
+
+        Box::new(with_timeout(outcome, Duration::from_secs(COMMAND_TIMEOUT_SECS), &self.inner.handle, "command")
+            .then(move |res| {
+                server.inner.pending.borrow_mut().remove(&id);
+                res
+            }))
+    }
+
+    /// Runs the commands written to `CONTROL_SOCKET`.
+    fn control_socket(&self) -> Result<Box<Future<Item = (), Error = Error>>, Error> {
+        let path = self.inner.opts.dir.join(CONTROL_SOCKET);
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path, &self.inner.handle)?;
+
+        let server = self.clone();
+        Ok(Box::new(listener.incoming().map_err(Error::from).for_each(move |(stream, _)| {
+            let server2 = server.clone();
+            let (r, w) = stream.split();
+
+            let request = lines(BufReader::new(r)).into_future().map_err(|(e, _)| Error::from(e));
+            let work = request
+                .and_then(move |(line, _)| -> Box<Future<Item = Outcome, Error = Error>> {
+                    let line = line.unwrap_or_default();
+                    match serde_json::from_str::<SignedCommand>(&line) {
+                        Ok(signed) => {
+                            let id = signed.unverified().map(|o| o.id).unwrap_or_default();
+                            Box::new(server2.command(signed).or_else(move |e| Ok::<Outcome, Error>(refused(id, e))))
+                        }
+                        Err(e) => Box::new(future::ok(refused(String::new(), e.into()))),
+                    }
+                })
+                .and_then(|outcome| -> Result<Vec<u8>, Error> {
+                    let mut msg = serde_json::to_vec(&outcome)?;
+                    msg.push(b'\n');
+                    Ok(msg)
+                })
+                .and_then(move |msg| write_all(w, msg).map_err(Error::from))
+                .then(|res| {
+                    if let Err(e) = res {
+                        warn!("{}: {}", CONTROL_SOCKET, e);
+                    }
+                    Ok::<(), ()>(())
+                });
+
+            server.inner.handle.spawn(work);
+            Ok(())
+        })))
+    }
+
+    fn accept(&self, stream: TcpStream, peer: SocketAddr) -> Box<Future<Item = (), Error = Error>> {
+        let server  = self.clone();
+        let server2 = self.clone();
+        let server3 = self.clone();
+        let handle  = self.inner.handle.clone();
+        let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT_SECS);
+
+        let head = stream.framed(HandshakeCodec).into_future().map_err(|(e, _)| e);
+
+        Box::new(with_timeout(head, timeout, &handle, "handshake")
+            .and_then(move |(head, framed)| -> Result<_, Error> {
+                let head = head.ok_or_else(|| format_err!("closed during handshake"))?;
+                let key = head.verify_request()?;
+
+                let identity = head.header("X-LF-Name")
+                    .ok_or_else(|| format_err!("missing X-LF-Name"))?
+                    .to_string();
+                let nonce = base64::decode(head.header("X-LF-Nonce").unwrap_or(""))?;
+                if nonce.len() != NONCE_LEN {
+                    bail!("{} sent an invalid nonce", identity);
+                }
+
+                let allow = &server.inner.opts.allow;
+                if !allow.is_empty() && !allow.contains(&identity) {
+                    bail!("{} is not allowed", identity);
+                }
+
+                Ok((framed, key, identity, nonce))
+            })
+            .and_then(|(framed, key, identity, nonce)| {
+                framed.send(websocket::server_response(&key))
+                    .map_err(Error::from)
+                    .map(move |framed| {
+                        let ws: Transport = Framed::from_parts(framed.into_parts(), Codec::new(Role::Server));
+                        (ws, identity, nonce)
+                    })
+            })
+            .and_then(move |(ws, identity, device_nonce)| server2.authenticate(ws, identity, device_nonce))
+            .and_then(move |(ws, identity)| server3.session(ws, identity, peer)))
+    }
+
+    /// Sends the challenge and waits for the proof of the device.
+    fn authenticate(&self, ws: Transport, identity: String, device_nonce: Vec<u8>)
+        -> Box<Future<Item = (Transport, String), Error = Error>>
+    {
+        let handle = self.inner.handle.clone();
+        let server_nonce = auth::nonce();
+        let challenge = auth::challenge(&self.inner.opts.keypair, &device_nonce, &server_nonce, &identity);
+
+        let msg = match serde_json::to_vec(&challenge) {
+            Ok(msg) => msg,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+
+        Box::new(ws.send(Frame::Text(Bytes::from(msg)))
+            .map_err(Error::from)
+            .and_then(move |ws| with_timeout(ws.into_future().map_err(|(e, _)| e),
+                                             Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+                                             &handle, "authentication"))
+            .and_then(move |(frame, ws)| -> Result<(Transport, String), Error> {
+                let proof: auth::Proof = match frame {
+                    Some(Frame::Text(data)) => serde_json::from_slice(&data)?,
+                    Some(other) => bail!("expected a proof, got {:?}", other),
+                    None => bail!("device closed the connection during authentication"),
+                };
+
+                if !auth::verify_proof(&identity, &device_nonce, &server_nonce, &proof) {
+                    bail!("{} failed to authenticate", identity);
+                }
+
+                Ok((ws, identity))
+            }))
+    }
+
+    /// Runs the session of an authenticated device until it disconnects, goes
+    /// silent or connects again.
+    fn session(&self, ws: Transport, identity: String, peer: SocketAddr) -> Box<Future<Item = (), Error = Error>> {
+        let handle = self.inner.handle.clone();
+        let (sink, frames) = ws.split();
+
+        let (tx, rx) = mpsc::unbounded::<Frame>();
+        let writer = rx
+            .map_err(|()| format_err!("session channel closed"))
+            .forward(sink.sink_map_err(Error::from))
+            .map(|_| ());
+
+        let mux = Mux::new(tx.clone(), handle.clone(), None, 2);
+
+        let conn = self.inner.next_conn.get();
+        self.inner.next_conn.set(conn + 1);
+
+        let (listeners_tx, listeners_rx) = oneshot::channel::<()>();
+        let (session_tx, session_rx) = oneshot::channel::<()>();
+
+        let info = match self.expose(&identity, peer, &mux, &tx, listeners_rx) {
+            Ok(info) => info,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        info!("{} connected from {}", identity, peer);
+        for (service, port) in &info.ports {
+            info!("{} {} on 127.0.0.1:{} and {}", identity, service, port, info.sockets[service].display());
+        }
+
+        // a device that connects again replaces its old session
+        self.inner.devices.borrow_mut().insert(identity.clone(), Slot {
+            info: info,
+            conn: conn,
+            tx: tx.clone(),
+            _listeners: listeners_tx,
+            _session: session_tx,
+        });
+        self.write_devices();
+
+        let last_seen = Rc::new(Cell::new(Instant::now()));
+        let idle = match Interval::new(Duration::from_secs(IDLE_TIMEOUT_SECS / 3), &handle) {
+            Ok(i) => i,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let idle = {
+            let last_seen = last_seen.clone();
+            idle.map_err(Error::from).for_each(move |()| -> Result<(), Error> {
+                if last_seen.get().elapsed() > Duration::from_secs(IDLE_TIMEOUT_SECS) {
+                    bail!("device went silent");
+                }
+                Ok(())
+            })
+        };
+
+        let server = self.clone();
+        let control_identity = identity.clone();
+        let frames_mux = mux.clone();
+        let incoming = frames
+            .map_err(Error::from)
+            .inspect(move |_| last_seen.set(Instant::now()))
+            .take_while(|frame| Ok::<bool, Error>(!frame.is_close()))
+            .for_each(move |frame| -> Result<(), Error> {
+                match frame {
+                    Frame::Ping(data) => {
+                        let _ = tx.unbounded_send(Frame::Pong(data));
+                    }
+                    Frame::Binary(data) => frames_mux.handle_frame(data)?,
+                    Frame::Text(data) => server.control(&tx, &control_identity, conn, &data),
+                    _ => {}
+                }
+                Ok(())
+            });
+
+        let replaced = session_rx.then(|_| Ok::<(), Error>(()));
+
+        let server = self.clone();
+        Box::new(incoming
+            .select(idle).map(|_| ()).map_err(|(e, _)| e)
+            .select(writer).map(|_| ()).map_err(|(e, _)| e)
+            .select(replaced).map(|_| ()).map_err(|(e, _)| e)
+            .then(move |res| {
+                mux.shutdown();
+                server.disconnect(&identity, conn);
+                res
+            }))
+    }
+
+    /// Listens for local connections to the services of a device, until
+    /// `stop` is dropped. The device is told which local user connected to a
+    /// unix socket, for its session audit. Connections to the ports are
+    /// anonymous.
+    fn expose(&self, identity: &str, peer: SocketAddr, mux: &Mux, tx: &mpsc::UnboundedSender<Frame>,
+              stop: oneshot::Receiver<()>)
+        -> Result<Connected, Error>
+    {
+        let handle = &self.inner.handle;
+        let mut ports = BTreeMap::new();
+        let mut sockets = BTreeMap::new();
+        let mut listeners: Vec<Box<Future<Item = (), Error = Error>>> = Vec::new();
+
+        for service in &self.inner.opts.services {
+            let tcp = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)), handle)?;
+            ports.insert(service.clone(), tcp.local_addr()?.port());
+
+            let path = self.inner.opts.dir.join(format!("{}.{}", identity, service));
+            let _ = fs::remove_file(&path);
+            let unix = UnixListener::bind(&path, handle)?;
+            sockets.insert(service.clone(), path);
+
+            let (m, s) = (mux.clone(), service.clone());
+            listeners.push(Box::new(tcp.incoming().map_err(Error::from).for_each(move |(stream, _)| {
+                m.open(&s, Box::new(HalfClose(stream)) as Box<Io>);
+                Ok(())
+            })));
+
+            let (m, s, t, i) = (mux.clone(), service.clone(), tx.clone(), identity.to_string());
+            listeners.push(Box::new(unix.incoming().map_err(Error::from).for_each(move |(stream, _)| -> Result<(), Error> {
+                if let Some(operator) = peer_user(&stream) {
+                    info!("{}: {} opened {}", i, operator, s);
+                    let msg = serde_json::to_vec(&Control::Operator { stream: m.next_id(), operator: operator })?;
+                    let _ = t.unbounded_send(Frame::Text(Bytes::from(msg)));
+                }
+                m.open(&s, Box::new(stream) as Box<Io>);
+                Ok(())
+            })));
+        }
+
+        let identity2 = identity.to_string();
+        handle.spawn(future::join_all(listeners).select2(stop).then(move |res| {
+            if let Err(Either::A((e, _))) = res {
+                warn!("{}: listener failed: {}", identity2, e);
+            }
+            Ok(())
+        }));
+
+        Ok(Connected {
+            identity: identity.to_string(),
+            peer: peer,
+            since: unix_now(),
+            ports: ports,
+            sockets: sockets,
+            telemetry: None,
+            reported: None,
+        })
+    }
+
+    fn control(&self, tx: &mpsc::UnboundedSender<Frame>, identity: &str, conn: u64, data: &[u8]) {
+        match serde_json::from_slice::<Control>(data) {
+            Ok(Control::GetServers { version }) => {
+                let signed = match self.inner.opts.server_list {
+                    Some(ref signed) => signed,
+                    None => return,
+                };
+                match signed.verify(&self.inner.opts.keypair.public) {
+                    Ok(ref list) if list.version > version => {
+                        info!("{} has server list version {}, sending version {}", identity, version, list.version);
+                        if let Ok(msg) = serde_json::to_vec(&Control::Servers(signed.clone())) {
+                            let _ = tx.unbounded_send(Frame::Text(Bytes::from(msg)));
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("not sending server list: {}", e),
+                }
+            }
+            Ok(Control::Telemetry(telemetry)) => self.telemetry(identity, conn, telemetry),
+            Ok(Control::Outcome(outcome)) => self.outcome(identity, outcome),
+            Ok(other) => debug!("{}: {:?}", identity, other),
+            Err(e) => warn!("{}: invalid control message: {}", identity, e),
+        }
+    }
+
+    /// Keeps the telemetry of connection `conn` with the device and logs it.
+    fn telemetry(&self, identity: &str, conn: u64, telemetry: Telemetry) {
+        let now = unix_now();
+        debug!("{}: {:?}", identity, telemetry);
+
+        let path = self.inner.opts.dir.join(TELEMETRY_DIR).join(format!("{}.json", identity));
+        let res = serde_json::to_vec(&Report { time: now, telemetry: &telemetry })
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                let mut f = OpenOptions::new().create(true).append(true).open(&path)?;
+                f.write_all(&line)?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            warn!("cannot write {}: {}", path.display(), e);
+        }
+
+        match self.inner.devices.borrow_mut().get_mut(identity) {
+            Some(slot) if slot.conn == conn => {
+                slot.info.telemetry = Some(telemetry);
+                slot.info.reported = Some(now);
+            }
+            _ => return,
+        }
+        self.write_devices();
+    }
+
+    /// Hands the outcome of a command to whoever is waiting for it.
+    fn outcome(&self, identity: &str, outcome: Outcome) {
+        let entry = self.inner.pending.borrow_mut().remove(&outcome.id);
+        let waiting = match entry {
+            Some((device, waiting)) => {
+                if device != identity {
+                    warn!("{} answered command {} sent to {}", identity, outcome.id, device);
+                    self.inner.pending.borrow_mut().insert(outcome.id.clone(), (device, waiting));
+                    return;
+                }
+                waiting
+            }
+            None => {
+                info!("{}: outcome of command {}: {}", identity, outcome.id, outcome.output);
+                return;
+            }
+        };
+
+        info!("{}: command {} {}: {}", identity, outcome.id, if outcome.ok { "done" } else { "failed" }, outcome.output);
+        let _ = waiting.send(outcome);
+    }
+
+    /// Forgets connection `conn` of a device, unless it was replaced already.
+    fn disconnect(&self, identity: &str, conn: u64) {
+        let slot = {
+            let mut devices = self.inner.devices.borrow_mut();
+            match devices.get(identity).map(|s| s.conn) {
+                Some(c) if c == conn => devices.remove(identity),
+                _ => None,
+            }
+        };
+
+        if let Some(slot) = slot {
+            for path in slot.info.sockets.values() {
+                let _ = fs::remove_file(path);
+            }
+            info!("{} gone", identity);
+            self.write_devices();
+        }
+    }
+
+    fn write_devices(&self) {
+        let path = self.inner.opts.dir.join(DEVICES_FILE);
+        let res = File::create(&path)
+            .map_err(Error::from)
+            .and_then(|f| serde_json::to_writer_pretty(f, &self.devices()).map_err(Error::from));
+
+        if let Err(e) = res {
+            warn!("cannot write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The local user on the other end of a unix socket.
+fn peer_user(stream: &UnixStream) -> Option<String> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+        return None;
+    }
+
+    // the server runs on one thread, getpwuid is safe enough
+    let pw = unsafe { libc::getpwuid(cred.uid) };
+    if pw.is_null() {
+        return Some(format!("uid {}", cred.uid));
+    }
+    Some(unsafe { CStr::from_ptr((*pw).pw_name) }.to_string_lossy().into_owned())
+}
+
+fn refused(id: String, e: Error) -> Outcome {
+    Outcome { id: id, ok: false, output: e.to_string() }
+}
+
+/// Reads the devices a server in `dir` has connected.
+pub fn list(dir: &Path) -> Result<Vec<Connected>, Error> {
+    let f = File::open(dir.join(DEVICES_FILE))?;
+    Ok(serde_json::from_reader(f)?)
+}
@@ -0,0 +1,48 @@
+//! What lifeline is doing right now, logged on every change and kept in
+//! `STATE_PATH` for anyone who wants to look.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::IpAddr;
+
+use serde_json;
+
+pub const STATE_PATH: &'static str = "/tmp/lifeline.state";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum State {
+    Resolving,
+    Dialing { addresses: usize },
+    Connected { server: String, ip: IpAddr },
+    /// Waiting after a failed or ended connection.
+    Waiting { ms: u64, failures: u32 },
+    /// Too many reconnects recently.
+    RateLimited { ms: u64 },
+}
+
+pub fn report(state: &State) {
+    match *state {
+        State::Resolving => info!("state: resolving servers"),
+        State::Dialing { addresses } => info!("state: dialing {} addresses", addresses),
+        State::Connected { ref server, ip } => info!("state: connected to {} at {}", server, ip),
+        State::Waiting { ms, failures } =>
+            info!("state: reconnecting in {}.{:03}s after {} failures", ms / 1000, ms % 1000, failures),
+        State::RateLimited { ms } =>
+            warn!("state: too many reconnects, waiting {}.{:03}s", ms / 1000, ms % 1000),
+    }
+
+    if let Err(e) = write(state) {
+        debug!("cannot write {}: {}", STATE_PATH, e);
+    }
+}
+
+fn write(state: &State) -> ::std::io::Result<()> {
+    let tmp = format!("{}.tmp", STATE_PATH);
+    {
+        let mut f = File::create(&tmp)?;
+        serde_json::to_writer(&mut f, state)?;
+        f.write_all(b"\n")?;
+    }
+    fs::rename(&tmp, STATE_PATH)
+}
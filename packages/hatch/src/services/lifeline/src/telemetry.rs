@@ -0,0 +1,175 @@
+//! What a device tells the server about itself, sent as a control message
+//! every few minutes. Everything is optional, a device sends what it could
+//! find out and the server keeps whatever arrived last.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Command;
+
+use serde_json;
+
+/// The genesis partition, `genesis` and `config` in it link to files named
+/// after their hash.
+const GENESIS_DIR: &'static str = "/genesis";
+const RELEASE_PATH: &'static str = "/etc/openwrt_release";
+
+/// Fills in what lifeline can not know by itself, like the state of sentry.
+pub type Probe = Box<Fn(&mut Telemetry)>;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Telemetry {
+    /// Seconds since boot.
+    pub uptime: Option<u64>,
+    /// The 1, 5 and 15 minute load averages.
+    pub load: Option<[f32; 3]>,
+    /// Bytes.
+    pub mem_total: Option<u64>,
+    pub mem_available: Option<u64>,
+    pub wan_ip: Option<IpAddr>,
+    /// The openwrt revision the firmware was built from.
+    pub firmware: Option<String>,
+    /// Hashes of the genesis package and config the device booted with.
+    pub genesis: Option<String>,
+    pub config: Option<String>,
+    /// Clients authorized on the public wifi.
+    pub sentry_clients: Option<usize>,
+    pub public_wifi: Option<bool>,
+}
+
+impl Telemetry {
+    /// Collects what this device currently looks like.
+    pub fn collect(probe: &Probe) -> Telemetry {
+        let mut t = Telemetry::default();
+
+        t.uptime = read("/proc/uptime").and_then(|s| parse_uptime(&s));
+        t.load = read("/proc/loadavg").and_then(|s| parse_loadavg(&s));
+        if let Some(meminfo) = read("/proc/meminfo") {
+            let (total, available) = parse_meminfo(&meminfo);
+            t.mem_total = total;
+            t.mem_available = available;
+        }
+        t.wan_ip = ubus(&["call", "network.interface.wan", "status"]).and_then(|s| parse_wan_status(&s));
+        t.firmware = read(RELEASE_PATH).and_then(|s| parse_release(&s));
+        t.genesis = hash_of(&Path::new(GENESIS_DIR).join("genesis"));
+        t.config = hash_of(&Path::new(GENESIS_DIR).join("config"));
+
+        probe(&mut t);
+        t
+    }
+}
+
+fn read(path: &str) -> Option<String> {
+    let mut buf = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut buf)) {
+        Ok(_) => Some(buf),
+        Err(e) => {
+            debug!("cannot read {}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn ubus(args: &[&str]) -> Option<String> {
+    match Command::new("ubus").args(args).output() {
+        Ok(ref output) if output.status.success() => String::from_utf8(output.stdout.clone()).ok(),
+        Ok(_) => None,
+        Err(e) => {
+            debug!("cannot run ubus: {}", e);
+            None
+        }
+    }
+}
+
+fn hash_of(link: &Path) -> Option<String> {
+    fs::read_link(link).ok()
+        .and_then(|target| target.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()))
+}
+
+fn parse_uptime(s: &str) -> Option<u64> {
+    s.split_whitespace().next()
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .map(|secs| secs as u64)
+}
+
+fn parse_loadavg(s: &str) -> Option<[f32; 3]> {
+    let mut fields = s.split_whitespace().map(|f| f.parse::<f32>());
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(Ok(a)), Some(Ok(b)), Some(Ok(c))) => Some([a, b, c]),
+        _ => None,
+    }
+}
+
+/// Total and available memory. Kernels before 3.14 have no `MemAvailable`,
+/// `MemFree` is the closest.
+fn parse_meminfo(s: &str) -> (Option<u64>, Option<u64>) {
+    let field = |name: &str| s.lines()
+        .find(|line| line.starts_with(name))
+        .and_then(|line| line[name.len()..].split_whitespace().next())
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024);
+
+    (field("MemTotal:"), field("MemAvailable:").or_else(|| field("MemFree:")))
+}
+
+fn parse_wan_status(s: &str) -> Option<IpAddr> {
+    let status: serde_json::Value = serde_json::from_str(s).ok()?;
+    ["ipv4-address", "ipv6-address"].iter()
+        .filter_map(|family| status[*family][0]["address"].as_str())
+        .filter_map(|addr| addr.parse().ok())
+        .next()
+}
+
+fn parse_release(s: &str) -> Option<String> {
+    let line = s.lines().find(|line| line.starts_with("DISTRIB_REVISION="))?;
+    let revision = line["DISTRIB_REVISION=".len()..].trim_matches(|c| c == '\'' || c == '"');
+    if revision.is_empty() {
+        return None;
+    }
+    Some(revision.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proc() {
+        assert_eq!(parse_uptime("35161.74 62484.01\n"), Some(35161));
+        assert_eq!(parse_loadavg("0.08 0.12 0.09 1/54 2131\n"), Some([0.08, 0.12, 0.09]));
+        assert_eq!(parse_loadavg("0.08\n"), None);
+
+        let meminfo = "MemTotal:         124884 kB\nMemFree:           70412 kB\nMemAvailable:      58716 kB\n";
+        assert_eq!(parse_meminfo(meminfo), (Some(124884 * 1024), Some(58716 * 1024)));
+
+        let old = "MemTotal:         124884 kB\nMemFree:           70412 kB\n";
+        assert_eq!(parse_meminfo(old), (Some(124884 * 1024), Some(70412 * 1024)));
+    }
+
+    #[test]
+    fn test_wan_status() {
+        let status = r#"{"up": true, "ipv4-address": [{"address": "85.10.1.7", "mask": 24}], "ipv6-address": []}"#;
+        assert_eq!(parse_wan_status(status), Some("85.10.1.7".parse().unwrap()));
+
+        let down = r#"{"up": false, "ipv4-address": [], "ipv6-address": []}"#;
+        assert_eq!(parse_wan_status(down), None);
+        assert_eq!(parse_wan_status("Command failed"), None);
+    }
+
+    #[test]
+    fn test_release() {
+        let release = "DISTRIB_ID='OpenWrt'\nDISTRIB_REVISION='r6755+1-d089a5d773'\nDISTRIB_TARGET='ar71xx/generic'\n";
+        assert_eq!(parse_release(release), Some("r6755+1-d089a5d773".to_string()));
+        assert_eq!(parse_release("DISTRIB_ID='OpenWrt'\n"), None);
+    }
+
+    #[test]
+    fn test_defaults() {
+        // fields a device does not know or an older device does not send
+        let t: Telemetry = serde_json::from_str(r#"{"uptime": 12, "something_new": 1}"#).unwrap();
+        assert_eq!(t.uptime, Some(12));
+        assert_eq!(t.wan_ip, None);
+    }
+}
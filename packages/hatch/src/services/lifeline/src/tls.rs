@@ -0,0 +1,181 @@
+//! TLS to the lifeline servers, authenticated by SPKI pins instead of a CA.
+//!
+//! A pin is the base64 sha256 of the DER `SubjectPublicKeyInfo` of the server
+//! certificate, the same value as the `pin-sha256` of HPKP:
+//!
+//! ```text
+//! openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der \
+//!     | openssl dgst -sha256 -binary | base64
+//! ```
+//!
+//! Only the leaf certificate is checked. The chain is not verified, so a pin on
+//! an intermediate would let anyone present a copy of that intermediate.
+
+use std::sync::Arc;
+
+use failure::Error;
+use base64;
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use sha2::{Digest, Sha256};
+use webpki;
+
+/// Splits the next DER element off `input`.
+/// Returns the tag, the whole element, its content and the rest of the input.
+fn der_next(input: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    if input.len() < 2 {
+        return None;
+    }
+
+    let tag = input[0];
+    let (len, header) = match input[1] {
+        l if l < 0x80 => (l as usize, 2),
+        0x81...0x84 => {
+            let n = (input[1] & 0x7f) as usize;
+            if input.len() < 2 + n {
+                return None;
+            }
+            let len = input[2..2 + n].iter().fold(0usize, |acc, b| acc << 8 | *b as usize);
+            (len, 2 + n)
+        }
+        _ => return None,
+    };
+
+    if input.len() < header + len {
+        return None;
+    }
+
+    Some((tag, &input[..header + len], &input[header..header + len], &input[header + len..]))
+}
+
+/// Returns the DER `SubjectPublicKeyInfo` of a DER certificate.
+pub fn spki(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, _, cert, _) = der_next(cert)?;
+    let (_, _, mut tbs, _) = der_next(cert)?;
+
+    // version [0] EXPLICIT is optional
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_next(tbs)?.3;
+    }
+
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_next(tbs)?.3;
+    }
+
+    match der_next(tbs)? {
+        (0x30, spki, _, _) => Some(spki),
+        _ => None,
+    }
+}
+
+/// Computes the pin of a DER certificate.
+pub fn pin(cert: &[u8]) -> Option<String> {
+    spki(cert).map(|spki| {
+        let mut sha = Sha256::default();
+        sha.input(spki);
+        base64::encode(&sha.result())
+    })
+}
+
+struct PinVerifier {
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(&self,
+                          _roots: &RootCertStore,
+                          presented_certs: &[Certificate],
+                          _dns_name: webpki::DNSNameRef,
+                          _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        let leaf = presented_certs.first()
+            .ok_or_else(|| TLSError::NoCertificatesPresented)?;
+
+        match pin(&leaf.0) {
+            Some(ref pin) if self.pins.contains(pin) => Ok(ServerCertVerified::assertion()),
+            Some(pin) => Err(TLSError::General(format!("certificate pin {} is not pinned", pin))),
+            None => Err(TLSError::General("cannot parse the server certificate".to_string())),
+        }
+    }
+}
+
+/// Creates a client config that only accepts servers with one of the `pins`.
+pub fn client_config(pins: &[String]) -> Result<Arc<ClientConfig>, Error> {
+    if pins.is_empty() {
+        bail!("no tls pins configured");
+    }
+
+    let mut config = ClientConfig::new();
+    config.dangerous().set_certificate_verifier(Arc::new(PinVerifier {
+        pins: pins.to_vec(),
+    }));
+
+    Ok(Arc::new(config))
+}
+
+/// The name used for SNI.
+pub fn server_name(hostname: &str) -> Result<webpki::DNSNameRef, Error> {
+    webpki::DNSNameRef::try_from_ascii_str(hostname)
+        .map_err(|()| format_err!("{} is not a valid tls server name", hostname))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut v = vec![tag];
+        if content.len() < 0x80 {
+            v.push(content.len() as u8);
+        } else {
+            v.push(0x82);
+            v.push((content.len() >> 8) as u8);
+            v.push(content.len() as u8);
+        }
+        v.extend_from_slice(content);
+        v
+    }
+
+    fn cert(with_version: bool, spki: &[u8]) -> Vec<u8> {
+        let mut tbs = Vec::new();
+        if with_version {
+            tbs.extend(tlv(0xa0, &tlv(0x02, &[2])));
+        }
+        tbs.extend(tlv(0x02, &[1]));                   // serial
+        tbs.extend(tlv(0x30, &tlv(0x06, &[1, 2, 3])));  // signature algorithm
+        tbs.extend(tlv(0x30, &[]));                    // issuer
+        tbs.extend(tlv(0x30, &[]));                    // validity
+        tbs.extend(tlv(0x30, &vec![0x05; 200]));       // subject, long form length
+        tbs.extend_from_slice(spki);
+        tbs.extend(tlv(0xa3, &[]));                    // extensions
+
+        let mut cert = tlv(0x30, &tbs);
+        cert.extend(tlv(0x30, &tlv(0x06, &[1, 2, 3])));
+        cert.extend(tlv(0x03, &[0, 1, 2, 3]));
+        tlv(0x30, &cert)
+    }
+
+    #[test]
+    fn test_spki() {
+        let spki_der = tlv(0x30, &tlv(0x03, &[0, 42, 42]));
+
+        assert_eq!(spki(&cert(true, &spki_der)), Some(&spki_der[..]));
+        assert_eq!(spki(&cert(false, &spki_der)), Some(&spki_der[..]));
+    }
+
+    #[test]
+    fn test_pin_differs() {
+        let a = cert(true, &tlv(0x30, &tlv(0x03, &[0, 1])));
+        let b = cert(true, &tlv(0x30, &tlv(0x03, &[0, 2])));
+
+        assert!(pin(&a).is_some());
+        assert_ne!(pin(&a), pin(&b));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let c = cert(true, &tlv(0x30, &tlv(0x03, &[0, 1])));
+        assert!(spki(&c[..c.len() / 2]).is_none());
+        assert!(spki(&[]).is_none());
+    }
+}
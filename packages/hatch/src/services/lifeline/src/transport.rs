@@ -0,0 +1,317 @@
+//! Getting a byte stream to a lifeline server, with or without TLS.
+
+use failure::Error;
+use futures::future::{self, Either};
+use futures::{Async, Future, Poll};
+use futures::future::select_ok;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::ClientConfigExt;
+use rustls::ClientConfig;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+use nix::sys::socket::{setsockopt, sockopt};
+use libc;
+
+use config::{Config, TlsMode};
+use proxy::{Proxy, WPAD};
+use tls;
+
+pub const PLAIN_PORT: u16 = 80;
+pub const TLS_PORT: u16 = 443;
+
+/// Where the servers listen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ports {
+    pub plain: u16,
+    pub tls: u16,
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Ports {
+            plain: PLAIN_PORT,
+            tls: TLS_PORT,
+        }
+    }
+}
+
+const CONNECT_TIMEOUT_MS: u64 = 1000;
+
+/// Time between starting parallel connection attempts, as in RFC 8305.
+const ATTEMPT_DELAY_MS: u64 = 250;
+const TLS_TIMEOUT_SECS: u64 = 10;
+
+/// Anything lifeline can run over.
+pub trait Io: AsyncRead + AsyncWrite {}
+impl<T: AsyncRead + AsyncWrite> Io for T {}
+
+/// A tcp stream that passes on a closed direction. `shutdown` of a tokio-core
+/// stream only flushes, the peer would never see the end of the data.
+pub struct HalfClose(pub TcpStream);
+
+impl Read for HalfClose {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for HalfClose {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncRead for HalfClose {}
+
+impl AsyncWrite for HalfClose {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.0.shutdown(Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// How the servers are reached.
+#[derive(Clone)]
+pub enum Security {
+    Plain,
+    Tls {
+        config: Arc<ClientConfig>,
+        /// Never fall back to plaintext.
+        require: bool,
+    },
+}
+
+impl Security {
+    /// A proxy found with wpad was announced by anyone on the network, it
+    /// never gets to see plaintext: `wpad` requires TLS.
+    pub fn from_config(config: &Config) -> Result<Security, Error> {
+        let wpad = config.proxy.as_ref().map(|p| p.as_str()) == Some(WPAD);
+        let require = match config.tls {
+            _ if wpad        => true,
+            TlsMode::Off     => return Ok(Security::Plain),
+            TlsMode::Prefer  => false,
+            TlsMode::Require => true,
+        };
+
+        match tls::client_config(&config.tls_pins) {
+            Ok(c) => Ok(Security::Tls { config: c, require: require }),
+            Err(e) => if require {
+                Err(e)
+            } else {
+                warn!("{}, using plaintext", e);
+                Ok(Security::Plain)
+            },
+        }
+    }
+}
+
+/// Fails with a timeout error if `f` does not resolve within `dur`.
+pub fn with_timeout<F>(f: F, dur: Duration, handle: &Handle, what: &'static str)
+    -> Box<Future<Item = F::Item, Error = Error>>
+    where F: Future + 'static,
+          F::Error: Into<Error>,
+{
+    let timeout = match Timeout::new(dur, handle) {
+        Ok(t) => t,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+
+    Box::new(f.map_err(|e| -> Error { e.into() }).select2(timeout).then(move |res| match res {
+        Ok(Either::A((got, _timeout))) => Ok(got),
+        Ok(Either::B((_timeout, _get))) => Err(format_err!("{} timed out", what)),
+        Err(Either::A((get_error, _timeout))) => Err(get_error),
+        Err(Either::B((timeout_error, _get))) => Err(Error::from(timeout_error)),
+    }))
+}
+
+fn set_keepalive(stream: &TcpStream) {
+    let fd = stream.as_raw_fd();
+    setsockopt(fd, sockopt::KeepAlive,    &true).ok();
+    setsockopt(fd, sockopt::TcpKeepIdle,  &10).ok();
+
+    unsafe{libc::setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, &10 as *const i32 as *const libc::c_void, 4);}
+    unsafe{libc::setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, &10 as *const i32  as *const libc::c_void, 4);}
+}
+
+/// Connects with a short timeout.
+pub fn tcp(addr: SocketAddr, handle: &Handle) -> Box<Future<Item = TcpStream, Error = Error>> {
+    let tcp = TcpStream::connect(&addr, handle);
+    with_timeout(tcp, Duration::from_millis(CONNECT_TIMEOUT_MS), handle, "connect")
+}
+
+/// Connects to `hostname` at `ip`, or through the proxy at `ip`.
+fn open(hostname: &str, ip: IpAddr, port: u16, proxy: Option<&Proxy>, handle: &Handle)
+    -> Box<Future<Item = TcpStream, Error = Error>>
+{
+    match proxy {
+        Some(proxy) => proxy.connect(ip, hostname, port, handle),
+        None => tcp(SocketAddr::new(ip, port), handle),
+    }
+}
+
+fn plain(hostname: String, ip: IpAddr, port: u16, proxy: Option<&Proxy>, handle: &Handle)
+    -> Box<Future<Item = (Box<Io>, bool), Error = Error>>
+{
+    let via = proxy.map(|p| format!(" via {}", p)).unwrap_or_default();
+    Box::new(open(&hostname, ip, port, proxy, handle).map(move |stream| {
+        info!("bearer connected to {} at {}:{}{} in plaintext", hostname, ip, port, via);
+        set_keepalive(&stream);
+        (Box::new(stream) as Box<Io>, true)
+    }))
+}
+
+fn tls(config: Arc<ClientConfig>, hostname: String, stream: TcpStream, handle: &Handle)
+    -> Box<Future<Item = (Box<Io>, bool), Error = Error>>
+{
+    set_keepalive(&stream);
+
+    let handshake = match tls::server_name(&hostname) {
+        Ok(name) => config.connect_async(name, stream),
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    Box::new(with_timeout(handshake, Duration::from_secs(TLS_TIMEOUT_SECS), handle, "tls handshake")
+        .map(move |stream| {
+            info!("bearer connected to {} with tls, pin matched", hostname);
+            (Box::new(stream) as Box<Io>, false)
+        }))
+}
+
+/// Opens a stream to the server at `ip`. With TLS preferred, a server that
+/// cannot be reached on the tls port is retried in plaintext. A failed tls
+/// handshake is never retried in plaintext, that might be an attack.
+///
+/// With a proxy, `ip` is the address of the proxy and `hostname` is resolved
+/// by the proxy.
+///
+/// Also returns whether the stream runs in plaintext.
+pub fn dial(hostname: &str, ip: IpAddr, security: &Security, ports: Ports, proxy: Option<&Proxy>, handle: &Handle)
+    -> Box<Future<Item = (Box<Io>, bool), Error = Error>>
+{
+    let hostname = hostname.to_string();
+
+    let (config, require) = match *security {
+        Security::Plain => return plain(hostname, ip, ports.plain, proxy, handle),
+        Security::Tls { ref config, require } => (config.clone(), require),
+    };
+
+    let handle2 = handle.clone();
+    let proxy2  = proxy.cloned();
+    Box::new(open(&hostname, ip, ports.tls, proxy, handle).then(move |res| match res {
+        Ok(stream) => tls(config, hostname, stream, &handle2),
+        Err(ref e) if !require => {
+            warn!("{}:{} of {} unreachable ({}), falling back to plaintext",
+                  ip, ports.tls, hostname, e);
+            plain(hostname, ip, ports.plain, proxy2.as_ref(), &handle2)
+        }
+        Err(e) => Box::new(future::err(e)),
+    }))
+}
+
+/// Orders addresses for dialing: alternating between the address families,
+/// starting with the family of the first address, otherwise keeping the order
+/// of preference.
+pub fn interleave(candidates: Vec<(String, IpAddr)>) -> Vec<(String, IpAddr)> {
+    let first_v6 = candidates.first().map(|c| c.1.is_ipv6()).unwrap_or(false);
+    let (mut first, mut second): (Vec<_>, Vec<_>) = candidates.into_iter()
+        .partition(|c| c.1.is_ipv6() == first_v6);
+    first.reverse();
+    second.reverse();
+
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop(), second.pop()) {
+            (None, None) => break,
+            (a, b) => {
+                ordered.extend(a);
+                ordered.extend(b);
+            }
+        }
+    }
+    ordered
+}
+
+/// Dials all candidates, Happy Eyeballs style: the attempts are started one
+/// after the other with a short delay, without waiting for the previous one to
+/// fail. The first stream that connects wins and the others are dropped.
+pub fn dial_any(candidates: Vec<(String, IpAddr)>, security: &Security, ports: Ports, proxy: Option<&Proxy>,
+                handle: &Handle)
+    -> Box<Future<Item = (String, IpAddr, Box<Io>, bool), Error = Error>>
+{
+    if candidates.is_empty() {
+        return Box::new(future::err(format_err!("no server addresses")));
+    }
+
+    let attempts: Vec<_> = interleave(candidates).into_iter().enumerate().map(|(i, (hostname, ip))| {
+        let security = security.clone();
+        let handle2 = handle.clone();
+        let proxy = proxy.cloned();
+        let attempt = move || dial(&hostname, ip, &security, ports, proxy.as_ref(), &handle2)
+            .map(move |(io, plaintext)| (hostname, ip, io, plaintext));
+
+        // nothing is dialed before the delay passed
+        let delay = Duration::from_millis(ATTEMPT_DELAY_MS * i as u64);
+        let attempt: Box<Future<Item = _, Error = Error>> = match Timeout::new(delay, handle) {
+            Ok(t) => Box::new(t.map_err(Error::from).and_then(move |()| attempt())),
+            Err(e) => Box::new(future::err(e.into())),
+        };
+        attempt
+    }).collect();
+
+    Box::new(select_ok(attempts).map(|(winner, _rest)| winner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(host: &str, ip: &str) -> (String, IpAddr) {
+        (host.to_string(), ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_interleave() {
+        let got = interleave(vec![
+            c("a", "2001:db8::1"),
+            c("a", "2001:db8::2"),
+            c("a", "192.0.2.1"),
+            c("b", "2001:db8::3"),
+            c("b", "192.0.2.2"),
+        ]);
+
+        assert_eq!(got, vec![
+            c("a", "2001:db8::1"),
+            c("a", "192.0.2.1"),
+            c("a", "2001:db8::2"),
+            c("b", "192.0.2.2"),
+            c("b", "2001:db8::3"),
+        ]);
+    }
+
+    #[test]
+    fn test_wpad_requires_tls() {
+        let mut config = Config::default();
+        config.tls = TlsMode::Off;
+        assert!(match Security::from_config(&config) { Ok(Security::Plain) => true, _ => false });
+
+        // no pins, no tls, no wpad
+        config.proxy = Some(WPAD.to_string());
+        assert!(Security::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_interleave_single_family() {
+        let all = vec![c("a", "192.0.2.1"), c("b", "192.0.2.2")];
+        assert_eq!(interleave(all.clone()), all);
+        assert_eq!(interleave(Vec::new()), Vec::new());
+    }
+}
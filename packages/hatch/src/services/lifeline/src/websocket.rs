@@ -0,0 +1,495 @@
+//! Just enough RFC 6455 to carry lifeline over http middleboxes.
+//!
+//! The handshake is done with `HandshakeCodec`, after which the same socket is
+//! turned into a `Framed` with `Codec`, keeping whatever was already buffered.
+
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio_io::codec::{Decoder, Encoder};
+
+use base64;
+use httparse;
+use rand;
+use sha1;
+
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The lifeline version spoken on top of the websocket.
+pub const PROTOCOL: &'static str = "lifeline.2";
+
+/// Largest http head we accept during the handshake.
+const MAX_HEAD: usize = 8192;
+
+/// Largest message we accept, after reassembling fragments.
+const MAX_PAYLOAD: usize = 1 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Creates a fresh random `Sec-WebSocket-Key`.
+pub fn generate_key() -> String {
+    base64::encode(&rand::random::<[u8; 16]>())
+}
+
+/// Computes the `Sec-WebSocket-Accept` the server has to answer for `key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha = sha1::Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(GUID.as_bytes());
+    base64::encode(&sha.digest().bytes())
+}
+
+/// Builds the upgrade request sent by the device.
+pub fn client_request(path: &str, host: &str, key: &str, extra: &[(&str, &str)]) -> Vec<u8> {
+    let mut req = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n",
+        path, host, key, PROTOCOL
+    );
+
+    for &(name, value) in extra {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    req.push_str("\r\n");
+    req.into_bytes()
+}
+
+/// Builds the response of the server, accepting the upgrade for `key`.
+pub fn server_response(key: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\
+         \r\n",
+        accept_key(key),
+        PROTOCOL
+    ).into_bytes()
+}
+
+/// A parsed http request or response head.
+#[derive(Debug, Default)]
+pub struct Head {
+    /// The request path, for requests.
+    pub path: Option<String>,
+    /// The status code, for responses.
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// Returns the first header with the given name, compared case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    fn header_contains(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    }
+
+    /// Checks that this response accepts the upgrade request made with `key`.
+    pub fn verify_response(&self, key: &str) -> io::Result<()> {
+        if self.status != Some(101) {
+            return Err(protocol_error(&format!(
+                "server answered with status {:?} instead of 101",
+                self.status
+            )));
+        }
+        if !self.header_contains("Upgrade", "websocket") {
+            return Err(protocol_error("missing upgrade: websocket"));
+        }
+        if !self.header_contains("Connection", "upgrade") {
+            return Err(protocol_error("missing connection: upgrade"));
+        }
+        if self.header("Sec-WebSocket-Accept") != Some(accept_key(key).as_str()) {
+            return Err(protocol_error("invalid Sec-WebSocket-Accept"));
+        }
+        if self.header("Sec-WebSocket-Protocol") != Some(PROTOCOL) {
+            return Err(protocol_error("server does not speak lifeline.2"));
+        }
+        Ok(())
+    }
+
+    /// Checks that this is an upgrade request and returns its key.
+    pub fn verify_request(&self) -> io::Result<String> {
+        if !self.header_contains("Upgrade", "websocket") {
+            return Err(protocol_error("missing upgrade: websocket"));
+        }
+        if self.header("Sec-WebSocket-Version") != Some("13") {
+            return Err(protocol_error("unsupported websocket version"));
+        }
+        if !self.header_contains("Sec-WebSocket-Protocol", PROTOCOL) {
+            return Err(protocol_error("client does not speak lifeline.2"));
+        }
+        self.header("Sec-WebSocket-Key")
+            .map(|k| k.to_string())
+            .ok_or_else(|| protocol_error("missing Sec-WebSocket-Key"))
+    }
+}
+
+/// Reads a single http head and writes raw bytes.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Head;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Head>> {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None if buf.len() > MAX_HEAD => return Err(protocol_error("http head too large")),
+            None => return Ok(None),
+        };
+
+        let raw = buf.split_to(end);
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+
+        let mut head = Head::default();
+        if raw.starts_with(b"HTTP/") {
+            let mut resp = httparse::Response::new(&mut headers);
+            resp.parse(&raw)
+                .map_err(|e| protocol_error(&format!("invalid http response: {}", e)))?;
+            head.status = resp.code;
+            head.headers = collect_headers(resp.headers);
+        } else {
+            let mut req = httparse::Request::new(&mut headers);
+            req.parse(&raw)
+                .map_err(|e| protocol_error(&format!("invalid http request: {}", e)))?;
+            head.path = req.path.map(|p| p.to_string());
+            head.headers = collect_headers(req.headers);
+        }
+
+        Ok(Some(head))
+    }
+}
+
+fn collect_headers(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_string(),
+                String::from_utf8_lossy(h.value).trim().to_string(),
+            )
+        })
+        .collect()
+}
+
+impl Encoder for HandshakeCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, data: Vec<u8>, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(&data);
+        Ok(())
+    }
+}
+
+/// A complete websocket message. Fragments are reassembled by the codec.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(Bytes),
+    Binary(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close(Option<u16>),
+}
+
+impl Frame {
+    pub fn is_close(&self) -> bool {
+        match *self {
+            Frame::Close(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Which side of the connection the codec is on. Clients mask, servers do not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Debug)]
+pub struct Codec {
+    role: Role,
+    fragments: Option<(u8, BytesMut)>,
+}
+
+impl Codec {
+    pub fn new(role: Role) -> Codec {
+        Codec {
+            role: role,
+            fragments: None,
+        }
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        loop {
+            if buf.len() < 2 {
+                return Ok(None);
+            }
+
+            let fin = buf[0] & 0x80 != 0;
+            let opcode = buf[0] & 0x0f;
+            let masked = buf[1] & 0x80 != 0;
+
+            if buf[0] & 0x70 != 0 {
+                return Err(protocol_error("reserved bits set"));
+            }
+            if masked != (self.role == Role::Server) {
+                return Err(protocol_error("invalid masking"));
+            }
+
+            let (len, mut offset) = match buf[1] & 0x7f {
+                126 => {
+                    if buf.len() < 4 {
+                        return Ok(None);
+                    }
+                    ((buf[2] as usize) << 8 | buf[3] as usize, 4)
+                }
+                127 => {
+                    if buf.len() < 10 {
+                        return Ok(None);
+                    }
+                    let mut len = 0u64;
+                    for b in &buf[2..10] {
+                        len = len << 8 | u64::from(*b);
+                    }
+                    if len > MAX_PAYLOAD as u64 {
+                        return Err(protocol_error("frame too large"));
+                    }
+                    (len as usize, 10)
+                }
+                len => (len as usize, 2),
+            };
+
+            if len > MAX_PAYLOAD {
+                return Err(protocol_error("frame too large"));
+            }
+
+            let mask = if masked {
+                if buf.len() < offset + 4 {
+                    return Ok(None);
+                }
+                let mut mask = [0u8; 4];
+                mask.copy_from_slice(&buf[offset..offset + 4]);
+                offset += 4;
+                Some(mask)
+            } else {
+                None
+            };
+
+            if buf.len() < offset + len {
+                buf.reserve(offset + len - buf.len());
+                return Ok(None);
+            }
+
+            buf.advance(offset);
+            let mut payload = buf.split_to(len);
+            if let Some(mask) = mask {
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
+                }
+            }
+
+            if opcode >= OP_CLOSE {
+                if !fin || len > 125 {
+                    return Err(protocol_error("invalid control frame"));
+                }
+                return Ok(Some(match opcode {
+                    OP_CLOSE if payload.len() >= 2 => {
+                        Frame::Close(Some((payload[0] as u16) << 8 | payload[1] as u16))
+                    }
+                    OP_CLOSE => Frame::Close(None),
+                    OP_PING => Frame::Ping(payload.freeze()),
+                    OP_PONG => Frame::Pong(payload.freeze()),
+                    _ => return Err(protocol_error("unknown control opcode")),
+                }));
+            }
+
+            let (opcode, payload) = match (opcode, self.fragments.take()) {
+                (OP_CONTINUATION, Some((opcode, mut data))) => {
+                    if data.len() + payload.len() > MAX_PAYLOAD {
+                        return Err(protocol_error("message too large"));
+                    }
+                    data.extend_from_slice(&payload);
+                    (opcode, data)
+                }
+                (OP_CONTINUATION, None) => return Err(protocol_error("unexpected continuation")),
+                (_, Some(_)) => return Err(protocol_error("expected continuation")),
+                (opcode, None) => (opcode, payload),
+            };
+
+            if !fin {
+                self.fragments = Some((opcode, payload));
+                continue;
+            }
+
+            return Ok(Some(match opcode {
+                OP_TEXT => Frame::Text(payload.freeze()),
+                OP_BINARY => Frame::Binary(payload.freeze()),
+                _ => return Err(protocol_error("unknown opcode")),
+            }));
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        let (opcode, payload) = match frame {
+            Frame::Text(data) => (OP_TEXT, data),
+            Frame::Binary(data) => (OP_BINARY, data),
+            Frame::Ping(data) => (OP_PING, data),
+            Frame::Pong(data) => (OP_PONG, data),
+            Frame::Close(Some(code)) => (OP_CLOSE, Bytes::from(&[(code >> 8) as u8, code as u8][..])),
+            Frame::Close(None) => (OP_CLOSE, Bytes::new()),
+        };
+
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        buf.reserve(payload.len() + 14);
+        buf.put_u8(0x80 | opcode);
+
+        if payload.len() < 126 {
+            buf.put_u8(mask_bit | payload.len() as u8);
+        } else if payload.len() <= 0xffff {
+            buf.put_u8(mask_bit | 126);
+            buf.put_u16_be(payload.len() as u16);
+        } else {
+            buf.put_u8(mask_bit | 127);
+            buf.put_u64_be(payload.len() as u64);
+        }
+
+        if self.role == Role::Client {
+            let mask = rand::random::<[u8; 4]>();
+            buf.put_slice(&mask);
+            for (i, b) in payload.iter().enumerate() {
+                buf.put_u8(b ^ mask[i % 4]);
+            }
+        } else {
+            buf.put_slice(&payload);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        let key = generate_key();
+
+        let mut buf = BytesMut::from(client_request("/lifeline/2", "example.org", &key, &[]));
+        let req = HandshakeCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.path, Some(String::from("/lifeline/2")));
+        assert_eq!(req.verify_request().unwrap(), key);
+
+        let mut buf = BytesMut::from(server_response(&key));
+        buf.extend_from_slice(b"\x82\x00");
+        let resp = HandshakeCodec.decode(&mut buf).unwrap().unwrap();
+        resp.verify_response(&key).unwrap();
+        assert!(resp.verify_response(&generate_key()).is_err());
+
+        // the first frame stays buffered
+        assert_eq!(&buf[..], b"\x82\x00");
+    }
+
+    #[test]
+    fn test_handshake_partial() {
+        let mut buf = BytesMut::from(&b"HTTP/1.1 101 Switching Protocols\r\n"[..]);
+        assert!(HandshakeCodec.decode(&mut buf).unwrap().is_none());
+    }
+
+    fn roundtrip(frame: Frame) {
+        let mut buf = BytesMut::new();
+        Codec::new(Role::Client).encode(frame.clone(), &mut buf).unwrap();
+        assert_eq!(Codec::new(Role::Server).decode(&mut buf).unwrap(), Some(frame.clone()));
+        assert!(buf.is_empty());
+
+        Codec::new(Role::Server).encode(frame.clone(), &mut buf).unwrap();
+        assert_eq!(Codec::new(Role::Client).decode(&mut buf).unwrap(), Some(frame));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(Frame::Binary(Bytes::from(&b"hello"[..])));
+        roundtrip(Frame::Binary(Bytes::from(vec![7u8; 300])));
+        roundtrip(Frame::Binary(Bytes::from(vec![9u8; 70000])));
+        roundtrip(Frame::Ping(Bytes::from(&b"ping"[..])));
+        roundtrip(Frame::Pong(Bytes::new()));
+        roundtrip(Frame::Close(Some(1000)));
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut buf = BytesMut::from(&b"\x02\x03hel\x89\x00\x80\x02lo"[..]);
+        let mut codec = Codec::new(Role::Client);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Frame::Ping(Bytes::new())));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Binary(Bytes::from(&b"hello"[..])))
+        );
+    }
+
+    #[test]
+    fn test_partial_frame() {
+        let mut buf = BytesMut::from(&b"\x82\x05hel"[..]);
+        let mut codec = Codec::new(Role::Client);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"lo");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Frame::Binary(Bytes::from(&b"hello"[..])))
+        );
+    }
+
+    #[test]
+    fn test_unmasked_client_frame() {
+        let mut buf = BytesMut::from(&b"\x82\x00"[..]);
+        assert!(Codec::new(Role::Server).decode(&mut buf).is_err());
+    }
+}
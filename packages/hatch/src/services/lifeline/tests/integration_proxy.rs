@@ -0,0 +1,222 @@
+extern crate base64;
+extern crate futures;
+extern crate lifeline;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+use futures::Future;
+use tokio_core::reactor::Core;
+use tokio_io::io::{read_exact, write_all};
+
+use lifeline::proxy::Proxy;
+
+/// A service answering with whatever it gets.
+fn echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut w = stream.try_clone().unwrap();
+            thread::spawn(move || io::copy(&mut stream, &mut w));
+        }
+    });
+
+    addr
+}
+
+/// Connects to `target` and copies in both directions.
+fn relay(client: TcpStream, target: &str) -> io::Result<()> {
+    let upstream = TcpStream::connect(target)?;
+
+    let (mut client_r, mut upstream_w) = (client.try_clone()?, upstream.try_clone()?);
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_r, &mut upstream_w);
+        let _ = upstream_w.shutdown(Shutdown::Write);
+    });
+    let (mut upstream_r, mut client_w) = (upstream, client);
+    io::copy(&mut upstream_r, &mut client_w)?;
+    Ok(())
+}
+
+/// A stand-in for a proxy, answering one connection with `handle`. Whatever
+/// the client asked for is sent to the returned channel.
+fn stand_in<F>(handle: F) -> (SocketAddr, mpsc::Receiver<String>)
+    where F: FnOnce(TcpStream, mpsc::Sender<String>) -> io::Result<()> + Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let _ = handle(stream, tx);
+    });
+
+    (addr, rx)
+}
+
+fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8(head).unwrap())
+}
+
+/// HTTP CONNECT, with Basic authentication if `credentials` is set.
+fn http_proxy(credentials: Option<&'static str>) -> (SocketAddr, mpsc::Receiver<String>) {
+    stand_in(move |mut stream, tx| {
+        let head = read_head(&mut stream)?;
+        let target = head.split_whitespace().nth(1).unwrap().to_string();
+        tx.send(target.clone()).unwrap();
+
+        if let Some(credentials) = credentials {
+            let expected = format!("Proxy-Authorization: Basic {}\r\n", base64::encode(credentials.as_bytes()));
+            if !head.contains(&expected) {
+                stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                                   Proxy-Authenticate: Basic realm=\"venue\"\r\n\r\n")?;
+                return Ok(());
+            }
+        }
+
+        stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+        relay(stream, &target)
+    })
+}
+
+/// SOCKS5, with username and password if `credentials` is set.
+fn socks5_proxy(credentials: Option<(&'static str, &'static str)>) -> (SocketAddr, mpsc::Receiver<String>) {
+    stand_in(move |mut stream, tx| {
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting)?;
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods)?;
+
+        let method = if credentials.is_some() { 2 } else { 0 };
+        if !methods.contains(&method) {
+            stream.write_all(&[5, 0xff])?;
+            return Ok(());
+        }
+        stream.write_all(&[5, method])?;
+
+        if let Some((user, password)) = credentials {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len)?;
+            let mut got_user = vec![0u8; len[1] as usize];
+            stream.read_exact(&mut got_user)?;
+            stream.read_exact(&mut len[..1])?;
+            let mut got_password = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut got_password)?;
+
+            if got_user != user.as_bytes() || got_password != password.as_bytes() {
+                stream.write_all(&[1, 1])?;
+                return Ok(());
+            }
+            stream.write_all(&[1, 0])?;
+        }
+
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request)?;
+        let host = match request[3] {
+            3 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                let mut name = vec![0u8; len[0] as usize];
+                stream.read_exact(&mut name)?;
+                String::from_utf8(name).unwrap()
+            }
+            1 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip)?;
+                format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
+            }
+            other => panic!("unexpected address type {}", other),
+        };
+        let mut port = [0u8; 2];
+        stream.read_exact(&mut port)?;
+        let target = format!("{}:{}", host, (port[0] as u16) << 8 | port[1] as u16);
+        tx.send(target.clone()).unwrap();
+
+        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+        relay(stream, &target)
+    })
+}
+
+/// Connects to the echo service through `proxy` and sends a greeting.
+fn through(proxy: &Proxy, proxy_addr: SocketAddr, echo: SocketAddr) -> Result<Vec<u8>, String> {
+    let mut core = Core::new().unwrap();
+    let connect = proxy.connect(proxy_addr.ip(), "localhost", echo.port(), &core.handle());
+
+    core.run(connect
+        .and_then(|stream| write_all(stream, b"hello proxy").map_err(Into::into))
+        .and_then(|(stream, _)| read_exact(stream, vec![0u8; 11]).map_err(Into::into))
+        .map(|(_, got)| got))
+        .map_err(|e| e.to_string())
+}
+
+fn url(scheme: &str, credentials: &str, addr: SocketAddr) -> Proxy {
+    Proxy::parse(&format!("{}://{}{}", scheme, credentials, addr)).unwrap()
+}
+
+#[test]
+fn test_http_connect() {
+    let echo = echo();
+    let (addr, targets) = http_proxy(None);
+
+    let got = through(&url("http", "", addr), addr, echo).unwrap();
+    assert_eq!(got, b"hello proxy");
+    assert_eq!(targets.recv().unwrap(), format!("localhost:{}", echo.port()));
+}
+
+#[test]
+fn test_http_connect_auth() {
+    let echo = echo();
+    let (addr, _targets) = http_proxy(Some("venue:s3cr:t"));
+
+    let got = through(&url("http", "venue:s3cr%3At@", addr), addr, echo).unwrap();
+    assert_eq!(got, b"hello proxy");
+
+    let (addr, _targets) = http_proxy(Some("venue:s3cr:t"));
+    let err = through(&url("http", "venue:wrong@", addr), addr, echo).unwrap_err();
+    assert!(err.contains("refused the credentials"), "{}", err);
+
+    let (addr, _targets) = http_proxy(Some("venue:s3cr:t"));
+    let err = through(&url("http", "", addr), addr, echo).unwrap_err();
+    assert!(err.contains("requires authentication"), "{}", err);
+}
+
+#[test]
+fn test_socks5() {
+    let echo = echo();
+    let (addr, targets) = socks5_proxy(None);
+
+    let got = through(&url("socks5", "", addr), addr, echo).unwrap();
+    assert_eq!(got, b"hello proxy");
+    assert_eq!(targets.recv().unwrap(), format!("localhost:{}", echo.port()));
+}
+
+#[test]
+fn test_socks5_auth() {
+    let echo = echo();
+    let (addr, _targets) = socks5_proxy(Some(("venue", "s3cret")));
+
+    let got = through(&url("socks5", "venue:s3cret@", addr), addr, echo).unwrap();
+    assert_eq!(got, b"hello proxy");
+
+    let (addr, _targets) = socks5_proxy(Some(("venue", "s3cret")));
+    let err = through(&url("socks5", "venue:wrong@", addr), addr, echo).unwrap_err();
+    assert!(err.contains("refused the credentials"), "{}", err);
+
+    let (addr, _targets) = socks5_proxy(Some(("venue", "s3cret")));
+    let err = through(&url("socks5", "", addr), addr, echo).unwrap_err();
+    assert!(err.contains("none of our authentication methods"), "{}", err);
+}
@@ -0,0 +1,338 @@
+extern crate ed25519_dalek;
+extern crate failure;
+extern crate futures;
+extern crate lifeline;
+extern crate rand;
+extern crate sha2;
+extern crate tempdir;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_uds;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use futures::{Future, Stream};
+use rand::OsRng;
+use sha2::Sha512;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Timeout};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, read_to_end, write_all};
+use tokio_uds::UnixStream;
+
+use lifeline::audit::Session;
+use lifeline::auth;
+use lifeline::command::{self, Command, Commands, Order, SignedCommand};
+use lifeline::device::{self, Device};
+use lifeline::server::{Options, Server};
+use lifeline::telemetry::Telemetry;
+use lifeline::transport::{Io, Ports, Security};
+
+fn keypair() -> Keypair {
+    Keypair::generate::<Sha512>(&mut OsRng::new().unwrap())
+}
+
+fn copy_key(key: &Keypair) -> Keypair {
+    let secret = SecretKey::from_bytes(key.secret.as_bytes()).unwrap();
+    Keypair { public: PublicKey::from_secret::<Sha512>(&secret), secret: secret }
+}
+
+/// A local service answering with whatever it gets.
+fn echo(core: &Core) -> SocketAddr {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = core.handle();
+    core.handle().spawn(listener.incoming().for_each(move |(stream, _)| {
+        let (r, w) = stream.split();
+        handle.spawn(copy(r, w).map(|_| ()).map_err(|_| ()));
+        Ok(())
+    }).map_err(|_| ()));
+
+    addr
+}
+
+fn wait(core: &mut Core, ms: u64) {
+    core.run(Timeout::new(Duration::from_millis(ms), &core.handle()).unwrap()).unwrap();
+}
+
+fn start(core: &mut Core, server_key: &Keypair, allow: Vec<String>, dir: &tempdir::TempDir) -> (Server, SocketAddr) {
+    let server = Server::new(Options {
+        keypair: copy_key(server_key),
+        listen: "127.0.0.1:0".parse().unwrap(),
+        dir: dir.path().to_path_buf(),
+        services: vec!["echo".to_string()],
+        allow: allow,
+        server_list: None,
+    }, &core.handle()).unwrap();
+
+    let (addr, accept) = server.serve().unwrap();
+    core.handle().spawn(accept.map_err(|_| ()));
+    (server, addr)
+}
+
+fn device(device_key: Keypair, server_key: &Keypair, echo: SocketAddr) -> Device {
+    let mut services = HashMap::new();
+    services.insert("echo".to_string(), echo);
+
+    let identity = auth::encode_key(&device_key.public);
+    Device::new(identity, device_key, server_key.public, Security::Plain, Ports::default(), services)
+        .with_probe(Box::new(|t: &mut Telemetry| t.sentry_clients = Some(3)))
+}
+
+fn connect_device(core: &mut Core, device_key: Keypair, server_key: &Keypair, server: SocketAddr, echo: SocketAddr) {
+    run_device(core, device(device_key, server_key, echo), server, false);
+}
+
+/// `plaintext` restricts the device as if it reached the server without TLS.
+fn run_device(core: &mut Core, device: Device, server: SocketAddr, plaintext: bool) {
+    let device = Rc::new(device);
+    let handle = core.handle();
+    core.handle().spawn(TcpStream::connect(&server, &core.handle())
+        .map_err(Into::into)
+        .and_then(move |stream| device::run(Box::new(stream) as Box<Io>, plaintext, device, "localhost", &handle))
+        .map_err(|_| ()));
+}
+
+#[test]
+fn test_end_to_end() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let server_key = keypair();
+    let device_key = keypair();
+    let identity = auth::encode_key(&device_key.public);
+
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &server_key, Vec::new(), &dir);
+    connect_device(&mut core, device_key, &server_key, addr, echo_addr);
+
+    for _ in 0..50 {
+        if !server.devices().is_empty() {
+            break;
+        }
+        wait(&mut core, 20);
+    }
+
+    let devices = server.devices();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].identity, identity);
+    assert!(dir.path().join(format!("{}.echo", identity)).exists());
+
+    let port = devices[0].ports["echo"];
+    let local = SocketAddr::from(([127, 0, 0, 1], port));
+
+    let (_, got) = core.run(TcpStream::connect(&local, &core.handle())
+        .and_then(|s| write_all(s, b"hello lifeline"))
+        .and_then(|(s, _)| read_exact(s, [0u8; 14]))).unwrap();
+
+    assert_eq!(&got, b"hello lifeline");
+}
+
+#[test]
+fn test_wrong_server_key() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &keypair(), Vec::new(), &dir);
+
+    // the device has pinned a different key and refuses the server
+    connect_device(&mut core, keypair(), &keypair(), addr, echo_addr);
+    wait(&mut core, 300);
+
+    assert!(server.devices().is_empty());
+}
+
+#[test]
+fn test_not_allowed() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let server_key = keypair();
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &server_key, vec!["someone else".to_string()], &dir);
+
+    connect_device(&mut core, keypair(), &server_key, addr, echo_addr);
+    wait(&mut core, 300);
+
+    assert!(server.devices().is_empty());
+}
+
+#[test]
+fn test_telemetry() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let server_key = keypair();
+    let device_key = keypair();
+    let identity = auth::encode_key(&device_key.public);
+
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &server_key, Vec::new(), &dir);
+    connect_device(&mut core, device_key, &server_key, addr, echo_addr);
+
+    // sent right after authentication
+    for _ in 0..50 {
+        if server.devices().iter().any(|d| d.telemetry.is_some()) {
+            break;
+        }
+        wait(&mut core, 20);
+    }
+
+    let devices = server.devices();
+    let telemetry = devices[0].telemetry.as_ref().expect("no telemetry");
+    assert_eq!(telemetry.sentry_clients, Some(3));
+    assert!(devices[0].reported.is_some());
+
+    let log = dir.path().join("telemetry").join(format!("{}.json", identity));
+    assert!(log.exists());
+}
+
+#[test]
+fn test_command() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let server_key = keypair();
+    let operator_key = keypair();
+    let device_key = keypair();
+    let identity = auth::encode_key(&device_key.public);
+
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &server_key, Vec::new(), &dir);
+
+    let device = device(device_key, &server_key, echo_addr).with_commands(Commands {
+        operator_key: operator_key.public,
+        executor: Arc::new(|command: &Command| -> Result<String, failure::Error> { Ok(format!("ran {:?}", command)) }),
+        seen: dir.path().join("seen"),
+    });
+    run_device(&mut core, device, addr, false);
+
+    for _ in 0..50 {
+        if !server.devices().is_empty() {
+            break;
+        }
+        wait(&mut core, 20);
+    }
+
+    let order = Order {
+        id: "1".to_string(),
+        device: identity.clone(),
+        expires: command::unix_now() + 60,
+        command: Command::Reboot,
+    };
+    let signed = SignedCommand::sign(&operator_key, &order).unwrap();
+
+    let outcome = core.run(server.command(signed.clone())).unwrap();
+    assert!(outcome.ok, "{}", outcome.output);
+    assert_eq!(outcome.id, "1");
+    assert_eq!(outcome.output, "ran Reboot");
+
+    // the same command again
+    let outcome = core.run(server.command(signed)).unwrap();
+    assert!(!outcome.ok);
+
+    // signed by the server instead of the operators
+    let forged = SignedCommand::sign(&server_key, &Order { id: "2".to_string(), ..order }).unwrap();
+    let outcome = core.run(server.command(forged)).unwrap();
+    assert!(!outcome.ok);
+}
+
+#[test]
+fn test_audit() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let server_key = keypair();
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &server_key, Vec::new(), &dir);
+
+    let sessions = Rc::new(RefCell::new(Vec::<Session>::new()));
+    let recorded = sessions.clone();
+    let device = device(keypair(), &server_key, echo_addr)
+        .with_auditor(Box::new(move |session: &Session| recorded.borrow_mut().push(session.clone())));
+    run_device(&mut core, device, addr, false);
+
+    for _ in 0..50 {
+        if !server.devices().is_empty() {
+            break;
+        }
+        wait(&mut core, 20);
+    }
+
+    // through the unix socket, the server knows who we are
+    let path = server.devices()[0].sockets["echo"].clone();
+    let stream = UnixStream::connect(&path, &core.handle()).unwrap();
+    let (stream, _) = core.run(write_all(stream, b"hello audit")).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let (_, got) = core.run(read_to_end(stream, Vec::new())).unwrap();
+    assert_eq!(got, b"hello audit");
+
+    for _ in 0..50 {
+        if !sessions.borrow().is_empty() {
+            break;
+        }
+        wait(&mut core, 20);
+    }
+
+    let sessions = sessions.borrow();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].service, "echo");
+    assert_eq!(sessions[0].target, echo_addr);
+    assert!(sessions[0].operator.is_some());
+    assert_eq!((sessions[0].bytes_in, sessions[0].bytes_out), (11, 11));
+    assert_eq!(sessions[0].reason, "closed");
+}
+
+#[test]
+fn test_plaintext() {
+    let mut core = Core::new().unwrap();
+    let dir = tempdir::TempDir::new("lifeline").unwrap();
+
+    let server_key = keypair();
+    let operator_key = keypair();
+    let device_key = keypair();
+    let identity = auth::encode_key(&device_key.public);
+
+    let echo_addr = echo(&core);
+    let (server, addr) = start(&mut core, &server_key, Vec::new(), &dir);
+
+    let device = device(device_key, &server_key, echo_addr).with_commands(Commands {
+        operator_key: operator_key.public,
+        executor: Arc::new(|command: &Command| -> Result<String, failure::Error> { Ok(format!("ran {:?}", command)) }),
+        seen: dir.path().join("seen"),
+    });
+    run_device(&mut core, device, addr, true);
+
+    for _ in 0..50 {
+        if !server.devices().is_empty() {
+            break;
+        }
+        wait(&mut core, 20);
+    }
+
+    // anyone in between could have opened it, only ssh is bridged
+    let port = server.devices()[0].ports["echo"];
+    let local = SocketAddr::from(([127, 0, 0, 1], port));
+    let (_, got) = core.run(TcpStream::connect(&local, &core.handle())
+        .and_then(|s| write_all(s, b"hello lifeline"))
+        .and_then(|(s, _)| read_to_end(s, Vec::new()))).unwrap();
+    assert!(got.is_empty());
+
+    let order = Order {
+        id: "1".to_string(),
+        device: identity,
+        expires: command::unix_now() + 60,
+        command: Command::Reboot,
+    };
+    let outcome = core.run(server.command(SignedCommand::sign(&operator_key, &order).unwrap())).unwrap();
+    assert!(!outcome.ok);
+    assert_eq!(outcome.output, "no commands without tls");
+}
@@ -1,4 +1,4 @@
-use failure::{self, Error};
+use failure::Error;
 use tokio_core::reactor;
 use tokio_core::reactor::Timeout;
 use tokio_core::net::TcpStream;
@@ -14,19 +14,25 @@ use nix::sys::socket::{setsockopt, sockopt};
 use std;
 use libc;
 
-mod copy;
+mod splice;
 
-const LIFELINE1_SERVERS : [&'static str;4] = [
-    "lifeline.hy5.berlin",
-    "lifeline.exys.org",
-    "lifeline.captif.io",
-    "lifeline.superscale.io"
-];
+use lifeline::audit::{self, Session};
+use lifeline::command::unix_now;
+use lifeline::dns::{resolve, SERVERS};
+use lifeline::transport::HalfClose;
+
+/// A shell may sit there for a long time, the bearer has tcp keepalive to
+/// notice a dead server.
+const IDLE_SECS: u64 = 2 * 3600;
+const DEADLINE_SECS: u64 = 24 * 3600;
+
+fn ssh_addr() -> SocketAddr {
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127,0,0,1)), 22)
+}
 
 fn local(handle: reactor::Handle) -> Box<Future<Item=(Box<AsyncRead>, Box<AsyncWrite>), Error=std::io::Error>> {
     let timeout = Timeout::new(Duration::from_millis(1000), &handle).unwrap();
-    let addr    = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127,0,0,1)), 22);
-    let tcp     = TcpStream::connect(&addr, &handle);
+    let tcp     = TcpStream::connect(&ssh_addr(), &handle);
 
     let tcp = tcp.select2(timeout).then(|res| match res {
         Ok(Either::A((got, _timeout))) => Ok(got),
@@ -42,7 +48,7 @@ fn local(handle: reactor::Handle) -> Box<Future<Item=(Box<AsyncRead>, Box<AsyncW
 
     Box::new(tcp.and_then(move |stream| {
         info!("local ssh connected");
-        let (local_r, local_w) = stream.split();
+        let (local_r, local_w) = HalfClose(stream).split();
         future::ok((Box::new(local_r) as Box<AsyncRead>,
                     Box::new(local_w) as Box<AsyncWrite>))
     }))
@@ -74,7 +80,7 @@ fn remote(identity: &str, hostname : &str, ip: std::net::IpAddr) -> Result<(), E
     let handle  = core.handle();
     let handle2 = handle.clone();
     let handle3 = handle.clone();
-    let handle4 = handle.clone();
+    let server  = hostname.to_string();
 
     let timeout = Timeout::new(Duration::from_millis(1000), &handle)?;
     let tcp     = TcpStream::connect(&addr, &handle);
@@ -120,19 +126,44 @@ fn remote(identity: &str, hostname : &str, ip: std::net::IpAddr) -> Result<(), E
                     Err(Either::A((get_error, _timeout))) => Err(get_error),
                     Err(Either::B((timeout_error, _get))) => Err(From::from(timeout_error)),
                 })
-                .and_then(|(socket, _b, _size)| {
+                .and_then(move |(socket, _b, _size)| {
                     // we're ignoring the content. this is ok because lifeline v1 is dead code
                     // anyway. the header is fixed size and fits in a single package.
-                    info!("icoming control connection");
-
-                    let (remote_r, remote_w) = socket.split();
-                    local(handle2).and_then(|(local_r, local_w)|{
-                        let copy1 = copy::copy_with_deadline(remote_r, local_w, handle3, Duration::from_secs(60));
-                        let copy2 = copy::copy_with_deadline(local_r, remote_w, handle4, Duration::from_secs(60));
-                        copy1.select2(copy2).then(|_| {
-                            info!("stream ended");
-                            future::ok::<(), std::io::Error>(())
-                        })
+                    info!("incoming control connection from {} at {}", server, ip);
+                    let start = unix_now();
+
+                    let (remote_r, remote_w) = HalfClose(socket).split();
+                    local(handle2).and_then(move |(local_r, local_w)| {
+                        let deadlines = splice::Deadlines {
+                            idle: Some(Duration::from_secs(IDLE_SECS)),
+                            absolute: Some(Duration::from_secs(DEADLINE_SECS)),
+                        };
+                        splice::splice((remote_r, remote_w), (local_r, local_w), deadlines, &handle3)
+                    })
+                    .and_then(|splice| splice)
+                    .then(move |res| {
+                        let (bytes_in, bytes_out, reason) = match res {
+                            Ok(s) => (s.a_to_b, s.b_to_a, match s.end {
+                                splice::End::Error(kind) => format!("error: {:?}", kind),
+                                end => format!("{:?}", end).to_lowercase(),
+                            }),
+                            Err(e) => (0, 0, e.to_string()),
+                        };
+                        audit::record(&Session {
+                            protocol:  1,
+                            server:    server,
+                            server_ip: Some(ip),
+                            stream:    None,
+                            service:   "ssh".to_string(),
+                            target:    ssh_addr(),
+                            operator:  None,
+                            start:     start,
+                            end:       unix_now(),
+                            bytes_in:  bytes_in,
+                            bytes_out: bytes_out,
+                            reason:    reason,
+                        });
+                        future::ok::<(), std::io::Error>(())
                     })
                 })
                 .map_err(|e|warn!("{:?}",e)))
@@ -143,76 +174,10 @@ fn remote(identity: &str, hostname : &str, ip: std::net::IpAddr) -> Result<(), E
     Ok(())
 }
 
-fn resolve(name: &str) -> Result<(Vec<std::net::IpAddr>), Error> {
-    debug!("resolving {}", name);
-
-    use std::net::*;
-    use trust_dns_resolver::{
-        Resolver,
-        system_conf,
-    };
-    use trust_dns_resolver::config::*;
-
-    let mut config = ResolverConfig::new();
-    use std::error::Error;
-
-    // cloudflare
-    config.add_name_server(NameServerConfig{
-        tls_dns_name: None,
-        socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 53),
-        protocol: Protocol::Udp,
-    });
-    config.add_name_server(NameServerConfig{
-        tls_dns_name: None,
-        socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)), 53),
-        protocol: Protocol::Udp
-    });
-    config.add_name_server(NameServerConfig{
-        tls_dns_name: None,
-        socket_addr: SocketAddr::new(
-                         IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111,)),53),
-                         protocol: Protocol::Udp,
-    });
-
-    // google
-    config.add_name_server(NameServerConfig{
-        tls_dns_name: None,
-        socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53),
-        protocol: Protocol::Udp
-    });
-    config.add_name_server(NameServerConfig{
-        tls_dns_name: None,
-        socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 4, 4)), 53),
-        protocol: Protocol::Udp
-    });
-    config.add_name_server(NameServerConfig{
-        tls_dns_name: None,
-        socket_addr: SocketAddr::new(
-                         IpAddr::V6(Ipv6Addr::new(0x2001,0x4860,0x4860,0,0,0,0,0x8888,)),53),
-                         protocol: Protocol::Udp,
-    });
-
-    // from local dhcp as fallback, if all others are blocked
-    if let Ok((sysconf, _))  = system_conf::read_system_conf() {
-        for ns in sysconf.name_servers() {
-            config.add_name_server(ns.clone());
-        }
-    }
-
-    let resolver = Resolver::new(config, ResolverOpts::default())?;
-
-    let response = match resolver.lookup_ip(name) {
-        Ok(r) => r,
-        Err(e) => return Err(failure::Error::from(std::io::Error::new(std::io::ErrorKind::Other, e.description()))),
-    };
-
-    Ok(response.iter().collect())
-}
-
 pub fn main(identity: String) {
     loop {
         let mut ips : Vec<(&'static str, std::net::IpAddr)> = Vec::new();
-        for name in LIFELINE1_SERVERS.iter() {
+        for name in SERVERS.iter() {
             if let Ok(mut rips) = resolve(name) {
                 for ip in rips {
                     ips.push((name,ip));
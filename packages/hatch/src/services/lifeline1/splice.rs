@@ -0,0 +1,414 @@
+//! Copies between two streams in both directions, until both are closed.
+//!
+//! A direction ends when its reader hits EOF. The writer on the other side is
+//! shut down then, so the peer sees the end of the data, while the opposite
+//! direction keeps going. That is what ssh does when a command finished
+//! reading its input.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const BUF_SIZE: usize = 8192;
+
+/// Why a splice ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum End {
+    /// Both directions were closed.
+    Closed,
+    /// Nothing moved in either direction for the idle time.
+    Idle,
+    /// The absolute deadline passed.
+    Deadline,
+    /// Reading or writing failed in one of the directions. The counts are
+    /// what got through before.
+    Error(io::ErrorKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spliced {
+    /// Bytes copied from `a` to `b`.
+    pub a_to_b: u64,
+    /// Bytes copied from `b` to `a`.
+    pub b_to_a: u64,
+    pub end: End,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deadlines {
+    /// The splice ends once nothing moved for this long.
+    pub idle: Option<Duration>,
+    /// The splice ends this long after it started, busy or not.
+    pub absolute: Option<Duration>,
+}
+
+struct Direction<R, W> {
+    reader: R,
+    writer: W,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+    read_done: bool,
+    done: bool,
+}
+
+impl<R, W> Direction<R, W>
+    where R: AsyncRead,
+          W: AsyncWrite,
+{
+    fn new(reader: R, writer: W) -> Direction<R, W> {
+        Direction {
+            reader: reader,
+            writer: writer,
+            buf: Box::new([0; BUF_SIZE]),
+            pos: 0,
+            cap: 0,
+            amt: 0,
+            read_done: false,
+            done: false,
+        }
+    }
+
+    /// Copies until the reader or the writer would block. Ready once the
+    /// reader hit EOF, everything was written and the writer is shut down.
+    /// `progress` is set if anything moved.
+    fn poll(&mut self, progress: &mut bool) -> Poll<(), io::Error> {
+        if self.done {
+            return Ok(Async::Ready(()));
+        }
+
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                let n = try_nb!(self.reader.read(&mut self.buf));
+                *progress = true;
+
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+
+            while self.pos < self.cap {
+                let i = try_nb!(self.writer.write(&self.buf[self.pos..self.cap]));
+                if i == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero byte into writer"));
+                }
+                *progress = true;
+                self.pos += i;
+                self.amt += i as u64;
+            }
+
+            if self.read_done {
+                try_nb!(self.writer.flush());
+                match self.writer.shutdown()? {
+                    Async::Ready(()) => {
+                        self.done = true;
+                        return Ok(Async::Ready(()));
+                    }
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            }
+        }
+    }
+}
+
+/// Created by `splice`, resolves once both directions are closed or a
+/// deadline passed. Dropping it closes all four halves.
+pub struct Splice<AR, AW, BR, BW> {
+    a_to_b: Direction<AR, BW>,
+    b_to_a: Direction<BR, AW>,
+    idle: Option<(Timeout, Duration)>,
+    absolute: Option<Timeout>,
+}
+
+/// Copies from `a` to `b` and from `b` to `a`, each given as its read and
+/// write half.
+pub fn splice<AR, AW, BR, BW>(a: (AR, AW), b: (BR, BW), deadlines: Deadlines, handle: &Handle)
+    -> io::Result<Splice<AR, AW, BR, BW>>
+    where AR: AsyncRead,
+          AW: AsyncWrite,
+          BR: AsyncRead,
+          BW: AsyncWrite,
+{
+    let idle = match deadlines.idle {
+        Some(d) => Some((Timeout::new(d, handle)?, d)),
+        None => None,
+    };
+    let absolute = match deadlines.absolute {
+        Some(d) => Some(Timeout::new(d, handle)?),
+        None => None,
+    };
+
+    Ok(Splice {
+        a_to_b: Direction::new(a.0, b.1),
+        b_to_a: Direction::new(b.0, a.1),
+        idle: idle,
+        absolute: absolute,
+    })
+}
+
+impl<AR, AW, BR, BW> Splice<AR, AW, BR, BW> {
+    fn spliced(&self, end: End) -> Spliced {
+        Spliced {
+            a_to_b: self.a_to_b.amt,
+            b_to_a: self.b_to_a.amt,
+            end: end,
+        }
+    }
+}
+
+impl<AR, AW, BR, BW> Future for Splice<AR, AW, BR, BW>
+    where AR: AsyncRead,
+          AW: AsyncWrite,
+          BR: AsyncRead,
+          BW: AsyncWrite,
+{
+    type Item = Spliced;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Spliced, io::Error> {
+        if let Some(ref mut absolute) = self.absolute {
+            if absolute.poll()?.is_ready() {
+                return Ok(Async::Ready(self.spliced(End::Deadline)));
+            }
+        }
+
+        let mut progress = false;
+        let a_to_b = match self.a_to_b.poll(&mut progress) {
+            Ok(a_to_b) => a_to_b,
+            Err(e) => return Ok(Async::Ready(self.spliced(End::Error(e.kind())))),
+        };
+        let b_to_a = match self.b_to_a.poll(&mut progress) {
+            Ok(b_to_a) => b_to_a,
+            Err(e) => return Ok(Async::Ready(self.spliced(End::Error(e.kind())))),
+        };
+        if a_to_b.is_ready() && b_to_a.is_ready() {
+            return Ok(Async::Ready(self.spliced(End::Closed)));
+        }
+
+        if let Some((ref mut timeout, idle)) = self.idle {
+            if progress {
+                timeout.reset(Instant::now() + idle);
+            }
+            if timeout.poll()?.is_ready() {
+                return Ok(Async::Ready(self.spliced(End::Idle)));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::rc::Rc;
+    use futures::sync::oneshot;
+    use futures::task::{self, Task};
+    use tokio_core::reactor::Core;
+    use tokio_io::io::read_to_end;
+
+    #[derive(Default)]
+    struct Buffer {
+        data: VecDeque<u8>,
+        closed: bool,
+        reader: Option<Task>,
+    }
+
+    struct PipeReader(Rc<RefCell<Buffer>>);
+    struct PipeWriter(Rc<RefCell<Buffer>>);
+
+    /// An in-memory stream in one direction, written without limit.
+    fn pipe() -> (PipeWriter, PipeReader) {
+        let buffer = Rc::new(RefCell::new(Buffer::default()));
+        (PipeWriter(buffer.clone()), PipeReader(buffer))
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut b = self.0.borrow_mut();
+            if b.data.is_empty() {
+                if b.closed {
+                    return Ok(0);
+                }
+                b.reader = Some(task::current());
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            let n = buf.len().min(b.data.len());
+            for (dst, src) in buf.iter_mut().zip(b.data.drain(..n)) {
+                *dst = src;
+            }
+            Ok(n)
+        }
+    }
+
+    impl AsyncRead for PipeReader {}
+
+    impl PipeWriter {
+        fn wake(&self) {
+            if let Some(task) = self.0.borrow_mut().reader.take() {
+                task.notify();
+            }
+        }
+    }
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0.borrow().closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.0.borrow_mut().data.extend(buf);
+            self.wake();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for PipeWriter {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            self.0.borrow_mut().closed = true;
+            self.wake();
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Both ends of a splice: what `a` sends and receives, and the same for `b`.
+    struct Ends {
+        a_tx: PipeWriter,
+        a_rx: PipeReader,
+        b_tx: PipeWriter,
+        b_rx: PipeReader,
+    }
+
+    fn spliced(core: &Core, deadlines: Deadlines)
+        -> (Ends, Splice<PipeReader, PipeWriter, PipeReader, PipeWriter>)
+    {
+        let (a_tx, a_r) = pipe();
+        let (a_w, a_rx) = pipe();
+        let (b_tx, b_r) = pipe();
+        let (b_w, b_rx) = pipe();
+
+        let splice = splice((a_r, a_w), (b_r, b_w), deadlines, &core.handle()).unwrap();
+        (Ends { a_tx: a_tx, a_rx: a_rx, b_tx: b_tx, b_rx: b_rx }, splice)
+    }
+
+    #[test]
+    fn test_both_directions() {
+        let mut core = Core::new().unwrap();
+        let (mut ends, splice) = spliced(&core, Deadlines::default());
+
+        ends.a_tx.write_all(b"hello").unwrap();
+        ends.a_tx.shutdown().unwrap();
+        ends.b_tx.write_all(b"bye").unwrap();
+        ends.b_tx.shutdown().unwrap();
+
+        let (spliced, (_, at_b), (_, at_a)) = core.run(splice.join3(read_to_end(ends.b_rx, Vec::new()),
+                                                                  read_to_end(ends.a_rx, Vec::new()))).unwrap();
+
+        assert_eq!(at_b, b"hello");
+        assert_eq!(at_a, b"bye");
+        assert_eq!(spliced, Spliced { a_to_b: 5, b_to_a: 3, end: End::Closed });
+    }
+
+    #[test]
+    fn test_half_close() {
+        let mut core = Core::new().unwrap();
+        let (mut ends, splice) = spliced(&core, Deadlines::default());
+
+        let (done_tx, done_rx) = oneshot::channel();
+        core.handle().spawn(splice.then(|res| done_tx.send(res).map_err(|_| ())));
+
+        // a is done sending, b sees the end of the data
+        ends.a_tx.write_all(b"input").unwrap();
+        ends.a_tx.shutdown().unwrap();
+        let (_, at_b) = core.run(read_to_end(ends.b_rx, Vec::new())).unwrap();
+        assert_eq!(at_b, b"input");
+
+        // and can still answer
+        ends.b_tx.write_all(b"output").unwrap();
+        ends.b_tx.shutdown().unwrap();
+        let (_, at_a) = core.run(read_to_end(ends.a_rx, Vec::new())).unwrap();
+        assert_eq!(at_a, b"output");
+
+        let spliced = core.run(done_rx).unwrap().unwrap();
+        assert_eq!(spliced, Spliced { a_to_b: 5, b_to_a: 6, end: End::Closed });
+    }
+
+    /// Takes `room` bytes, then fails as if the peer reset the connection.
+    struct Failing {
+        room: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let n = buf.len().min(self.room);
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Failing {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let mut core = Core::new().unwrap();
+        let (mut a_tx, a_r) = pipe();
+        let (a_w, _a_rx) = pipe();
+        let (_b_tx, b_r) = pipe();
+
+        let splice = splice((a_r, a_w), (b_r, Failing { room: 5 }), Deadlines::default(), &core.handle()).unwrap();
+        a_tx.write_all(b"hello world").unwrap();
+
+        // what made it to b before it failed is still counted
+        let spliced = core.run(splice).unwrap();
+        assert_eq!(spliced, Spliced { a_to_b: 5, b_to_a: 0, end: End::Error(io::ErrorKind::ConnectionReset) });
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut core = Core::new().unwrap();
+        let deadlines = Deadlines { idle: Some(Duration::from_millis(50)), absolute: None };
+        let (mut ends, splice) = spliced(&core, deadlines);
+
+        // one direction closed, the other one silent
+        ends.a_tx.write_all(b"x").unwrap();
+        ends.a_tx.shutdown().unwrap();
+
+        let spliced = core.run(splice).unwrap();
+        assert_eq!(spliced, Spliced { a_to_b: 1, b_to_a: 0, end: End::Idle });
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut core = Core::new().unwrap();
+        let deadlines = Deadlines { idle: Some(Duration::from_secs(60)), absolute: Some(Duration::from_millis(50)) };
+        let (_ends, splice) = spliced(&core, deadlines);
+
+        let spliced = core.run(splice).unwrap();
+        assert_eq!(spliced.end, End::Deadline);
+    }
+}
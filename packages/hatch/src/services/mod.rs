@@ -1 +1,15 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod attestation;
+pub mod system;
+pub mod watchdog;
+#[cfg(feature = "lifeline")]
+pub mod commands;
+#[cfg(feature = "lifeline")]
 pub mod lifeline1;
+#[cfg(feature = "lifeline")]
+pub mod telemetry;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -1,4 +1,5 @@
 use errors::*;
+use time_trust::Trust;
 
 use std::fs::File;
 use std::io::Read;
@@ -55,6 +56,14 @@ impl<'rule> Rule<'rule> {
         )
     }
 
+    /// Returns the same rule, stamped with the current time.
+    fn restamped(&self) -> Rule<'rule> {
+        Rule {
+            mac_source: self.mac_source,
+            timestamp: Utc::now().timestamp(),
+        }
+    }
+
     fn is_expired(&self, valid_time: Duration) -> bool {
         self.timestamp + valid_time.num_seconds() < Utc::now().timestamp()
     }
@@ -80,17 +89,37 @@ fn read_valid_time() -> Duration {
 /// # Arguments
 ///
 /// `valid_time` - The time it takes until an access is expired.
+///
+/// While the clock is not trusted, no access is expired. Accesses that were
+/// authorized before the clock was trusted get the current time as timestamp,
+/// as soon as the clock is trusted.
 pub fn check_for_expired(valid_time: Option<Duration>) -> Result<()> {
+    check_for_expired_with(valid_time, Trust::system())
+}
+
+/// `check_for_expired` with the trust in the clock decided by the caller.
+pub fn check_for_expired_with(valid_time: Option<Duration>, trust: Trust) -> Result<()> {
+    if !trust.is_trusted() {
+        return Ok(());
+    }
+
     let valid_time = valid_time.unwrap_or_else(read_valid_time);
 
-    let ipt = iptables::new(false).unwrap();
+    // runs next to lifeline in hatch supervise, a panic takes both down
+    let ipt = iptables::new(false)
+        .map_err(|e| format!("Could not run iptables: {}", e))?;
 
     let rules = ipt.list(IPT_TABLE, IPT_CHAIN)
         .chain_err(|| "Could not list the chain rules!")?;
 
     for rule in rules {
         if let Some(rule) = Rule::parse(&rule) {
-            if rule.is_expired(valid_time) {
+            if trust.is_before(rule.timestamp) {
+                ipt.append(IPT_TABLE, IPT_CHAIN, &rule.restamped().to_string())
+                    .chain_err(|| format!("Error restamping rule: {}", rule.to_string()))?;
+                ipt.delete(IPT_TABLE, IPT_CHAIN, &rule.to_string())
+                    .chain_err(|| format!("Error deleting rule: {}", rule.to_string()))?;
+            } else if rule.is_expired(valid_time) {
                 ipt.delete(IPT_TABLE, IPT_CHAIN, &rule.to_string())
                     .chain_err(|| format!("Error deleting rule: {}", rule.to_string()))?;
             }
@@ -100,6 +129,41 @@ pub fn check_for_expired(valid_time: Option<Duration>) -> Result<()> {
     Ok(())
 }
 
+/// Returns how many clients are currently authorized on the public wifi.
+pub fn authorized_clients() -> Result<usize> {
+    // runs in lifeline too, which must not go down with iptables
+    let ipt = iptables::new(false)
+        .map_err(|e| format!("Could not run iptables: {}", e))?;
+
+    let rules = ipt.list(IPT_TABLE, IPT_CHAIN)
+        .chain_err(|| "Could not list the chain rules!")?;
+
+    Ok(count_authorized(&rules))
+}
+
+/// Removes the access of every authorized client, they have to accept the
+/// terms again. Returns how many were removed.
+pub fn revoke_all() -> Result<usize> {
+    let ipt = iptables::new(false)
+        .map_err(|e| format!("Could not run iptables: {}", e))?;
+
+    let rules = ipt.list(IPT_TABLE, IPT_CHAIN)
+        .chain_err(|| "Could not list the chain rules!")?;
+
+    let mut revoked = 0;
+    for rule in rules.iter().filter_map(|rule| Rule::parse(rule)) {
+        ipt.delete(IPT_TABLE, IPT_CHAIN, &rule.to_string())
+            .chain_err(|| format!("Error deleting rule: {}", rule.to_string()))?;
+        revoked += 1;
+    }
+
+    Ok(revoked)
+}
+
+fn count_authorized(rules: &[String]) -> usize {
+    rules.iter().filter(|rule| Rule::parse(rule).is_some()).count()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,6 +220,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_count_authorized() {
+        let rules = vec![
+            "-N prerouting_public_rule".to_string(),
+            "-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AD \
+             -m comment --comment \"timestamp=233445\" -j ACCEPT".to_string(),
+            "-A prerouting_public_rule -m mac --mac-source DE:AD:BE:EF:DE:AE \
+             -m comment --comment \"timestamp=233446\" -j ACCEPT".to_string(),
+        ];
+
+        assert_eq!(count_authorized(&rules), 2);
+    }
+
     #[test]
     fn test_rule_expired() {
         let duration = Duration::hours(1);
@@ -0,0 +1,203 @@
+//FIXME: Use the ubus rust implementation!
+
+use errors::*;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::Command;
+
+use serde_json;
+
+/// The 802.11 reason code sent to clients when the public wifi closes
+/// ("deauthenticated because sending station is leaving").
+const DEAUTH_REASON_LEAVING: u32 = 3;
+
+/// netifd writes the config of all bss of a radio to `hostapd-<phy>.conf` in
+/// here.
+const HOSTAPD_CONFIG_DIR: &str = "/var/run";
+
+fn ubus_call(object: &str, method: &str, args: &serde_json::Value) -> Result<String> {
+    let output = Command::new("ubus")
+        .args(&["call", object, method, &args.to_string()])
+        .output()
+        .chain_err(|| format!("error running ubus call {} {}", object, method))?;
+
+    if !output.status.success() {
+        bail!("ubus call {} {} failed", object, method);
+    }
+
+    String::from_utf8(output.stdout).chain_err(|| "error parsing ubus output as utf8 string")
+}
+
+fn parse_clients(output: &str) -> Vec<String> {
+    let json: serde_json::Result<HashMap<String, serde_json::Value>> =
+        serde_json::from_str(output);
+
+    match json {
+        Ok(json) => json.get("clients")
+            .and_then(|c| c.as_object())
+            .map(|c| c.keys().cloned().collect())
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Returns the mac addresses of all clients associated with the given interface.
+pub fn get_clients(ifname: &str) -> Result<Vec<String>> {
+    let output = ubus_call(
+        &format!("hostapd.{}", ifname),
+        "get_clients",
+        &json!({}),
+    )?;
+
+    Ok(parse_clients(&output))
+}
+
+/// Deauthenticates all clients of the given interface, so they know the
+/// network is gone instead of waiting for a beacon loss.
+pub fn deauth_clients(ifname: &str) -> Result<()> {
+    for mac in get_clients(ifname)? {
+        ubus_call(
+            &format!("hostapd.{}", ifname),
+            "del_client",
+            &json!({
+                "addr": mac,
+                "reason": DEAUTH_REASON_LEAVING,
+                "deauth": true,
+            }),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Removes a single bss from hostapd without touching the other interfaces
+/// on the same radio.
+pub fn disable_interface(ifname: &str) -> Result<()> {
+    ubus_call("hostapd", "config_remove", &json!({ "iface": ifname })).map(|_| ())
+}
+
+/// Adds a bss removed with `disable_interface` back to hostapd, with the
+/// config netifd wrote for its radio. netifd still thinks it is up, asking it
+/// would not help.
+pub fn enable_interface(ifname: &str) -> Result<()> {
+    if Path::new("/sys/class/net").join(ifname).exists() {
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(HOSTAPD_CONFIG_DIR)
+        .chain_err(|| format!("error reading {}", HOSTAPD_CONFIG_DIR))?;
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("hostapd-phy") || !name.ends_with(".conf") {
+            continue;
+        }
+        let phy = &name["hostapd-".len()..name.len() - ".conf".len()];
+
+        let mut radio_config = String::new();
+        File::open(entry.path())
+            .and_then(|mut f| f.read_to_string(&mut radio_config))
+            .chain_err(|| format!("error reading {}", name))?;
+
+        if let Some(config) = bss_config(&radio_config, ifname) {
+            let path = format!("{}/sentry-{}.conf", HOSTAPD_CONFIG_DIR, ifname);
+            File::create(&path)
+                .and_then(|mut f| f.write_all(config.as_bytes()))
+                .chain_err(|| format!("error writing {}", path))?;
+
+            return ubus_call("hostapd", "config_add", &json!({ "iface": phy, "config": path })).map(|_| ());
+        }
+    }
+
+    bail!("no hostapd config has {}", ifname)
+}
+
+/// The lines of one bss in the config of a whole radio, as a config of its
+/// own.
+fn bss_config(radio_config: &str, ifname: &str) -> Option<String> {
+    let first = format!("interface={}", ifname);
+    let other = format!("bss={}", ifname);
+    let mut lines = radio_config.lines()
+        .map(|line| line.trim())
+        .skip_while(|line| *line != first && *line != other);
+    lines.next()?;
+
+    let mut config = format!("interface={}\n", ifname);
+    for line in lines.take_while(|line| !line.starts_with("bss=")) {
+        config.push_str(line);
+        config.push('\n');
+    }
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UBUS_GET_CLIENTS_OUTPUT: &'static str = r#"
+    {
+        "freq": 2437,
+        "clients": {
+            "de:ad:be:ef:00:11": {
+                "auth": true,
+                "assoc": true,
+                "authorized": true
+            },
+            "de:ad:be:ef:00:22": {
+                "auth": true,
+                "assoc": true,
+                "authorized": true
+            }
+        }
+    }"#;
+
+    #[test]
+    fn test_parse_clients() {
+        let mut clients = parse_clients(UBUS_GET_CLIENTS_OUTPUT);
+        clients.sort();
+
+        assert_eq!(
+            clients,
+            vec![
+                String::from("de:ad:be:ef:00:11"),
+                String::from("de:ad:be:ef:00:22"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_clients_invalid_output() {
+        assert!(parse_clients("Command failed: Not found").is_empty());
+    }
+
+    const RADIO_CONFIG: &'static str = "\
+driver=nl80211
+hw_mode=g
+channel=1
+interface=wlan1
+ctrl_interface=/var/run/hostapd
+ssid=Office
+bss=w-pub-g
+ctrl_interface=/var/run/hostapd
+ssid=Free Wifi
+bssid=02:00:00:00:00:01
+bss=wlan1-2
+ssid=Other
+";
+
+    #[test]
+    fn test_bss_config() {
+        assert_eq!(
+            bss_config(RADIO_CONFIG, "w-pub-g").unwrap(),
+            "interface=w-pub-g\nctrl_interface=/var/run/hostapd\nssid=Free Wifi\nbssid=02:00:00:00:00:01\n"
+        );
+        assert_eq!(
+            bss_config(RADIO_CONFIG, "wlan1").unwrap(),
+            "interface=wlan1\nctrl_interface=/var/run/hostapd\nssid=Office\n"
+        );
+        assert!(bss_config(RADIO_CONFIG, "w-pub-a").is_none());
+    }
+}
@@ -1,20 +1,35 @@
+#[cfg(feature = "portal")]
 extern crate bytes;
 extern crate chrono;
+#[cfg(feature = "time-control")]
 extern crate chrono_tz;
+#[cfg(feature = "portal")]
 #[macro_use]
 extern crate derive_new;
 #[macro_use]
 extern crate error_chain;
+#[cfg(feature = "portal")]
 extern crate futures;
+#[cfg(feature = "portal")]
 extern crate hyper;
+#[cfg(any(feature = "portal", feature = "access-control", feature = "time-control"))]
 extern crate iptables;
+extern crate libc;
+#[cfg(feature = "portal")]
 extern crate rand;
+#[cfg(feature = "access-control")]
 extern crate regex;
+#[cfg(any(feature = "portal", feature = "time-control"))]
+#[cfg_attr(feature = "time-control", macro_use)]
 extern crate serde_json;
+#[cfg(feature = "portal")]
 extern crate tokio_core;
+#[cfg(feature = "time-control")]
 extern crate serde;
+#[cfg(feature = "time-control")]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "access-control")]
 #[macro_use]
 extern crate lazy_static;
 
@@ -22,12 +37,37 @@ extern crate lazy_static;
 extern crate tokio_proto;
 
 pub mod errors;
+#[cfg(feature = "portal")]
 mod sentry;
+#[cfg(feature = "time-control")]
 mod time_control;
+#[cfg(feature = "access-control")]
 mod access_control;
+#[cfg(feature = "time-control")]
+mod hostapd;
+mod time_trust;
 
+/// The port the portal listens on. time_control redirects clients to it
+/// while it announces a shutdown.
+#[cfg(any(feature = "portal", feature = "time-control"))]
+const DEFAULT_LISTEN_PORT: u16 = 8444;
+
+#[cfg(feature = "portal")]
 pub use sentry::sentry_main;
-pub use access_control::check_for_expired;
+#[cfg(feature = "access-control")]
+pub use access_control::{authorized_clients, check_for_expired, check_for_expired_with, revoke_all};
+#[cfg(feature = "time-control")]
 pub use time_control::check_public_wifi;
+#[cfg(feature = "time-control")]
 pub use time_control::TimeControl;
+#[cfg(feature = "time-control")]
 pub use time_control::PUBLIC_WIFI_TIME_CONTROL_PATH;
+#[cfg(feature = "time-control")]
+pub use time_control::pending_shutdown;
+#[cfg(feature = "time-control")]
+pub use time_control::is_pub_wifi_enabled;
+#[cfg(feature = "time-control")]
+pub use time_control::{override_public_wifi, public_wifi_override, PUBLIC_WIFI_OVERRIDE_PATH};
+pub use time_trust::{is_time_trusted, time_trust, TimeTrust};
+#[cfg(feature = "access-control")]
+pub use time_trust::Trust;
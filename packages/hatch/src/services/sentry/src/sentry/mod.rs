@@ -6,6 +6,7 @@ mod service;
 mod ubus;
 
 use errors::*;
+use DEFAULT_LISTEN_PORT;
 use sentry::sentry::Sentry;
 use sentry::service::Service;
 
@@ -25,7 +26,6 @@ use rand::{self, Rng};
 
 const DEFAULT_PATH_TO_REDIRECT_URL: &'static str = "/etc/sentry.url";
 const DEFAULT_REDIRECT_URL: &'static str = "http://portal.captif.io/?origin=";
-const DEFAULT_LISTEN_PORT: u16 = 8444;
 const SECRET_LENGTH: usize = 16;
 
 fn get_redirect_url(path_opt: Option<&str>) -> String {
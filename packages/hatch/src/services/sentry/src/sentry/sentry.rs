@@ -4,7 +4,9 @@ use sentry::portal;
 use sentry::ip;
 use sentry::proxy;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use tokio_core::reactor::Handle;
 
@@ -15,16 +17,26 @@ use chrono::Local;
 
 use iptables;
 
+/// How long listed accept rules are used before iptables is asked again.
+const AUTHORIZED_CACHE_SECS: u64 = 60;
+
 #[derive(Clone, new, Debug)]
 pub struct Sentry {
     secret: String,
     pylon_name: String,
     evt_loop_handle: Handle,
+    /// Clients that already got the closing notice during the current shutdown.
+    #[new(default)]
+    notified: Arc<Mutex<HashSet<String>>>,
+    /// The accept rules, uppercased, and when they were listed.
+    #[new(default)]
+    authorized: Arc<Mutex<Option<(Instant, Vec<String>)>>>,
 }
 
 impl Sentry {
     fn authorize_client_in_iptables(&self, mac: &str) -> Result<()> {
-        let ipt = iptables::new(false).unwrap();
+        let ipt = iptables::new(false)
+            .map_err(|e| format!("Could not run iptables: {}", e))?;
 
         ipt.append(
             "nat",
@@ -46,6 +58,8 @@ impl Sentry {
         };
 
         if self.authorize_client_in_iptables(&mac).is_ok() {
+            *self.authorized.lock().unwrap() = None;
+
             let time = format!("{}", Local::now().timestamp());
             let mut map: HashMap<&str, &str> = HashMap::new();
             map.insert("ip", ip);
@@ -97,6 +111,43 @@ impl Sentry {
         )
     }
 
+    /// Checks if the client already has an accept rule in iptables. Asked for
+    /// every request during a pending shutdown, so the rules are only listed
+    /// every `AUTHORIZED_CACHE_SECS`.
+    pub fn is_client_authorized(&self, ip: &str) -> Result<bool> {
+        let mac = match ip::ip_to_mac(ip) {
+            Some(mac) => mac.to_uppercase(),
+            None => return Ok(false),
+        };
+
+        let mut authorized = self.authorized.lock().unwrap();
+        let fresh = match *authorized {
+            Some((ref listed, _)) => listed.elapsed() < Duration::from_secs(AUTHORIZED_CACHE_SECS),
+            None => false,
+        };
+
+        if !fresh {
+            let ipt = iptables::new(false)
+                .map_err(|e| format!("Could not run iptables: {}", e))?;
+            let rules = ipt.list("nat", "prerouting_public_rule")
+                .chain_err(|| "Could not list the chain rules!")?;
+            *authorized = Some((Instant::now(), rules.iter().map(|r| r.to_uppercase()).collect()));
+        }
+
+        Ok(authorized.as_ref().map_or(false, |&(_, ref rules)| rules.iter().any(|r| r.contains(&mac))))
+    }
+
+    /// Remembers that the client saw the closing notice. Returns true, if the
+    /// client did not see it before.
+    pub fn notify_closing(&self, ip: &str) -> bool {
+        self.notified.lock().unwrap().insert(ip.to_owned())
+    }
+
+    /// Forgets all notified clients, once no shutdown is pending anymore.
+    pub fn reset_closing(&self) {
+        self.notified.lock().unwrap().clear();
+    }
+
     pub fn contains_secret(&self, query: &str) -> bool {
         query.contains("tos_accepted=true")
     }
@@ -1,16 +1,44 @@
 use sentry::Sentry;
 use sentry::proxy;
+#[cfg(feature = "time-control")]
+use time_control::pending_shutdown;
 
 use std::net::SocketAddr;
 use std::str::FromStr;
 
 use hyper;
 use hyper::server::{self, Request, Response};
-use hyper::header::{Connection, Host, Location, Referer};
+use hyper::header::{Connection, ContentType, Host, Location, Referer};
 
 use futures::future::{Either, Future};
 use futures;
 
+use chrono::offset::Utc;
+
+/// Without time control the public wifi is never shut down.
+#[cfg(not(feature = "time-control"))]
+fn pending_shutdown() -> Option<i64> {
+    None
+}
+
+const CLOSING_PAGE: &'static str = include_str!("../../res/closing.html");
+
+/// Escapes text for html, in elements and quoted attributes alike.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[derive(Clone, new, Debug)]
 pub struct Service {
     redirect_url: String,
@@ -48,6 +76,58 @@ impl Service {
         }
     }
 
+    /// While a wifi shutdown is pending, authorized clients are captured again.
+    /// The first request of each client is answered with the closing notice,
+    /// all further requests are proxied to their destination.
+    fn handle_closing(&self, req: &Request) -> Option<proxy::Result> {
+        let deadline = match pending_shutdown() {
+            Some(deadline) => deadline,
+            None => {
+                self.sentry.reset_closing();
+                return None;
+            }
+        };
+
+        let host = match req.headers().get::<Host>() {
+            Some(host) => host,
+            None => return None,
+        };
+
+        let ip = match req.remote_addr() {
+            Some(address) => self.remote_addr_to_ip(&address),
+            None => return None,
+        };
+
+        match self.sentry.is_client_authorized(&ip) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                println!("Error checking whether {} is authorized: {}", ip, e);
+                return None;
+            }
+        }
+
+        let url = format!("http://{}{}", host, req.uri().as_ref());
+
+        if !self.sentry.notify_closing(&ip) {
+            let uri = hyper::Uri::from_str(&url).expect("Error at building the closing url!");
+            return Some(self.sentry.proxy_request(&uri, req.method(), req.headers()));
+        }
+
+        let minutes = ((deadline - Utc::now().timestamp()) / 60).max(0) + 1;
+        // the host and uri are the client's, they must not make it into the page
+        let body = CLOSING_PAGE
+            .replace("{url}", &escape_html(&url))
+            .replace("{minutes}", &minutes.to_string());
+
+        let resp = Response::new()
+            .with_header(ContentType::html())
+            .with_header(Connection::close())
+            .with_body(body);
+
+        Some(Box::new(futures::future::ok(resp)))
+    }
+
     /// Fetches the portal if the host header is equal to the `redirect_host`
     fn handle_portal(&self, req: &Request) -> Option<proxy::Result> {
         if let Some(host) = req.headers().get::<Host>() {
@@ -132,7 +212,9 @@ impl server::Service for Service {
     fn call(&self, req: Request) -> Self::Future {
         self.handle_authorized(&req);
 
-        if let Some(resp) = self.handle_portal(&req) {
+        if let Some(resp) = self.handle_closing(&req) {
+            Either::B(resp)
+        } else if let Some(resp) = self.handle_portal(&req) {
             Either::B(resp)
         } else if let Some(resp) = self.handle_referer(&req) {
             Either::B(resp)
@@ -141,3 +223,17 @@ impl server::Service for Service {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("http://example.com/?a=1&b=2"), "http://example.com/?a=1&amp;b=2");
+        assert_eq!(
+            escape_html(r#"http://x/"><script>alert('x')</script>"#),
+            "http://x/&quot;&gt;&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"
+        );
+    }
+}
@@ -1,16 +1,42 @@
 use errors::*;
+use hostapd;
+use time_trust;
+use DEFAULT_LISTEN_PORT;
 
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
 use std::process::Command;
 
-use chrono::{Datelike, TimeZone, Timelike, Weekday};
+use chrono::{DateTime, Datelike, Duration, Timelike, Weekday};
 use chrono::offset::Utc;
 use chrono_tz::Tz;
 
+use iptables;
+
 use serde_json;
 
 pub const PUBLIC_WIFI_RADIOS: &[&str] = &["a", "g"];
+pub const PUBLIC_WIFI_INTERFACES: &[&str] = &["w-pub-a", "w-pub-g"];
 pub const PUBLIC_WIFI_TIME_CONTROL_PATH: &str = "/etc/zealot.pub.tc";
+pub const PUBLIC_WIFI_SHUTDOWN_PATH: &str = "/tmp/zealot.pub.shutdown";
+/// "on" or "off", set by the operators. Replaces the schedule until it is
+/// removed or the device reboots.
+pub const PUBLIC_WIFI_OVERRIDE_PATH: &str = "/tmp/zealot.pub.override";
+
+const IPT_CHAIN: &str = "prerouting_public_rule";
+const IPT_TABLE: &str = "nat";
+const CAPTURE_COMMENT: &str = "sentry_closing";
+
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
 
 /// Stores information about the wifi up times.
 #[derive(Debug, Deserialize, Serialize)]
@@ -21,6 +47,9 @@ pub struct TimeControl {
     up_time: Vec<Vec<u8>>,
     /// The timezone of the given up times.
     timezone: String,
+    /// Minutes between announcing the shutdown to the connected clients and
+    /// actually turning off the public wifi.
+    grace_period: u32,
 }
 
 impl Default for TimeControl {
@@ -28,29 +57,187 @@ impl Default for TimeControl {
         TimeControl {
             up_time: vec![],
             timezone: "Europe/Berlin".to_string(),
+            grace_period: 10,
         }
     }
 }
 
 /// Checks if the current status of the public wifi corresponds to the configured
 /// up times.
-/// If the status does not match, the public wifi is activated/deactivated.
+///
+/// Switching the wifi on happens immediately. Switching it off is announced first:
+/// authorized clients are captured by sentry again, which shows them a notice until
+/// the grace period ends. Only then the public interfaces are shut down.
+///
+/// Nothing is changed as long as the clock is not trusted, the wifi stays in the
+/// state genesis configured it with.
 pub fn check_public_wifi() -> Result<()> {
+    if !time_trust::is_time_trusted() {
+        return Ok(());
+    }
+
     let wifi_status = is_pub_wifi_enabled().unwrap_or(false);
-    let req_wifi_status = get_current_requested_wifi_status().unwrap_or(true);
+    let req_wifi_status = public_wifi_override()
+        .unwrap_or_else(|| get_current_requested_wifi_status().unwrap_or(true));
+
+    if req_wifi_status {
+        if pending_shutdown().is_some() {
+            cancel_shutdown();
+        }
+
+        if !wifi_status {
+            enable_public_wifi();
+        }
+
+        return Ok(());
+    }
+
+    if !wifi_status {
+        return Ok(());
+    }
+
+    let now = Utc::now().timestamp();
 
-    if wifi_status != req_wifi_status {
-        for standard in PUBLIC_WIFI_RADIOS {
-            change_wifi_status(req_wifi_status, standard);
+    match pending_shutdown() {
+        None => {
+            let grace_period = read_time_control()
+                .map(|tc| tc.grace_period)
+                .unwrap_or_else(|_| TimeControl::default().grace_period);
+
+            if grace_period == 0 {
+                disable_public_wifi();
+            } else {
+                schedule_shutdown(now + i64::from(grace_period) * 60)?;
+            }
+        }
+        Some(deadline) if deadline <= now => {
+            disable_public_wifi();
+            cancel_shutdown();
         }
+        Some(_) => {}
+    }
+
+    Ok(())
+}
+
+/// Returns the unix timestamp at which the public wifi will be shut down, if a
+/// shutdown is currently announced.
+pub fn pending_shutdown() -> Option<i64> {
+    let mut content = String::new();
+
+    File::open(PUBLIC_WIFI_SHUTDOWN_PATH)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()
+        .and_then(|_| content.trim().parse::<i64>().ok())
+}
+
+/// Returns what the operators want the public wifi to be, if they overrode the
+/// schedule.
+pub fn public_wifi_override() -> Option<bool> {
+    let mut content = String::new();
+
+    File::open(PUBLIC_WIFI_OVERRIDE_PATH)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()
+        .and_then(|_| match content.trim() {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        })
+}
 
-        // activate the changes
-        let _ = Command::new("wifi").output();
+/// Switches the public wifi on or off right away, regardless of the schedule
+/// and of the clock. `None` hands the wifi back to the schedule, which is
+/// applied the next time it is checked.
+///
+/// Switching off does not announce the shutdown, the clients are
+/// deauthenticated immediately.
+pub fn override_public_wifi(enabled: Option<bool>) -> Result<()> {
+    match enabled {
+        Some(enabled) => {
+            let mut file = File::create(PUBLIC_WIFI_OVERRIDE_PATH)
+                .chain_err(|| "error creating the override file")?;
+            write!(file, "{}", if enabled { "on" } else { "off" })
+                .chain_err(|| "error writing the override file")?;
+
+            cancel_shutdown();
+            if enabled {
+                enable_public_wifi();
+            } else {
+                disable_public_wifi();
+            }
+        }
+        None => {
+            if Path::new(PUBLIC_WIFI_OVERRIDE_PATH).exists() {
+                fs::remove_file(PUBLIC_WIFI_OVERRIDE_PATH)
+                    .chain_err(|| "error removing the override file")?;
+            }
+        }
     }
 
     Ok(())
 }
 
+fn capture_rule() -> String {
+    format!(
+        "-p tcp --dport 80 -m comment --comment {} -j REDIRECT --to-ports {}",
+        CAPTURE_COMMENT,
+        DEFAULT_LISTEN_PORT
+    )
+}
+
+/// Announces the shutdown. The capture rule is inserted in front of the accepted
+/// clients, so their http traffic goes through sentry again.
+fn schedule_shutdown(deadline: i64) -> Result<()> {
+    // runs next to lifeline in hatch supervise, a panic takes both down
+    let ipt = iptables::new(false)
+        .map_err(|e| format!("Could not run iptables: {}", e))?;
+
+    if !ipt.exists(IPT_TABLE, IPT_CHAIN, &capture_rule())
+        .chain_err(|| "Could not list the chain rules!")?
+    {
+        ipt.insert(IPT_TABLE, IPT_CHAIN, &capture_rule(), 1)
+            .chain_err(|| "Error inserting the capture rule")?;
+    }
+
+    let mut file = File::create(PUBLIC_WIFI_SHUTDOWN_PATH)
+        .chain_err(|| "error creating the shutdown file")?;
+    write!(file, "{}", deadline).chain_err(|| "error writing the shutdown file")
+}
+
+fn cancel_shutdown() {
+    if let Ok(ipt) = iptables::new(false) {
+        let _ = ipt.delete(IPT_TABLE, IPT_CHAIN, &capture_rule());
+    }
+
+    let _ = fs::remove_file(PUBLIC_WIFI_SHUTDOWN_PATH);
+}
+
+/// Brings the public interfaces back through hostapd, the way
+/// `disable_public_wifi` took them down.
+fn enable_public_wifi() {
+    for standard in PUBLIC_WIFI_RADIOS {
+        change_wifi_status(true, standard);
+    }
+
+    for ifname in PUBLIC_WIFI_INTERFACES {
+        let _ = hostapd::enable_interface(ifname);
+    }
+}
+
+/// Shuts down only the public interfaces. Clients are deauthenticated first, so
+/// they roam away right away instead of waiting for a beacon loss.
+fn disable_public_wifi() {
+    for standard in PUBLIC_WIFI_RADIOS {
+        change_wifi_status(false, standard);
+    }
+
+    for ifname in PUBLIC_WIFI_INTERFACES {
+        let _ = hostapd::deauth_clients(ifname);
+        let _ = hostapd::disable_interface(ifname);
+    }
+}
+
 fn change_wifi_status(enable: bool, standard: &str) {
     let disabled = if enable { 0 } else { 1 };
     let _ = Command::new("uci")
@@ -61,6 +248,10 @@ fn change_wifi_status(enable: bool, standard: &str) {
         .output();
 }
 
+fn read_time_control() -> Result<TimeControl> {
+    TimeControl::load(PUBLIC_WIFI_TIME_CONTROL_PATH)
+}
+
 /// Checks, based on the time control, if the wifi should be on or off at the time
 /// this function is running.
 ///
@@ -69,30 +260,120 @@ fn change_wifi_status(enable: bool, standard: &str) {
 /// True => wifi on
 /// False => wifi off
 fn get_current_requested_wifi_status() -> Result<bool> {
-    let time_control =
-        File::open(PUBLIC_WIFI_TIME_CONTROL_PATH).chain_err(|| "error reading time control file")?;
-    let time_control: TimeControl =
-        serde_json::from_reader(time_control).chain_err(|| "error parsing time control file")?;
+    read_time_control()?.is_up_at(&Utc::now())
+}
+
+impl TimeControl {
+    /// Reads and validates a time control file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TimeControl> {
+        let path = path.as_ref();
+        let file = File::open(path).chain_err(|| format!("error reading {}", path.display()))?;
+        let time_control: TimeControl = serde_json::from_reader(file)
+            .chain_err(|| format!("error parsing {}", path.display()))?;
+
+        time_control.validate()?;
+        Ok(time_control)
+    }
+
+    /// Checks the values that serde can not check for us.
+    pub fn validate(&self) -> Result<()> {
+        self.tz()?;
+
+        if self.up_time.len() > WEEKDAYS.len() {
+            bail!(
+                "up_time has {} days, but a week only has {}",
+                self.up_time.len(),
+                WEEKDAYS.len()
+            );
+        }
 
-    let timezone: Tz = time_control.timezone.parse()?;
+        for (day, hours) in WEEKDAYS.iter().zip(&self.up_time) {
+            if let Some(hour) = hours.iter().find(|h| **h > 23) {
+                bail!("up_time for {:?}: hour {} is not between 0 and 23", day, hour);
+            }
+        }
 
-    let now = timezone.from_utc_datetime(&Utc::now().naive_utc());
+        Ok(())
+    }
 
-    let day_times = time_control
-        .up_time
-        .get(weekday_to_index(now.date().weekday()));
+    /// The timezone the up times are given in.
+    pub fn tz(&self) -> Result<Tz> {
+        self.timezone
+            .parse()
+            .map_err(|e: String| format!("invalid timezone \"{}\": {}", self.timezone, e).into())
+    }
 
-    Ok(day_times
+    /// Returns if the wifi should be on at the given time.
+    pub fn is_up_at(&self, time: &DateTime<Utc>) -> Result<bool> {
+        let local = time.with_timezone(&self.tz()?);
+        Ok(self.is_up_at_local(&local))
+    }
+
+    fn is_up_at_local(&self, local: &DateTime<Tz>) -> bool {
+        self.up_time
+            .get(weekday_to_index(local.weekday()))
             .map(|day_times| {
-                if day_times.is_empty() {
-                    // if no up times are given, the wifi should be activated the whole day
-                    true
-                } else {
-                    day_times.iter().any(|t| *t == now.time().hour() as u8)
-                }
+                // if no up times are given, the wifi should be activated the whole day
+                day_times.is_empty() || day_times.iter().any(|t| *t == local.hour() as u8)
             })
             // If None, enable wifi
-            .unwrap_or(true))
+            .unwrap_or(true)
+    }
+
+    /// Returns all switches of the wifi status in the `hours` after `from`, as
+    /// local time in the configured timezone and the new status.
+    pub fn transitions(&self, from: &DateTime<Utc>, hours: u32) -> Result<Vec<(DateTime<Tz>, bool)>> {
+        let local = from.with_timezone(&self.tz()?);
+        let mut status = self.is_up_at_local(&local);
+        let mut time = local - Duration::minutes(i64::from(local.minute()))
+            - Duration::seconds(i64::from(local.second()))
+            - Duration::nanoseconds(i64::from(local.nanosecond()));
+
+        let mut result = Vec::new();
+
+        for _ in 0..hours {
+            time = time + Duration::hours(1);
+            let next = self.is_up_at_local(&time);
+
+            if next != status {
+                result.push((time, next));
+                status = next;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Describes in words why the wifi should be on or off at the given time.
+    pub fn explain(&self, time: &DateTime<Utc>) -> Result<String> {
+        let local = time.with_timezone(&self.tz()?);
+        let day = local.weekday();
+
+        Ok(match self.up_time.get(weekday_to_index(day)) {
+            None => format!(
+                "no up times configured for {:?} (only {} days given), the wifi is on all day",
+                day,
+                self.up_time.len()
+            ),
+            Some(hours) if hours.is_empty() => format!(
+                "the up times for {:?} are empty, the wifi is on all day",
+                day
+            ),
+            Some(hours) => format!(
+                "the up times for {:?} are {:?}, it is {}:00 in {}, so the wifi is {}",
+                day,
+                hours,
+                local.hour(),
+                self.timezone,
+                if self.is_up_at_local(&local) { "on" } else { "off" }
+            ),
+        })
+    }
+
+    /// Minutes between announcing a shutdown and turning off the wifi.
+    pub fn grace_period(&self) -> u32 {
+        self.grace_period
+    }
 }
 
 fn weekday_to_index(wday: Weekday) -> usize {
@@ -108,7 +389,7 @@ fn weekday_to_index(wday: Weekday) -> usize {
 }
 
 /// Returns if the public wifi is currently enabled
-fn is_pub_wifi_enabled() -> Result<bool> {
+pub fn is_pub_wifi_enabled() -> Result<bool> {
     let uci_show = Command::new("uci")
         .args(&["show", "wireless"])
         .output()
@@ -133,6 +414,7 @@ fn is_pub_wifi_enabled_impl(output: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::TimeZone;
 
     const UCI_SHOW_NO_DISABLED: &str = r#"
         wireless.wpublicg=wifi-iface
@@ -183,4 +465,70 @@ mod tests {
     fn uci_show_disabled_parse() {
         assert!(!is_pub_wifi_enabled_impl(UCI_SHOW_DISABLED));
     }
+
+    fn time_control(up_time: Vec<Vec<u8>>, timezone: &str) -> TimeControl {
+        TimeControl {
+            up_time: up_time,
+            timezone: timezone.to_string(),
+            ..TimeControl::default()
+        }
+    }
+
+    #[test]
+    fn validate_invalid_hour() {
+        assert!(time_control(vec![vec![10], vec![24]], "UTC").validate().is_err());
+    }
+
+    #[test]
+    fn validate_too_many_days() {
+        assert!(time_control(vec![vec![]; 8], "UTC").validate().is_err());
+    }
+
+    #[test]
+    fn validate_invalid_timezone() {
+        assert!(time_control(vec![], "Europe/Nowhere").validate().is_err());
+    }
+
+    #[test]
+    fn validate_valid() {
+        assert!(time_control(vec![vec![0, 23]; 7], "Europe/Berlin").validate().is_ok());
+    }
+
+    #[test]
+    fn transitions_within_a_day() {
+        let tc = time_control(vec![vec![10, 11]; 7], "UTC");
+        // a monday
+        let from = Utc.ymd(2018, 6, 4).and_hms(9, 30, 0);
+
+        let transitions: Vec<(u32, bool)> = tc.transitions(&from, 24)
+            .unwrap()
+            .iter()
+            .map(|&(time, status)| (time.hour(), status))
+            .collect();
+
+        assert_eq!(transitions, vec![(10, true), (12, false)]);
+    }
+
+    #[test]
+    fn transitions_in_timezone() {
+        let tc = time_control(vec![vec![10]; 7], "Europe/Berlin");
+        // 08:00 utc is 10:00 in berlin during summer time
+        let from = Utc.ymd(2018, 6, 4).and_hms(7, 0, 0);
+
+        let transitions = tc.transitions(&from, 3).unwrap();
+
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[0].0.hour(), 10);
+        assert_eq!(transitions[1].0.hour(), 11);
+    }
+
+    #[test]
+    fn is_up_at_missing_day() {
+        let tc = time_control(vec![vec![10]], "UTC");
+        // a tuesday, only monday is configured
+        let time = Utc.ymd(2018, 6, 5).and_hms(3, 0, 0);
+
+        assert!(tc.is_up_at(&time).unwrap());
+        assert!(tc.explain(&time).unwrap().contains("on all day"));
+    }
 }
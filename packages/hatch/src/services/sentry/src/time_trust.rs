@@ -0,0 +1,162 @@
+use std::fs::File;
+#[cfg(feature = "access-control")]
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+
+use chrono::offset::Utc;
+
+use libc;
+
+/// Marker file, written once the clock was trusted for the first time. Holds
+/// the unix time it was written at. Lives on tmpfs, so it is gone after a
+/// reboot.
+pub const TIME_TRUSTED_PATH: &str = "/tmp/time.trusted";
+
+/// The archer has no rtc, so anything before this timestamp (2018-05-01) is
+/// the clock counting up from 1970 and not a real time.
+pub const TIME_TRUST_FLOOR: i64 = 1_525_132_800;
+
+// from <sys/timex.h>
+const TIME_ERROR: libc::c_int = 5;
+const STA_UNSYNC: libc::c_int = 0x0040;
+
+/// Why the clock is trusted or not.
+#[derive(Debug, PartialEq)]
+pub enum TimeTrust {
+    /// The clock was synchronized during this boot.
+    Trusted,
+    /// The clock is before the trust floor.
+    Implausible,
+    /// The clock looks plausible, but the kernel was not synchronized by ntp yet.
+    Unsynchronized,
+}
+
+/// Returns true if the system clock is reported as synchronized by the kernel.
+/// sysntpd (busybox ntpd) clears `STA_UNSYNC` once it has a good peer.
+fn kernel_synchronized() -> bool {
+    let mut tx: libc::timex = unsafe { mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut tx) };
+
+    state >= 0 && state != TIME_ERROR && tx.status & STA_UNSYNC == 0
+}
+
+fn check(now: i64, synchronized: bool) -> TimeTrust {
+    if now < TIME_TRUST_FLOOR {
+        TimeTrust::Implausible
+    } else if !synchronized {
+        TimeTrust::Unsynchronized
+    } else {
+        TimeTrust::Trusted
+    }
+}
+
+/// Determines whether the system clock can be used for decisions.
+///
+/// Once the clock was trusted, it stays trusted until the next reboot, even if
+/// ntp loses its peers later on. The clock keeps running fine without them.
+pub fn time_trust() -> TimeTrust {
+    if Path::new(TIME_TRUSTED_PATH).exists() {
+        return TimeTrust::Trusted;
+    }
+
+    let now = Utc::now().timestamp();
+    let trust = check(now, kernel_synchronized());
+
+    if trust == TimeTrust::Trusted {
+        let _ = File::create(TIME_TRUSTED_PATH).and_then(|mut f| write!(f, "{}", now));
+    }
+
+    trust
+}
+
+/// When the clock was trusted during this boot, if it was.
+#[cfg(feature = "access-control")]
+fn trusted_since() -> Option<i64> {
+    let mut s = String::new();
+    File::open(TIME_TRUSTED_PATH).and_then(|mut f| f.read_to_string(&mut s)).ok()?;
+    s.trim().parse().ok()
+}
+
+/// Shorthand for `time_trust() == TimeTrust::Trusted`.
+pub fn is_time_trusted() -> bool {
+    time_trust() == TimeTrust::Trusted
+}
+
+/// Whether the clock can be used for expiring accesses, and since when.
+#[cfg(feature = "access-control")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trust {
+    Untrusted,
+    /// Trusted since the unix time of the marker, if it holds one.
+    Trusted(Option<i64>),
+}
+
+#[cfg(feature = "access-control")]
+impl Trust {
+    /// The trust in the system clock, see `time_trust`.
+    pub fn system() -> Trust {
+        if is_time_trusted() {
+            Trust::Trusted(trusted_since())
+        } else {
+            Trust::Untrusted
+        }
+    }
+
+    pub fn is_trusted(&self) -> bool {
+        *self != Trust::Untrusted
+    }
+
+    /// Returns true if the timestamp was taken before the clock was trusted.
+    /// Such timestamps are meaningless: sysfixtime sets the clock to the build
+    /// date at boot, which looks plausible but may be far behind the time ntp
+    /// steps to.
+    pub fn is_before(&self, timestamp: i64) -> bool {
+        match *self {
+            Trust::Untrusted => true,
+            Trust::Trusted(since) => before_trust(timestamp, since),
+        }
+    }
+}
+
+#[cfg(feature = "access-control")]
+fn before_trust(timestamp: i64, trusted_since: Option<i64>) -> bool {
+    timestamp < trusted_since.unwrap_or(TIME_TRUST_FLOOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_implausible() {
+        assert_eq!(check(0, true), TimeTrust::Implausible);
+        assert_eq!(check(3600, false), TimeTrust::Implausible);
+    }
+
+    #[test]
+    fn test_check_unsynchronized() {
+        assert_eq!(check(TIME_TRUST_FLOOR + 1, false), TimeTrust::Unsynchronized);
+    }
+
+    #[test]
+    fn test_check_trusted() {
+        assert_eq!(check(TIME_TRUST_FLOOR + 1, true), TimeTrust::Trusted);
+    }
+
+    #[test]
+    #[cfg(feature = "access-control")]
+    fn test_before_trust() {
+        // authorized at the build date, then ntp stepped the clock a year on
+        let build_date = TIME_TRUST_FLOOR + 3600;
+        let synced = build_date + 365 * 24 * 3600;
+        assert!(before_trust(build_date + 60, Some(synced)));
+        assert!(!before_trust(synced, Some(synced)));
+        assert!(!before_trust(synced + 60, Some(synced)));
+
+        // a marker without a time
+        assert!(before_trust(TIME_TRUST_FLOOR - 1, None));
+        assert!(!before_trust(build_date, None));
+    }
+}
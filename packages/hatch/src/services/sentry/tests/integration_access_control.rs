@@ -1,3 +1,5 @@
+#![cfg(feature = "access-control")]
+
 extern crate chrono;
 extern crate iptables;
 extern crate sentry;
@@ -8,10 +10,18 @@ use std::io::Write;
 use chrono::Duration;
 use chrono::offset::Utc;
 
+use sentry::Trust;
+
 const IPT_TABLE: &str = "nat";
 const IPT_CHAIN: &str = "prerouting_public_rule";
 
-fn prepare_iptables(duration: Duration, valid_mac: &str, expired_mac: &str) {
+/// The clock was trusted long before any of the rules were added.
+fn trusted() -> Trust {
+    Trust::Trusted(Some(Utc::now().timestamp() - Duration::days(7).num_seconds()))
+}
+
+/// Returns the time the expired rule was stamped with.
+fn prepare_iptables(duration: Duration, valid_mac: &str, expired_mac: &str) -> i64 {
     let ipt = iptables::new(false).unwrap();
 
     ipt.flush_table(IPT_TABLE)
@@ -31,25 +41,32 @@ fn prepare_iptables(duration: Duration, valid_mac: &str, expired_mac: &str) {
         ),
     ).expect("Error adding rule 1");
 
+    let expired = ctime - duration.num_seconds() - 10;
     ipt.append(
         IPT_TABLE,
         IPT_CHAIN,
         &format!(
             r#"-m mac --mac-source {} -m comment --comment timestamp={} -j ACCEPT"#,
             expired_mac,
-            ctime - duration.num_seconds() - 10
+            expired
         ),
     ).expect("Error adding rule 2");
+
+    expired
 }
 
-fn check_iptables_output(valid_mac: &str, expired_mac: &str) {
+fn list_rules() -> String {
     let ipt = iptables::new(false).unwrap();
 
-    let rules = ipt.list(IPT_TABLE, IPT_CHAIN)
+    ipt.list(IPT_TABLE, IPT_CHAIN)
         .expect("Could not list rules")
         .iter()
         .flat_map(|s| s.chars())
-        .collect::<String>();
+        .collect::<String>()
+}
+
+fn check_iptables_output(valid_mac: &str, expired_mac: &str) {
+    let rules = list_rules();
 
     assert!(rules.contains(&valid_mac.to_string()));
     assert!(!rules.contains(&expired_mac.to_string()));
@@ -63,11 +80,46 @@ fn test_access_control() {
 
     prepare_iptables(duration, valid_mac, expired_mac);
 
-    sentry::check_for_expired(Some(duration)).expect("Error calling zealot main");
+    sentry::check_for_expired_with(Some(duration), trusted()).expect("Error calling zealot main");
 
     check_iptables_output(valid_mac, expired_mac);
 }
 
+#[test]
+fn test_access_control_restamp() {
+    let valid_mac = "DE:AD:BE:DE:AD:DD";
+    let expired_mac = "DE:AD:BE:DE:FF:DD";
+    let duration = Duration::hours(1);
+
+    let expired = prepare_iptables(duration, valid_mac, expired_mac);
+
+    // the clock was trusted after the expired rule was stamped, its timestamp
+    // is meaningless and replaced by the current time
+    let since = Utc::now().timestamp() - 5;
+    sentry::check_for_expired_with(Some(duration), Trust::Trusted(Some(since)))
+        .expect("Error calling zealot main");
+
+    let rules = list_rules();
+    assert!(rules.contains(&valid_mac.to_string()));
+    assert!(rules.contains(&expired_mac.to_string()));
+    assert!(!rules.contains(&format!("timestamp={}", expired)));
+}
+
+#[test]
+fn test_access_control_untrusted() {
+    let valid_mac = "DE:AD:BE:DE:AD:DC";
+    let expired_mac = "DE:AD:BE:DE:FF:DC";
+    let duration = Duration::hours(1);
+
+    let expired = prepare_iptables(duration, valid_mac, expired_mac);
+
+    sentry::check_for_expired_with(Some(duration), Trust::Untrusted).expect("Error calling zealot main");
+
+    let rules = list_rules();
+    assert!(rules.contains(&valid_mac.to_string()));
+    assert!(rules.contains(&format!("timestamp={}", expired)));
+}
+
 #[test]
 fn test_access_control_duration_from_file() {
     let valid_mac = "DE:AD:BE:DE:AD:DF";
@@ -80,7 +132,7 @@ fn test_access_control_duration_from_file() {
         File::create("/etc/zealot_rule_valid_time").expect("Error creating config file!");
     write!(conf_file, "{}", duration.num_seconds()).expect("Error writing to config file!");
 
-    sentry::check_for_expired(None).expect("Error calling zealot main");
+    sentry::check_for_expired_with(None, trusted()).expect("Error calling zealot main");
 
     check_iptables_output(valid_mac, expired_mac);
 }
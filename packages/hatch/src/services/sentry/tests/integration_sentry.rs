@@ -1,3 +1,5 @@
+#![cfg(feature = "portal")]
+
 extern crate bytes;
 #[macro_use]
 extern crate derive_new;
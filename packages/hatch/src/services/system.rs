@@ -0,0 +1,41 @@
+//! What the operators and the watchdog do to the device.
+
+use std::process::Command as Process;
+
+use failure::{self, Error};
+
+/// Where preinit unpacked the genesis package, and the config it ran with.
+const GENESIS_EXE: &str = "/tmp/genesispkg/exe";
+const GENESIS_CONFIG: &str = "/genesis/config";
+
+/// Long enough for the outcome of a command to reach the server before the
+/// reboot.
+const REBOOT_DELAY_SECS: u32 = 5;
+
+pub fn reboot() -> Result<String, Error> {
+    Process::new("sh")
+        .arg("-c")
+        .arg(format!("sleep {} && reboot", REBOOT_DELAY_SECS))
+        .spawn()?;
+    Ok(format!("rebooting in {}s", REBOOT_DELAY_SECS))
+}
+
+/// Runs genesis with the config on the genesis partition again, and reloads
+/// what it changed.
+pub fn genesis() -> Result<String, Error> {
+    let mut output = run_process(GENESIS_EXE, &[GENESIS_CONFIG])?;
+    output.push_str(&run_process("reload_config", &[])?);
+    Ok(output)
+}
+
+/// Runs a program to completion, failing unless it exits successfully.
+pub fn run_process(program: &str, args: &[&str]) -> Result<String, Error> {
+    let output = Process::new(program).args(args).output()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    if !output.status.success() {
+        return Err(failure::err_msg(format!("{} failed with {}: {}", program, output.status, text.trim())));
+    }
+    Ok(text)
+}
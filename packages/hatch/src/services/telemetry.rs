@@ -0,0 +1,20 @@
+//! What hatch adds to the lifeline telemetry.
+
+use lifeline::telemetry::Telemetry;
+#[cfg(any(feature = "access-control", feature = "time-control"))]
+use sentry;
+
+#[cfg_attr(not(any(feature = "access-control", feature = "time-control")), allow(unused_variables))]
+pub fn probe(t: &mut Telemetry) {
+    #[cfg(feature = "access-control")]
+    match sentry::authorized_clients() {
+        Ok(n) => t.sentry_clients = Some(n),
+        Err(e) => debug!("cannot count sentry clients: {}", e),
+    }
+
+    #[cfg(feature = "time-control")]
+    match sentry::is_pub_wifi_enabled() {
+        Ok(enabled) => t.public_wifi = Some(enabled),
+        Err(e) => debug!("cannot get public wifi status: {}", e),
+    }
+}
@@ -0,0 +1,476 @@
+//! Keeps the device reachable. Every `CHECK_INTERVAL_SECS` it checks the WAN
+//! link, the DHCP lease, DNS, lifeline and whether the portal is reachable.
+//! Once a local check, the link, the lease or DNS, failed for `GRACE_SECS` it
+//! renews the DHCP lease, then restarts the network, runs genesis again and
+//! finally reboots, `STEP_SECS` apart, until the local checks pass again.
+//! Neither genesis nor a reboot bring back a WAN link without carrier, it
+//! stops at restarting the network then.
+//!
+//! The portal and lifeline are only reported. They are down for reasons the
+//! device cannot repair, rebooting the fleet for an outage of the servers
+//! would not help. A device reboots at most `MAX_REBOOTS` times in a row,
+//! counted on flash, until the local checks pass again.
+//!
+//! It also takes the kernel watchdog over from procd and feeds it only while
+//! the checks keep running, the kernel reboots a hatch that hangs. On SIGTERM
+//! it closes the watchdog properly and hands it back to procd.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use libc;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use serde_json::{self, Value};
+
+#[cfg(feature = "lifeline")]
+use lifeline;
+use services::system;
+
+const CHECK_INTERVAL_SECS: u64 = 60;
+/// How long a check may fail before anything is done about it.
+const GRACE_SECS: u64 = 10 * 60;
+/// Time between the steps of the escalation.
+const STEP_SECS: u64 = 15 * 60;
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+const WATCHDOG_DEVICE: &str = "/dev/watchdog";
+/// procd feeds the kernel watchdog every 5s with a timeout of 30s.
+const FEED_SECS: u64 = 5;
+/// The checks are stuck when they did not finish a round for this long.
+const STALE_SECS: u64 = 5 * CHECK_INTERVAL_SECS;
+
+/// Reboots by the watchdog without the local checks passing in between.
+const MAX_REBOOTS: u32 = 3;
+/// On the state partition, so it survives reboots.
+const REBOOTS_PATH: &str = "/state/watchdog.reboots";
+
+const WAN: &str = "network.interface.wan";
+/// The portal url, written by genesis.
+const PORTAL_URL_PATH: &str = "/etc/sentry.url";
+
+/// How the watchdog repairs the connectivity, in order.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Step {
+    RenewLease,
+    RestartNetwork,
+    Genesis,
+    Reboot,
+}
+
+const STEPS: &[Step] = &[Step::RenewLease, Step::RestartNetwork, Step::Genesis, Step::Reboot];
+
+impl Step {
+    fn take(&self) -> Result<String, Error> {
+        match *self {
+            Step::RenewLease => system::run_process("ubus", &["call", WAN, "renew"]),
+            Step::RestartNetwork => system::run_process("/etc/init.d/network", &["restart"]),
+            Step::Genesis => system::genesis(),
+            Step::Reboot => system::reboot(),
+        }
+    }
+}
+
+/// The steps taken since the local checks started failing.
+#[derive(Default)]
+struct Escalation {
+    failing_since: Option<Instant>,
+    taken: usize,
+    /// Reboots taken before, in earlier boots too.
+    reboots: u32,
+}
+
+impl Escalation {
+    fn new(reboots: u32) -> Escalation {
+        Escalation { reboots: reboots, ..Escalation::default() }
+    }
+
+    /// The step due after a round of checks, if any. `ok` is whether the local
+    /// checks passed, `carrier` whether the WAN link has one.
+    fn next(&mut self, ok: bool, carrier: bool, now: Instant) -> Option<Step> {
+        if ok {
+            *self = Escalation::default();
+            return None;
+        }
+
+        let since = *self.failing_since.get_or_insert(now);
+        let due = Duration::from_secs(GRACE_SECS + STEP_SECS * self.taken as u64);
+        if now.duration_since(since) < due || self.taken >= STEPS.len() {
+            return None;
+        }
+
+        let step = STEPS[self.taken];
+        if step > Step::RestartNetwork && !carrier {
+            return None;
+        }
+        self.taken += 1;
+
+        if step == Step::Reboot {
+            if self.reboots >= MAX_REBOOTS {
+                return None;
+            }
+            self.reboots += 1;
+        }
+        Some(step)
+    }
+}
+
+fn load_reboots() -> u32 {
+    let mut count = String::new();
+    match File::open(REBOOTS_PATH).and_then(|mut f| f.read_to_string(&mut count)) {
+        Ok(_) => count.trim().parse().unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Written before the reboot, and once the local checks pass again.
+fn store_reboots(count: u32) {
+    let tmp = format!("{}.tmp", REBOOTS_PATH);
+    let stored = File::create(&tmp)
+        .and_then(|mut f| write!(f, "{}", count).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, REBOOTS_PATH));
+    if let Err(e) = stored {
+        warn!("watchdog: cannot write {}: {}", REBOOTS_PATH, e);
+    }
+}
+
+/// `ubus call network.interface.wan status`
+fn wan_status() -> Result<Value, String> {
+    let output = system::run_process("ubus", &["call", WAN, "status"]).map_err(|e| e.to_string())?;
+    serde_json::from_str(&output).map_err(|e| format!("invalid status of {}: {}", WAN, e))
+}
+
+fn wan_device(status: &Value) -> Option<&str> {
+    status.get("l3_device").or_else(|| status.get("device")).and_then(Value::as_str)
+}
+
+fn check_link(status: &Value) -> Result<(), String> {
+    let device = wan_device(status).ok_or_else(|| "wan has no device".to_string())?;
+    let mut carrier = String::new();
+    File::open(Path::new("/sys/class/net").join(device).join("carrier"))
+        .and_then(|mut f| f.read_to_string(&mut carrier))
+        .map_err(|e| format!("no carrier on {}: {}", device, e))?;
+
+    match carrier.trim() {
+        "1" => Ok(()),
+        _ => Err(format!("no carrier on {}", device)),
+    }
+}
+
+fn check_lease(status: &Value) -> Result<(), String> {
+    let proto = status.get("proto").and_then(Value::as_str).unwrap_or("-");
+    let up = status.get("up").and_then(Value::as_bool).unwrap_or(false);
+    let addresses = status.get("ipv4-address").and_then(Value::as_array).map(|a| a.len()).unwrap_or(0);
+
+    match (up, addresses) {
+        (true, n) if n > 0 => Ok(()),
+        (true, _) => Err(format!("wan ({}) has no address", proto)),
+        (false, _) => Err(format!("wan ({}) is down", proto)),
+    }
+}
+
+/// Host and port of an http(s) url.
+fn host_port(url: &str) -> Option<(String, u16)> {
+    let (rest, port) = if url.starts_with("https://") {
+        (&url["https://".len()..], 443)
+    } else if url.starts_with("http://") {
+        (&url["http://".len()..], 80)
+    } else {
+        return None;
+    };
+
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    let mut parts = authority.splitn(2, ':');
+    let host = parts.next().unwrap_or("");
+    let port = match parts.next() {
+        Some(port) => port.parse().ok()?,
+        None => port,
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some((host.to_string(), port))
+    }
+}
+
+fn portal() -> Result<(String, u16), String> {
+    let mut url = String::new();
+    File::open(PORTAL_URL_PATH)
+        .and_then(|mut f| f.read_to_string(&mut url))
+        .map_err(|e| format!("cannot read {}: {}", PORTAL_URL_PATH, e))?;
+    host_port(url.trim()).ok_or_else(|| format!("invalid portal url {}", url.trim()))
+}
+
+fn check_dns(host: &str, port: u16) -> Result<SocketAddr, String> {
+    (host, port).to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("{} has no address", host))
+}
+
+fn check_portal(addr: &SocketAddr) -> Result<(), String> {
+    TcpStream::connect_timeout(addr, Duration::from_secs(CONNECT_TIMEOUT_SECS))
+        .map(|_| ())
+        .map_err(|e| format!("cannot reach the portal at {}: {}", addr, e))
+}
+
+/// `None` while lifeline is not running.
+#[cfg(feature = "lifeline")]
+fn check_lifeline() -> Option<Result<(), String>> {
+    let mut state = String::new();
+    if File::open(lifeline::state::STATE_PATH).and_then(|mut f| f.read_to_string(&mut state)).is_err() {
+        return None;
+    }
+    Some(lifeline_connected(&state))
+}
+
+#[cfg(not(feature = "lifeline"))]
+fn check_lifeline() -> Option<Result<(), String>> {
+    None
+}
+
+#[cfg(any(feature = "lifeline", test))]
+fn lifeline_connected(state: &str) -> Result<(), String> {
+    let state: Value = serde_json::from_str(state).map_err(|e| format!("invalid lifeline state: {}", e))?;
+    match state.get("state").and_then(Value::as_str) {
+        Some("connected") => Ok(()),
+        Some(state) => Err(format!("lifeline is {}", state.replace('_', " "))),
+        None => Err("invalid lifeline state".to_string()),
+    }
+}
+
+type Checks = Vec<(&'static str, Result<(), String>)>;
+
+/// One round of checks, by name: the local ones the steps can repair, and the
+/// remote ones.
+fn checks() -> (Checks, Checks) {
+    let status = wan_status();
+    let mut local = vec![
+        ("wan link", status.as_ref().map_err(|e| e.clone()).and_then(check_link)),
+        ("dhcp lease", status.as_ref().map_err(|e| e.clone()).and_then(check_lease)),
+    ];
+    let mut remote = Vec::new();
+
+    match portal().and_then(|(host, port)| check_dns(&host, port)) {
+        Ok(addr) => {
+            local.push(("dns", Ok(())));
+            remote.push(("portal", check_portal(&addr)));
+        }
+        // the portal cannot be reached without its address
+        Err(e) => local.push(("dns", Err(e))),
+    }
+
+    if let Some(result) = check_lifeline() {
+        remote.push(("lifeline", result));
+    }
+    (local, remote)
+}
+
+fn failures(checks: &Checks) -> Vec<String> {
+    checks.iter()
+        .filter_map(|&(name, ref result)| result.as_ref().err().map(|e| format!("{}: {}", name, e)))
+        .collect()
+}
+
+/// Set on SIGTERM, the feeder hands the watchdog back then.
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn terminate(_: libc::c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+}
+
+/// Asks procd to stop feeding the kernel watchdog, and takes SIGTERM over to
+/// give it back.
+fn take_over() -> Result<File, Error> {
+    system::run_process("ubus", &["call", "system", "watchdog", r#"{"magicclose": true, "stop": true}"#])?;
+    let device = OpenOptions::new().write(true).open(WATCHDOG_DEVICE)?;
+
+    let action = SigAction::new(SigHandler::Handler(terminate), SaFlags::empty(), SigSet::empty());
+    unsafe { signal::sigaction(Signal::SIGTERM, &action)? };
+    Ok(device)
+}
+
+/// Disarms the kernel watchdog with the magic close, and lets procd feed it
+/// again.
+fn hand_back(mut device: File) {
+    if let Err(e) = device.write_all(b"V").and_then(|_| device.flush()) {
+        warn!("watchdog: cannot close {}: {}", WATCHDOG_DEVICE, e);
+    }
+    drop(device);
+    match system::run_process("ubus", &["call", "system", "watchdog", r#"{"stop": false}"#]) {
+        Ok(_) => info!("watchdog: procd feeds {} again", WATCHDOG_DEVICE),
+        Err(e) => error!("watchdog: cannot hand {} back to procd: {}", WATCHDOG_DEVICE, e),
+    }
+}
+
+/// Feeds the kernel watchdog until the checks stop beating, or hatch is
+/// terminated.
+fn feed(mut device: File, heartbeat: Arc<Mutex<Instant>>) {
+    let mut starving = false;
+    for tick in 0.. {
+        if TERMINATING.load(Ordering::SeqCst) {
+            hand_back(device);
+            process::exit(0);
+        }
+
+        if tick % FEED_SECS == 0 {
+            let fresh = heartbeat.lock().unwrap().elapsed() < Duration::from_secs(STALE_SECS);
+            if fresh {
+                if let Err(e) = device.write_all(b"\0").and_then(|_| device.flush()) {
+                    warn!("watchdog: cannot feed {}: {}", WATCHDOG_DEVICE, e);
+                }
+            } else if !starving {
+                error!("watchdog: hatch is unhealthy, letting the kernel watchdog reboot");
+            }
+            starving = !fresh;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+pub fn main() -> Result<(), Error> {
+    let heartbeat = Arc::new(Mutex::new(Instant::now()));
+
+    match take_over() {
+        Ok(device) => {
+            let heartbeat = heartbeat.clone();
+            thread::Builder::new()
+                .name("watchdog-feed".to_string())
+                .spawn(move || feed(device, heartbeat))?;
+        }
+        Err(e) => warn!("watchdog: cannot take over {}, procd keeps feeding it: {}", WATCHDOG_DEVICE, e),
+    }
+
+    let mut escalation = Escalation::new(load_reboots());
+    loop {
+        let (local, remote) = checks();
+        let failed = failures(&local);
+        let carrier = local.iter().any(|&(name, ref result)| name == "wan link" && result.is_ok());
+
+        if failed.is_empty() {
+            if escalation.failing_since.is_some() {
+                info!("watchdog: connectivity is back after {} steps", escalation.taken);
+            }
+        } else {
+            warn!("watchdog: {}", failed.join(", "));
+        }
+        let unreachable = failures(&remote);
+        if !unreachable.is_empty() {
+            warn!("watchdog: {}, not repaired from here", unreachable.join(", "));
+        }
+
+        let (taken, reboots) = (escalation.taken, escalation.reboots);
+        let step = escalation.next(failed.is_empty(), carrier, Instant::now());
+        if escalation.reboots != reboots {
+            store_reboots(escalation.reboots);
+        }
+        if step.is_none() && escalation.taken > taken {
+            error!("watchdog: rebooted {} times to no avail, not again", escalation.reboots);
+        }
+
+        if let Some(step) = step {
+            warn!("watchdog: failing for {}s, trying {:?}",
+                  escalation.failing_since.map(|t| t.elapsed().as_secs()).unwrap_or(0), step);
+            match step.take() {
+                Ok(output) => info!("watchdog: {:?} done {}", step, output.trim()),
+                Err(e) => error!("watchdog: {:?} failed: {}", step, e),
+            }
+        }
+
+        *heartbeat.lock().unwrap() = Instant::now();
+        thread::sleep(Duration::from_secs(CHECK_INTERVAL_SECS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalation() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut escalation = Escalation::default();
+
+        assert_eq!(escalation.next(false, true, at(0)), None);
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS - 1)), None);
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS)), Some(Step::RenewLease));
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS + 1)), None);
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS + STEP_SECS)), Some(Step::RestartNetwork));
+
+        // rebooting does not bring the cable back
+        assert_eq!(escalation.next(false, false, at(GRACE_SECS + 2 * STEP_SECS)), None);
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS + 2 * STEP_SECS)), Some(Step::Genesis));
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS + 3 * STEP_SECS)), Some(Step::Reboot));
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS + 9 * STEP_SECS)), None);
+
+        assert_eq!(escalation.next(true, true, at(GRACE_SECS + 9 * STEP_SECS)), None);
+        assert_eq!(escalation.taken, 0);
+        assert_eq!(escalation.next(false, true, at(GRACE_SECS + 10 * STEP_SECS)), None);
+    }
+
+    #[test]
+    fn test_reboot_cap() {
+        let start = Instant::now();
+        let at = |step: u64| start + Duration::from_secs(GRACE_SECS + step * STEP_SECS);
+
+        let mut escalation = Escalation::new(MAX_REBOOTS - 1);
+        escalation.next(false, true, start);
+        for step in 0..3 {
+            escalation.next(false, true, at(step));
+        }
+        assert_eq!(escalation.next(false, true, at(3)), Some(Step::Reboot));
+        assert_eq!(escalation.reboots, MAX_REBOOTS);
+
+        // after the reboot, the count came from flash
+        let mut escalation = Escalation::new(MAX_REBOOTS);
+        escalation.next(false, true, start);
+        for step in 0..3 {
+            assert!(escalation.next(false, true, at(step)).is_some());
+        }
+        assert_eq!(escalation.next(false, true, at(3)), None);
+
+        // passing local checks start the count over
+        escalation.next(true, true, at(4));
+        assert_eq!(escalation.reboots, 0);
+    }
+
+    #[test]
+    fn test_lease() {
+        let status: Value = serde_json::from_str(r#"{
+            "up": true, "proto": "dhcp", "l3_device": "eth0.2",
+            "ipv4-address": [{"address": "10.0.0.2", "mask": 24}]
+        }"#).unwrap();
+        assert_eq!(wan_device(&status), Some("eth0.2"));
+        assert!(check_lease(&status).is_ok());
+
+        let status: Value = serde_json::from_str(r#"{"up": false, "proto": "dhcp", "device": "eth0.2"}"#).unwrap();
+        assert_eq!(wan_device(&status), Some("eth0.2"));
+        assert_eq!(check_lease(&status), Err("wan (dhcp) is down".to_string()));
+    }
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!(host_port("http://gastfreund.net/?origin="), Some(("gastfreund.net".to_string(), 80)));
+        assert_eq!(host_port("https://portal.example:8443"), Some(("portal.example".to_string(), 8443)));
+        assert_eq!(host_port("http://portal.example?origin="), Some(("portal.example".to_string(), 80)));
+        assert_eq!(host_port("ftp://portal.example/"), None);
+        assert_eq!(host_port("http:///"), None);
+    }
+
+    #[test]
+    fn test_lifeline_connected() {
+        assert!(lifeline_connected(r#"{"state":"connected","server":"a","ip":"10.0.0.1"}"#).is_ok());
+        assert_eq!(lifeline_connected(r#"{"state":"rate_limited","ms":100}"#),
+                   Err("lifeline is rate limited".to_string()));
+        assert!(lifeline_connected("").is_err());
+    }
+}
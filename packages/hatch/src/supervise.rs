@@ -0,0 +1,286 @@
+//! Runs the hatch services in one process, which saves the memory of one
+//! process per service.
+//!
+//! Every service runs in a thread of its own. A service that returns, with an
+//! error or not, is started again after a backoff. The backoff is reset once
+//! it ran for `HEALTHY_SECS`. hatch is built with `panic = 'abort'`, so a
+//! panic still takes the whole process down and procd restarts it.
+//!
+//! The state of each service is written to `HEALTH_PATH` whenever it changes.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "sentry")]
+use failure;
+use failure::Error;
+use serde_json;
+use toml;
+
+#[cfg(feature = "lifeline")]
+use identity::Identity;
+#[cfg(feature = "lifeline")]
+use lifeline;
+#[cfg(feature = "sentry")]
+use sentry;
+use services::{self, unix_now};
+
+pub const CONFIG_PATH: &str = "/etc/hatch.toml";
+pub const HEALTH_PATH: &str = "/tmp/hatch.health";
+
+const BACKOFF_BASE_SECS: u64 = 1;
+const BACKOFF_CAP_SECS: u64 = 300;
+/// A service that ran this long is considered fine again.
+const HEALTHY_SECS: u64 = 600;
+/// How often access_control and time_control check.
+#[cfg(any(feature = "access-control", feature = "time-control"))]
+const CHECK_INTERVAL_SECS: u64 = 60;
+
+const SERVICES: &[&str] = &["sentry", "lifeline", "access_control", "time_control", "watchdog"];
+
+fn enabled() -> bool {
+    true
+}
+
+/// `/etc/hatch.toml`, written by genesis.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Run the services with `hatch supervise` rather than each on its own.
+    /// Read by the init scripts.
+    #[serde(default)]
+    pub supervise: bool,
+    /// Run lifeline with protocol version 2 rather than 1. Read by the init
+    /// scripts too.
+    #[serde(default)]
+    pub lifeline2: bool,
+    #[serde(default)]
+    pub services: Services,
+}
+
+/// Which services `hatch supervise` runs.
+#[derive(Debug, Deserialize)]
+pub struct Services {
+    #[serde(default = "enabled")]
+    pub sentry: bool,
+    #[serde(default = "enabled")]
+    pub lifeline: bool,
+    #[serde(default = "enabled")]
+    pub access_control: bool,
+    #[serde(default = "enabled")]
+    pub time_control: bool,
+    #[serde(default = "enabled")]
+    pub watchdog: bool,
+}
+
+impl Default for Services {
+    fn default() -> Self {
+        Services {
+            sentry: true,
+            lifeline: true,
+            access_control: true,
+            time_control: true,
+            watchdog: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Error> {
+        let mut s = String::new();
+        File::open(CONFIG_PATH)?.read_to_string(&mut s)?;
+        Ok(toml::from_str(&s)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Disabled,
+    Running,
+    /// Returned, waiting for the backoff before it is started again.
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+    pub state: State,
+    /// unix time of the last change of `state`.
+    pub since: u64,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+type Report = Arc<Mutex<BTreeMap<&'static str, Health>>>;
+
+/// Runs until it fails. Called again for every restart.
+type Run = Box<Fn() -> Result<(), Error> + Send>;
+
+fn update<F: FnOnce(&mut Health)>(report: &Report, name: &'static str, f: F) {
+    let mut report = report.lock().unwrap();
+    if let Some(health) = report.get_mut(name) {
+        f(health);
+    }
+
+    // replaced at once, `hatch supervise --status` never sees half of it
+    let tmp = format!("{}.tmp", HEALTH_PATH);
+    let written = serde_json::to_vec_pretty(&*report)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .and_then(|json| File::create(&tmp).and_then(|mut f| f.write_all(&json)))
+        .and_then(|_| fs::rename(&tmp, HEALTH_PATH));
+    if let Err(e) = written {
+        warn!("cannot write {}: {}", HEALTH_PATH, e);
+    }
+}
+
+fn supervise(name: &'static str, run: Run, report: Report) {
+    let base = Duration::from_secs(BACKOFF_BASE_SECS);
+    let mut wait = base;
+
+    loop {
+        update(&report, name, |h| {
+            h.state = State::Running;
+            h.since = unix_now();
+        });
+
+        let started = Instant::now();
+        let error = match run() {
+            Ok(()) => "returned".to_string(),
+            Err(e) => e.to_string(),
+        };
+        if started.elapsed() >= Duration::from_secs(HEALTHY_SECS) {
+            wait = base;
+        }
+
+        warn!("{} failed: {}, restarting in {}s", name, error, wait.as_secs());
+        update(&report, name, |h| {
+            h.state = State::Failed;
+            h.since = unix_now();
+            h.restarts += 1;
+            h.last_error = Some(error);
+        });
+
+        thread::sleep(wait);
+        wait = cmp::min(wait * 2, Duration::from_secs(BACKOFF_CAP_SECS));
+    }
+}
+
+/// Runs `check` every `CHECK_INTERVAL_SECS` until it fails.
+#[cfg(any(feature = "access-control", feature = "time-control"))]
+fn every_interval<F: Fn() -> sentry::errors::Result<()>>(check: F) -> Result<(), Error> {
+    loop {
+        check().map_err(|e| failure::err_msg(e.to_string()))?;
+        thread::sleep(Duration::from_secs(CHECK_INTERVAL_SECS));
+    }
+}
+
+/// The services built into this hatch.
+#[cfg_attr(not(any(feature = "portal", feature = "lifeline")), allow(unused_variables))]
+fn tasks(id: &str, lifeline2: bool) -> Vec<(&'static str, Run)> {
+    let mut tasks: Vec<(&'static str, Run)> = Vec::new();
+
+    #[cfg(feature = "portal")]
+    {
+        let id = id.to_string();
+        tasks.push(("sentry", Box::new(move || {
+            sentry::sentry_main(id.clone(), None, None).map_err(|e| failure::err_msg(e.to_string()))
+        })));
+    }
+    #[cfg(feature = "lifeline")]
+    {
+        let id = id.to_string();
+        tasks.push(("lifeline", Box::new(move || {
+            if !lifeline2 {
+                services::lifeline1::main(id.clone());
+                return Ok(());
+            }
+            // the key cannot be shared between runs, read it again
+            let keypair = Identity::load().ok().map(Identity::into_keypair);
+            lifeline::main(id.clone(), keypair,
+                           Box::new(services::telemetry::probe), Arc::new(services::commands::run));
+            Ok(())
+        })));
+    }
+    #[cfg(feature = "access-control")]
+    tasks.push(("access_control", Box::new(|| every_interval(|| sentry::check_for_expired(None)))));
+    #[cfg(feature = "time-control")]
+    tasks.push(("time_control", Box::new(|| every_interval(sentry::check_public_wifi))));
+    tasks.push(("watchdog", Box::new(services::watchdog::main)));
+
+    tasks
+}
+
+fn is_enabled(services: &Services, name: &str) -> bool {
+    match name {
+        "sentry" => services.sentry,
+        "lifeline" => services.lifeline,
+        "access_control" => services.access_control,
+        "time_control" => services.time_control,
+        "watchdog" => services.watchdog,
+        _ => false,
+    }
+}
+
+pub fn main(id: String) {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("cannot load {}, running all services: {}", CONFIG_PATH, e);
+            Config::default()
+        }
+    };
+    if !config.supervise {
+        warn!("supervise is off in {}, the init scripts start the services on their own too", CONFIG_PATH);
+    }
+
+    let tasks = tasks(&id, config.lifeline2);
+    for name in SERVICES {
+        if is_enabled(&config.services, name) && !tasks.iter().any(|&(built, _)| built == *name) {
+            warn!("{} is enabled, but not built into this hatch", name);
+        }
+    }
+
+    let report: Report = Arc::new(Mutex::new(tasks.iter().map(|&(name, _)| {
+        (name, Health { state: State::Disabled, since: unix_now(), restarts: 0, last_error: None })
+    }).collect()));
+
+    let mut threads = Vec::new();
+    for (name, run) in tasks {
+        if !is_enabled(&config.services, name) {
+            info!("{} is disabled", name);
+            continue;
+        }
+
+        let report = report.clone();
+        let spawned = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || supervise(name, run, report));
+        match spawned {
+            Ok(thread) => threads.push(thread),
+            Err(e) => error!("cannot start {}: {}", name, e),
+        }
+    }
+
+    // the supervisors never return
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+/// Prints the health the running supervisor last wrote.
+pub fn status() -> Result<(), Error> {
+    let report: BTreeMap<String, Health> = serde_json::from_reader(File::open(HEALTH_PATH)?)?;
+    for (name, health) in report {
+        print!("{:<16} {:<9} since {}, {} restarts", name,
+               format!("{:?}", health.state).to_lowercase(), health.since, health.restarts);
+        match health.last_error {
+            Some(e) => println!(", last: {}", e),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
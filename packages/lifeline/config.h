@@ -1,3 +1,4 @@
+// fallback only, genesis writes the servers to /etc/lifeline.servers
 char *LIFELINE_SERVERS[]   = {
     "lifeline.hy5.berlin",
     "lifeline.captif.io",
@@ -233,10 +233,39 @@ int attempt_remote(const char *connectName, int address)
 
 #include "dns.c"
 
+// servers from genesis, LIFELINE_SERVERS if there are none
+#define SERVERS_FILE "/etc/lifeline.servers"
+#define MAX_SERVERS 32
+static char *servers_file[MAX_SERVERS + 1];
+static char **servers = LIFELINE_SERVERS;
+
+void load_servers(const char *filename)
+{
+    FILE *fp = fopen(filename, "r");
+    if (!fp) {
+        return;
+    }
+
+    char buf[256];
+    int n = 0;
+    while (n < MAX_SERVERS && fgets(buf, sizeof(buf), fp) != 0) {
+        buf[strcspn(buf, " \t\r\n")] = 0;
+        if (buf[0] != 0) {
+            servers_file[n++] = strdup(buf);
+        }
+    }
+    servers_file[n] = 0;
+    fclose(fp);
+
+    if (n > 0) {
+        servers = servers_file;
+    }
+}
+
 int attempt_dns(const char *dns_ip)
 {
     fprintf(stderr, "trying dns: %s\n", dns_ip);
-    for (char **e = LIFELINE_SERVERS; *e != 0; e++) {
+    for (char **e = servers; *e != 0; e++) {
         printf("connecting to: %s:80\n", *e);
         int address = inet_addr(*e);
         if (address == INADDR_NONE) {
@@ -288,6 +317,7 @@ void parse_resolvconf(const char *filename) {
 
 int main(int argc, char *argv[])
 {
+    load_servers(SERVERS_FILE);
     for (char **re = DNS_RESOLVERS; *re != 0; re++){
         if (*re[0] == '/') {
             parse_resolvconf(*re);
@@ -0,0 +1,190 @@
+//! Creates and inspects identity partitions at provisioning time, and
+//! verifies the attestations of devices.
+
+extern crate bs58;
+extern crate ed25519_dalek;
+extern crate identity;
+extern crate rand;
+extern crate serde_json;
+
+use std::env;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::PublicKey;
+use identity::attestation::Attestation;
+use identity::blob::{self, Blob, Metadata};
+use identity::{Identity, Source};
+use rand::{OsRng, Rng};
+
+fn usage() -> ! {
+    eprintln!("usage:");
+    eprintln!("  identity-cli create <out> [--board name] [--batch name] [--secret partition] [--sign operator key]");
+    eprintln!("  identity-cli sign <partition> <operator key>");
+    eprintln!("  identity-cli inspect <partition> [--operator base58 key]");
+    eprintln!("  identity-cli verify <base58 operator key> <nonce> < attestation");
+    process::exit(1);
+}
+
+fn fail<E: Display>(e: E) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+fn read(path: &str) -> Blob {
+    let mut data = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        fail(format!("{}: {}", path, e));
+    }
+    Blob::parse(&data).unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+}
+
+/// Writes `blob` padded to the size of the partition, as erased flash.
+fn write(path: &str, blob: &Blob) {
+    let mut data = blob.encode().unwrap_or_else(|e| fail(e));
+    data.resize(blob::MAX_LEN, 0xff);
+
+    if let Err(e) = File::create(path).and_then(|mut f| f.write_all(&data)) {
+        fail(format!("{}: {}", path, e));
+    }
+}
+
+/// An operator key file holds the 32 bytes of the secret key, like a
+/// partition in the original layout.
+fn operator_key(path: &str) -> Identity {
+    Identity::load_from(&Source::File(path.into())).unwrap_or_else(|e| fail(e))
+}
+
+fn public_key(key: &str) -> PublicKey {
+    bs58::decode(key).with_alphabet(bs58::alphabet::BITCOIN).into_vec().ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+        .unwrap_or_else(|| fail(format!("invalid operator key {}", key)))
+}
+
+fn id(blob: &Blob) -> String {
+    Identity::from_secret(&blob.secret).unwrap_or_else(|e| fail(e)).id().to_string()
+}
+
+fn create(args: &[String]) {
+    let out = args.get(0).unwrap_or_else(|| usage());
+
+    let mut metadata = Metadata::default();
+    let mut secret = None;
+    let mut operator = None;
+
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next().unwrap_or_else(|| usage()).clone();
+        match arg.as_str() {
+            "--board" => metadata.board = Some(value),
+            "--batch" => metadata.batch = Some(value),
+            "--secret" => secret = Some(read(&value).secret),
+            "--sign" => operator = Some(operator_key(&value)),
+            _ => usage(),
+        }
+    }
+
+    let secret = secret.unwrap_or_else(|| {
+        let mut secret = [0u8; 32];
+        if let Err(e) = OsRng::new().map(|mut rng| rng.fill_bytes(&mut secret)) {
+            fail(e);
+        }
+        secret
+    });
+    metadata.provisioned = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+
+    let mut blob = Blob::new(secret, metadata);
+    if let Some(operator) = operator {
+        blob.certify(operator.keypair()).unwrap_or_else(|e| fail(e));
+    }
+
+    write(out, &blob);
+    println!("{}", id(&blob));
+}
+
+/// Adds a certificate, which also upgrades a partition in the original
+/// layout.
+fn sign(args: &[String]) {
+    if args.len() != 2 {
+        usage();
+    }
+
+    let mut blob = read(&args[0]);
+    blob.certify(operator_key(&args[1]).keypair()).unwrap_or_else(|e| fail(e));
+    write(&args[0], &blob);
+}
+
+fn inspect(args: &[String]) {
+    let path = args.get(0).unwrap_or_else(|| usage());
+    let operator = match (args.get(1).map(|s| s.as_str()), args.get(2)) {
+        (None, None) => None,
+        (Some("--operator"), Some(key)) => Some(public_key(key)),
+        _ => usage(),
+    };
+
+    let blob = read(path);
+    let identity = Identity::from_secret(&blob.secret).unwrap_or_else(|e| fail(e));
+
+    match blob.version {
+        blob::LEGACY => println!("version:     original layout"),
+        v => println!("version:     {}", v),
+    }
+    println!("device id:   {}", identity.id());
+    println!("board:       {}", blob.metadata.board.as_ref().map(|s| s.as_str()).unwrap_or("-"));
+    println!("batch:       {}", blob.metadata.batch.as_ref().map(|s| s.as_str()).unwrap_or("-"));
+    match blob.metadata.provisioned {
+        Some(t) => println!("provisioned: {}", t),
+        None => println!("provisioned: -"),
+    }
+
+    let certificate = match blob.certificate {
+        Some(ref certificate) => certificate,
+        None => {
+            println!("certificate: none");
+            return;
+        }
+    };
+    println!("certificate: issued by {}",
+             bs58::encode(certificate.issuer.as_bytes()).with_alphabet(bs58::alphabet::BITCOIN).into_string());
+
+    if let Some(operator) = operator {
+        match certificate.verify(identity.id(), &blob.metadata, &operator) {
+            Ok(()) => println!("             valid"),
+            Err(e) => fail(format!("             {}", e)),
+        }
+    }
+}
+
+/// Checks an attestation from a device against the operator key and the
+/// nonce it was asked for.
+fn verify(args: &[String]) {
+    if args.len() != 2 {
+        usage();
+    }
+    let operator = public_key(&args[0]);
+
+    let attestation: Attestation = serde_json::from_reader(io::stdin()).unwrap_or_else(|e| fail(e));
+    let statement = attestation.verify(&operator, &args[1]).unwrap_or_else(|e| fail(e));
+
+    println!("device id:   {}", statement.device);
+    println!("board:       {}", statement.board.as_ref().map(|s| s.as_str()).unwrap_or("-"));
+    println!("batch:       {}", statement.batch.as_ref().map(|s| s.as_str()).unwrap_or("-"));
+    println!("mac:         {}", statement.mac);
+    println!("firmware:    {}", statement.firmware);
+    println!("genesis:     {}", statement.genesis);
+    println!("issued:      {}", statement.issued);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.get(0).map(|s| s.as_str()) {
+        Some("create") => create(&args[1..]),
+        Some("sign") => sign(&args[1..]),
+        Some("inspect") => inspect(&args[1..]),
+        Some("verify") => verify(&args[1..]),
+        _ => usage(),
+    }
+}
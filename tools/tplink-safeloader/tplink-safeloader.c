@@ -1490,8 +1490,8 @@ static void build_image(const char *output,
     strcpy(mdat, "here should be lots of high quality randomness");
     parts[5] = put_data("identity", mdat, 4096);
 
-    memset(mdat, 0, 1024);
-    strcpy(mdat, "here should be a file system? no idea what to use this for yet");
+    // the jffs2 preinit mounts at /state, erased flash is an empty one
+    memset(mdat, 0xff, 1024);
     parts[6] = put_data("sysconf", mdat, 1024);
 
     parts[7] = read_file("file-system", genesis_image, false);